@@ -0,0 +1,170 @@
+use crate::error::Result;
+use crate::options;
+use core::ffi::c_void;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Source of executable memory for trampolines.
+///
+/// Implement this to let the engine place trampolines in your own code cache or JIT arena. Memory
+/// handed out by an allocator is always returned to the same allocator through [`free`].
+///
+/// [`free`]: ExecutableAllocator::free
+pub trait ExecutableAllocator: Send + Sync {
+    /// Allocate `size` bytes of RWX memory within `range` bytes of `pos`.
+    ///
+    /// `Ok(None)` means the allocator declines the placement (for example because it has no memory
+    /// in range); errors are reserved for real failures. The default implementation declines.
+    fn alloc_near(&self, size: usize, pos: usize, range: usize) -> Result<Option<*mut c_void>> {
+        let _ = (size, pos, range);
+        Ok(None)
+    }
+    /// Allocate `size` bytes of RWX memory anywhere.
+    fn alloc(&self, size: usize) -> Result<*mut c_void>;
+    /// Release memory previously returned by `alloc` or `alloc_near` with the same `size`.
+    unsafe fn free(&self, ptr: *mut c_void, size: usize) -> Result<()>;
+}
+
+/// The platform allocator (`mmap` on Unix, `VirtualAlloc` on Windows).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemAllocator;
+
+impl ExecutableAllocator for SystemAllocator {
+    fn alloc_near(&self, size: usize, pos: usize, range: usize) -> Result<Option<*mut c_void>> {
+        #[cfg(unix)]
+        unsafe {
+            crate::platform::unix::alloc_executable_near(size, pos, range)
+        }
+        #[cfg(windows)]
+        unsafe {
+            crate::platform::windows::alloc_executable_near(size, pos, range)
+        }
+        #[cfg(not(any(unix, windows)))]
+        {
+            let _ = (size, pos, range);
+            Err(crate::error::Error::UnsupportedPlatform)
+        }
+    }
+    fn alloc(&self, size: usize) -> Result<*mut c_void> {
+        #[cfg(unix)]
+        unsafe {
+            crate::platform::unix::alloc_executable(size)
+        }
+        #[cfg(windows)]
+        unsafe {
+            crate::platform::windows::alloc_executable(size)
+        }
+        #[cfg(not(any(unix, windows)))]
+        {
+            let _ = size;
+            Err(crate::error::Error::UnsupportedPlatform)
+        }
+    }
+    unsafe fn free(&self, ptr: *mut c_void, size: usize) -> Result<()> {
+        #[cfg(unix)]
+        {
+            crate::platform::unix::free_executable(ptr, size)
+        }
+        #[cfg(windows)]
+        {
+            let _ = size;
+            crate::platform::windows::free_executable(ptr)
+        }
+        #[cfg(not(any(unix, windows)))]
+        {
+            let _ = (ptr, size);
+            Err(crate::error::Error::UnsupportedPlatform)
+        }
+    }
+}
+
+/// Default allocator: honours the legacy near-code callback and falls back to [`SystemAllocator`].
+///
+/// Blocks returned by the callback belong to the embedder, so they are remembered and never
+/// passed to `munmap`/`VirtualFree`.
+#[derive(Default)]
+pub(crate) struct DefaultAllocator {
+    foreign: Mutex<HashSet<usize>>,
+}
+
+impl ExecutableAllocator for DefaultAllocator {
+    fn alloc_near(&self, size: usize, pos: usize, range: usize) -> Result<Option<*mut c_void>> {
+        if let Some(cb) = options::alloc_near_code_callback() {
+            let p = unsafe { cb(size as u32, pos, range) };
+            if p != 0 {
+                self.foreign.lock().unwrap().insert(p);
+                return Ok(Some(p as *mut c_void));
+            }
+        }
+        SystemAllocator.alloc_near(size, pos, range)
+    }
+    fn alloc(&self, size: usize) -> Result<*mut c_void> {
+        SystemAllocator.alloc(size)
+    }
+    unsafe fn free(&self, ptr: *mut c_void, size: usize) -> Result<()> {
+        if self.foreign.lock().unwrap().remove(&(ptr as usize)) {
+            return Ok(());
+        }
+        SystemAllocator.free(ptr, size)
+    }
+}
+
+#[cfg(all(test, unix, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+    use crate::engine;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct CountingAllocator {
+        live: Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl ExecutableAllocator for CountingAllocator {
+        fn alloc(&self, size: usize) -> Result<*mut c_void> {
+            let p = SystemAllocator.alloc(size)?;
+            self.live.lock().unwrap().push((p as usize, size));
+            Ok(p)
+        }
+        unsafe fn free(&self, ptr: *mut c_void, size: usize) -> Result<()> {
+            let mut live = self.live.lock().unwrap();
+            let idx = live
+                .iter()
+                .position(|e| *e == (ptr as usize, size))
+                .expect("freed block was allocated here");
+            live.remove(idx);
+            SystemAllocator.free(ptr, size)
+        }
+    }
+
+    #[inline(never)]
+    fn target(x: i32) -> i32 {
+        core::hint::black_box(x).wrapping_mul(3) + 1
+    }
+
+    #[inline(never)]
+    fn detour(x: i32) -> i32 {
+        x + 1000
+    }
+
+    #[test]
+    fn per_hook_allocator_owns_trampoline() {
+        let alloc = Arc::new(CountingAllocator::default());
+        let address = target as *const () as *mut c_void;
+        unsafe {
+            let tramp = engine::hook_with_allocator(
+                address,
+                detour as *const () as *mut c_void,
+                alloc.clone(),
+            )
+            .expect("hook");
+            assert_eq!(alloc.live.lock().unwrap().as_slice()[0].0, tramp as usize);
+            assert_eq!(core::hint::black_box(target as fn(i32) -> i32)(1), 1001);
+            let original: fn(i32) -> i32 = core::mem::transmute(tramp);
+            assert_eq!(original(1), 4);
+            engine::destroy(address).expect("destroy");
+        }
+        assert!(alloc.live.lock().unwrap().is_empty());
+        assert_eq!(core::hint::black_box(target as fn(i32) -> i32)(1), 4);
+    }
+}
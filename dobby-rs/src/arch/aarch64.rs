@@ -2,7 +2,18 @@
 
 use crate::error::{Error, Result};
 
-const OP_NOP: u32 = 0xD503201F;
+#[cfg(test)]
+pub(crate) mod emu;
+
+pub(crate) const OP_NOP: u32 = 0xD503201F;
+pub(crate) const OP_RET: u32 = 0xD65F_03C0;
+const OP_BR_X17: u32 = 0xD61F_0000 | (17 << 5);
+// Jumps into the middle of code use `ret`: unlike `br`, it isn't checked against BTI landing pads.
+pub(crate) const OP_RET_X17: u32 = 0xD65F_0000 | (17 << 5);
+pub(crate) const OP_BTI_C: u32 = 0xD503_245F;
+const OP_PACIASP: u32 = 0xD503_233F;
+const OP_PACIBSP: u32 = 0xD503_237F;
+const OP_BLR_X17: u32 = 0xD63F_0000 | (17 << 5);
 
 fn sign_extend(value: i64, bits: u32) -> i64 {
     let shift = 64 - bits;
@@ -17,6 +28,18 @@ fn encode_b_imm(imm26: i32) -> u32 {
     0x1400_0000 | ((imm26 as u32) & 0x03FF_FFFF)
 }
 
+// Signed 21-bit immediate of `adr`/`adrp` (`immhi:immlo`).
+fn adr_imm(word: u32) -> i64 {
+    let immlo = (word >> 29) & 0x3;
+    let immhi = (word >> 5) & 0x7FFFF;
+    sign_extend(((immhi << 2) | immlo) as i64, 21)
+}
+
+fn encode_adr_imm(word: u32, imm: i64) -> u32 {
+    let imm = imm as u32;
+    (word & 0x9F00_001F) | ((imm & 0x3) << 29) | (((imm >> 2) & 0x7FFFF) << 5)
+}
+
 fn encode_ldr_literal(rt: u32, imm19: i32, v: bool, opc: u32) -> u32 {
     ((opc & 0x3) << 30)
         | ((v as u32) << 26)
@@ -47,7 +70,138 @@ fn is_tbz_tbnz(insn: u32) -> bool {
     (insn & 0x7E00_0000) == 0x3600_0000
 }
 fn is_ldr_literal(insn: u32) -> bool {
-    (insn & 0x1F00_0000) == 0x1800_0000
+    (insn & 0x3B00_0000) == 0x1800_0000
+}
+
+// Width of the offset field at bit 5: imm14 for `tbz`/`tbnz`, imm19 otherwise.
+fn cond_branch_bits(word: u32) -> u32 {
+    if is_tbz_tbnz(word) { 14 } else { 19 }
+}
+
+fn cond_branch_target(word: u32, pc: u64) -> u64 {
+    let bits = cond_branch_bits(word);
+    let imm = sign_extend(((word >> 5) & ((1 << bits) - 1)) as i64, bits);
+    pc.wrapping_add((imm << 2) as u64)
+}
+
+fn with_cond_branch_offset(word: u32, offset: i64) -> u32 {
+    let mask = ((1u32 << cond_branch_bits(word)) - 1) << 5;
+    (word & !mask) | (((offset as u32) << 5) & mask)
+}
+
+/// The branch taken exactly when `word` isn't; `None` for `b.al`/`b.nv`, which always branch.
+fn invert_cond_branch(word: u32) -> Option<u32> {
+    if is_b_cond(word) {
+        // Conditions come in pairs differing in bit 0.
+        (word & 0xE != 0xE).then_some(word ^ 1)
+    } else {
+        // cbz <-> cbnz, tbz <-> tbnz
+        Some(word ^ (1 << 24))
+    }
+}
+
+/// Register-offset (`[xN]`) form of a literal load, by `opc`/`V`, and whether its destination is a
+/// general-purpose register that may hold the address. Returns `None` for the unallocated `V=1,
+/// opc=11`.
+fn literal_load_register_form(word: u32) -> Option<(u32, bool)> {
+    let opc = word >> 30;
+    let v = (word >> 26) & 1 != 0;
+    match (v, opc) {
+        (false, 0b00) => Some((0xB940_0000, true)),  // ldr wt
+        (false, 0b01) => Some((0xF940_0000, true)),  // ldr xt
+        (false, 0b10) => Some((0xB980_0000, true)),  // ldrsw xt
+        (false, 0b11) => Some((0xF980_0000, false)), // prfm
+        (true, 0b00) => Some((0xBD40_0000, false)),  // ldr st
+        (true, 0b01) => Some((0xFD40_0000, false)),  // ldr dt
+        (true, 0b10) => Some((0x3DC0_0000, false)),  // ldr qt
+        _ => None,
+    }
+}
+
+/// Any `bti` hint (`bti`, `bti c`, `bti j`, `bti jc`).
+pub(crate) fn is_bti(insn: u32) -> bool {
+    (insn & 0xFFFF_FF3F) == 0xD503_241F
+}
+
+/// An instruction an indirect call may land on when BTI is enforced: `bti c`/`bti jc`, or
+/// `paciasp`/`pacibsp`, which act as `bti c`.
+pub(crate) fn is_landing_pad(insn: u32) -> bool {
+    matches!(insn, OP_BTI_C | 0xD503_24DF | OP_PACIASP | OP_PACIBSP)
+}
+
+/// `b` from `from` to `to`, if within ±128 MiB.
+pub(crate) fn encode_b(from: u64, to: u64) -> Option<u32> {
+    let delta = (to as i64).wrapping_sub(from as i64);
+    ((-0x800_0000..0x800_0000).contains(&delta) && delta & 3 == 0)
+        .then(|| encode_b_imm((delta >> 2) as i32))
+}
+
+/// `adrp x<reg>, to; add x<reg>, x<reg>, :lo12:to; br x<reg>` placed at `from`, if the page of
+/// `to` is within ±4 GiB.
+pub(crate) fn encode_adrp_add_br(from: u64, to: u64, reg: u32) -> Option<[u32; 3]> {
+    let pages = ((to & !0xFFF) as i64).wrapping_sub((from & !0xFFF) as i64) >> 12;
+    if !(-0x10_0000..0x10_0000).contains(&pages) {
+        return None;
+    }
+    let adrp = encode_adr_imm(0x9000_0000 | reg, pages);
+    let add = 0x9100_0000 | (((to & 0xFFF) as u32) << 10) | (reg << 5) | reg;
+    Some([adrp, add, 0xD61F_0000 | (reg << 5)])
+}
+
+/// `movz` (or `movn`, if more halfwords are all ones than zero) and `movk`s loading `value` into
+/// `x<rd>`, skipping the halfwords the first instruction already sets.
+pub(crate) fn encode_mov(rd: u32, value: u64) -> Vec<u32> {
+    let halves: [u32; 4] = core::array::from_fn(|i| (value >> (i * 16)) as u32 & 0xFFFF);
+    let count = |h: u32| halves.iter().filter(|&&x| x == h).count();
+    let inverted = count(0xFFFF) > count(0);
+    let (first, fill) = if inverted {
+        (0x9280_0000, 0xFFFF)
+    } else {
+        (0xD280_0000, 0)
+    };
+    let mut out = Vec::new();
+    for (i, &h) in halves.iter().enumerate() {
+        if h == fill {
+            continue;
+        }
+        let (op, imm) = if out.is_empty() {
+            (first, h ^ fill)
+        } else {
+            (0xF280_0000, h)
+        };
+        out.push(op | ((i as u32) << 21) | (imm << 5) | rd);
+    }
+    if out.is_empty() {
+        out.push(first | rd);
+    }
+    out
+}
+
+/// `b`, `br` or `ret`, or a pointer-authenticated `braa`/`brab`, `braaz`/`brabz` or
+/// `retaa`/`retab`: execution never falls through to the next instruction.
+pub(crate) fn ends_flow(insn: u32) -> bool {
+    is_b(insn)
+        || (insn & 0xFFFF_FC1F) == 0xD61F_0000
+        || (insn & 0xFFFF_FC1F) == 0xD65F_0000
+        || (insn & 0xFFFF_F800) == 0xD71F_0800
+        || (insn & 0xFFFF_F81F) == 0xD61F_081F
+        || (insn & 0xFFFF_FBFF) == 0xD65F_0BFF
+}
+
+/// Whether a `b`, `b.cond`, `cbz`/`cbnz` or `tbz`/`tbnz` among `words` (starting at `pc`) targets
+/// an address in `range`.
+pub(crate) fn branches_into(words: &[u32], pc: u64, range: core::ops::Range<u64>) -> bool {
+    words.iter().enumerate().any(|(i, &word)| {
+        let at = pc + i as u64 * 4;
+        let target = if is_b(word) {
+            at.wrapping_add((sign_extend((word & 0x03FF_FFFF) as i64, 26) << 2) as u64)
+        } else if is_b_cond(word) || is_cbz_cbnz(word) || is_tbz_tbnz(word) {
+            cond_branch_target(word, at)
+        } else {
+            return false;
+        };
+        range.contains(&target)
+    })
 }
 
 pub(crate) fn relocate(instructions: &[u32], src_pc: u64, dst_pc: u64) -> Result<Vec<u32>> {
@@ -57,7 +211,25 @@ pub(crate) fn relocate(instructions: &[u32], src_pc: u64, dst_pc: u64) -> Result
         let out_word_pc = dst_pc + (out.len() as u64) * 4;
 
         if is_adr(word) || is_adrp(word) {
-            out.push(word);
+            let rd = word & 0x1F;
+            let imm = adr_imm(word);
+            let (target, delta) = if is_adrp(word) {
+                let page = |pc: u64| pc & !0xFFF;
+                let target = page(src_word_pc).wrapping_add((imm << 12) as u64);
+                (target, (target as i64 - page(out_word_pc) as i64) >> 12)
+            } else {
+                let target = src_word_pc.wrapping_add(imm as u64);
+                (target, target as i64 - out_word_pc as i64)
+            };
+            if (-0x10_0000..0x10_0000).contains(&delta) {
+                out.push(encode_adr_imm(word, delta));
+            } else {
+                // ldr xN, #8; b #12; .quad target
+                out.push(encode_ldr_literal(rd, 2, false, 1));
+                out.push(encode_b_imm(3));
+                out.push(target as u32);
+                out.push((target >> 32) as u32);
+            }
             continue;
         }
         if is_b(word) || is_bl(word) {
@@ -66,18 +238,59 @@ pub(crate) fn relocate(instructions: &[u32], src_pc: u64, dst_pc: u64) -> Result
             let delta = (target as i64 - out_word_pc as i64) >> 2;
             if (-0x0200_0000..=0x01FF_FFFF).contains(&delta) {
                 out.push((word & 0xFC00_0000) | ((delta as u32) & 0x03FF_FFFF));
+            } else if is_b(word) {
+                // ldr x17, #8; ret x17; .quad target
+                out.push(encode_ldr_literal(17, 2, false, 1));
+                out.push(OP_RET_X17);
+                out.push(target as u32);
+                out.push((target >> 32) as u32);
             } else {
-                // keep simple expansion
+                // ldr x17, #12; blr x17; b #12; .quad target
                 out.push(encode_ldr_literal(17, 3, false, 1));
-                out.push(0xD61F0000 | (17 << 5));
-                out.push((target & 0xFFFF_FFFF) as u32);
+                out.push(OP_BLR_X17);
+                out.push(encode_b_imm(3));
+                out.push(target as u32);
                 out.push((target >> 32) as u32);
             }
             continue;
         }
-        if is_b_cond(word) || is_cbz_cbnz(word) || is_tbz_tbnz(word) || is_ldr_literal(word) {
-            // conservative pass-through for now
-            out.push(word);
+        if is_b_cond(word) || is_cbz_cbnz(word) || is_tbz_tbnz(word) {
+            let bits = cond_branch_bits(word);
+            let target = cond_branch_target(word, src_word_pc);
+            let delta = (target as i64 - out_word_pc as i64) >> 2;
+            let limit = 1i64 << (bits - 1);
+            if (-limit..limit).contains(&delta) {
+                out.push(with_cond_branch_offset(word, delta));
+                continue;
+            }
+            // b.<!cond> #20 (none for b.al/b.nv); ldr x17, #8; ret x17; .quad target
+            if let Some(inverted) = invert_cond_branch(word) {
+                out.push(with_cond_branch_offset(inverted, 5));
+            }
+            out.push(encode_ldr_literal(17, 2, false, 1));
+            out.push(OP_RET_X17);
+            out.push(target as u32);
+            out.push((target >> 32) as u32);
+            continue;
+        }
+        if is_ldr_literal(word) {
+            let target = src_word_pc
+                .wrapping_add((sign_extend(imm19_from_word(word) as i64, 19) << 2) as u64);
+            let delta = (target as i64 - out_word_pc as i64) >> 2;
+            if (-0x4_0000..0x4_0000).contains(&delta) {
+                out.push((word & !(0x7FFFF << 5)) | (((delta as u32) & 0x7FFFF) << 5));
+                continue;
+            }
+            let rt = word & 0x1F;
+            let load = literal_load_register_form(word).ok_or(Error::RelocationFailed)?;
+            // General-purpose loads can use their own destination for the address.
+            let base = if load.1 { rt } else { 17 };
+            // ldr x<base>, #12; <load> [x<base>]; b #12; .quad target
+            out.push(encode_ldr_literal(base, 3, false, 1));
+            out.push(load.0 | (base << 5) | rt);
+            out.push(encode_b_imm(3));
+            out.push(target as u32);
+            out.push((target >> 32) as u32);
             continue;
         }
         if word == 0 {
@@ -101,22 +314,427 @@ mod tests {
         let out = relocate(&src, 0x1000_0000, 0x2000_0000).expect("ok");
         assert_eq!(out, src);
     }
+
+    #[test]
+    fn flow_ends_at_branches_and_returns() {
+        // b; br x17; ret; retaa; retab; braa x1, x2; brab x1, x2; braaz x3; brabz x3
+        for insn in [
+            0x1400_0010,
+            OP_BR_X17,
+            OP_RET,
+            0xD65F_0BFF,
+            0xD65F_0FFF,
+            0xD71F_0822,
+            0xD71F_0C22,
+            0xD61F_087F,
+            0xD61F_0C7F,
+        ] {
+            assert!(ends_flow(insn), "{insn:#010x}");
+        }
+        // bl; blr x17; blraa x1, x2; b.eq; paciasp; autiasp; nop
+        for insn in [
+            0x9400_0010,
+            OP_BLR_X17,
+            0xD73F_0822,
+            0x5400_0040,
+            OP_PACIASP,
+            0xD503_23BF,
+            OP_NOP,
+        ] {
+            assert!(!ends_flow(insn), "{insn:#010x}");
+        }
+    }
+
+    #[test]
+    fn branches_into_finds_internal_targets() {
+        const PC: u64 = 0x1000_0000;
+        // cbz x0, #8; b.eq #-4; tbz w1, #3, #12; b #4
+        let words = [0xB400_0040, 0x54FF_FFE0, 0x3618_0061, 0x1400_0001];
+        assert!(branches_into(&words[..1], PC, PC + 1..PC + 16));
+        assert!(!branches_into(&words[..1], PC, PC + 1..PC + 8));
+        // Back to the start of the range is fine.
+        assert!(!branches_into(&words[1..2], PC + 4, PC + 1..PC + 16));
+        assert!(branches_into(&words[1..2], PC + 4, PC..PC + 16));
+        assert!(branches_into(&words[2..3], PC + 8, PC + 1..PC + 24));
+        assert!(!branches_into(&words[2..3], PC + 8, PC + 1..PC + 20));
+        assert!(branches_into(&words[3..], PC + 12, PC + 1..PC + 20));
+        assert!(!branches_into(&[OP_NOP, OP_RET], PC, PC..PC + 8));
+    }
+    // Where the branch at `out[idx]`, placed at `pc`, ends up: decoded directly for `b`/`bl`, or
+    // from the literal of an `ldr x17` expansion.
+    fn branch_target(out: &[u32], idx: usize, pc: u64) -> u64 {
+        let word = out[idx];
+        if is_b(word) || is_bl(word) {
+            let imm26 = sign_extend((word & 0x03FF_FFFF) as i64, 26);
+            return (pc as i64 + (imm26 << 2)) as u64;
+        }
+        assert!(
+            is_ldr_literal(word) && word & 0x1F == 17,
+            "not a branch: {word:#x}"
+        );
+        let lit = idx + imm19_from_word(word) as usize;
+        out[lit] as u64 | (out[lit + 1] as u64) << 32
+    }
+
+    #[test]
+    fn relocate_near_branches_keep_targets() {
+        let src_pc = 0x1000_0000;
+        let dst_pc = 0x1008_0000;
+        // b #-0x40; bl #+0x100
+        let src = [encode_b_imm(-0x10), 0x9400_0000 | 0x40];
+        let out = relocate(&src, src_pc, dst_pc).expect("ok");
+        assert_eq!(out.len(), 2);
+        assert!(is_b(out[0]) && is_bl(out[1]));
+        assert_eq!(branch_target(&out, 0, dst_pc), src_pc - 0x40);
+        assert_eq!(branch_target(&out, 1, dst_pc + 4), src_pc + 4 + 0x100);
+    }
+
     #[test]
     fn relocate_far_branch_expands() {
-        let b = encode_b_imm(1);
-        let out = relocate(&[b], 0x1000_0000, 0x9000_0000_0000).expect("ok");
-        assert!(!out.is_empty());
+        let src_pc = 0x1000_0000;
+        let dst_pc = 0x9000_0000_0000;
+        let out = relocate(&[encode_b_imm(1)], src_pc, dst_pc).expect("ok");
+        assert_eq!(out[1], OP_RET_X17);
+        assert_eq!(branch_target(&out, 0, dst_pc), src_pc + 4);
+
+        let out = relocate(&[0x9400_0000 | 8], src_pc, dst_pc).expect("ok");
+        assert_eq!(out[1], OP_BLR_X17);
+        assert_eq!(branch_target(&out, 0, dst_pc), src_pc + 32);
+        // The call returns past the literal.
+        assert_eq!(
+            branch_target(&out, 2, dst_pc + 8),
+            dst_pc + out.len() as u64 * 4
+        );
     }
+
+    // Value the `adr`/`adrp` at `out[idx]`, placed at `pc`, puts in its register, or the literal
+    // of the `ldr` that replaced it.
+    fn adr_value(out: &[u32], idx: usize, pc: u64) -> u64 {
+        let word = out[idx];
+        if is_adrp(word) {
+            return (pc & !0xFFF).wrapping_add((adr_imm(word) << 12) as u64);
+        }
+        if is_adr(word) {
+            return pc.wrapping_add(adr_imm(word) as u64);
+        }
+        assert!(is_ldr_literal(word), "not an address load: {word:#x}");
+        let lit = idx + imm19_from_word(word) as usize;
+        out[lit] as u64 | (out[lit + 1] as u64) << 32
+    }
+
+    #[test]
+    fn landing_pads() {
+        assert!(is_landing_pad(OP_BTI_C) && is_bti(OP_BTI_C));
+        assert!(is_landing_pad(0xD503_24DF) && is_bti(0xD503_24DF));
+        // bti j only accepts jumps.
+        assert!(!is_landing_pad(0xD503_249F) && is_bti(0xD503_249F));
+        assert!(is_landing_pad(OP_PACIASP) && !is_bti(OP_PACIASP));
+        assert!(is_landing_pad(OP_PACIBSP));
+        assert!(!is_landing_pad(OP_NOP) && !is_bti(OP_NOP));
+        // Signing only depends on LR and SP, so it is copied unchanged.
+        let out = relocate(&[OP_PACIASP, 0xD503_23BF], 0x1000, 0x9000_0000_0000).expect("ok");
+        assert_eq!(out, [OP_PACIASP, 0xD503_23BF]);
+    }
+
+    #[test]
+    fn compact_jumps_reach_their_targets() {
+        let from = 0x7000_0000_1000;
+        for to in [from + 0x7FF_FFFC, from - 0x800_0000, from + 8] {
+            let b = encode_b(from, to).expect("in range");
+            let out = [b];
+            assert_eq!(branch_target(&out, 0, from), to);
+        }
+        assert_eq!(encode_b(from, from + 0x800_0000), None);
+
+        for to in [
+            from + 0xFFFF_0123,
+            from - 0x1_0000_0000 + 0x2345,
+            from + 0x10,
+        ] {
+            let [adrp, add, br] = encode_adrp_add_br(from, to, 17).expect("in range");
+            assert!(is_adrp(adrp) && adrp & 0x1F == 17);
+            assert_eq!(
+                adr_value(&[adrp], 0, from) + ((add >> 10) & 0xFFF) as u64,
+                to
+            );
+            assert_eq!(br, OP_BR_X17);
+        }
+        assert_eq!(encode_adrp_add_br(from, from + 0x1_0000_1000, 17), None);
+    }
+
+    #[test]
+    fn relocate_adr_near_and_far() {
+        let src_pc = 0x1000_0010;
+        // adr x3, #-0x24 and adr x4, #+0x1235
+        for (word, rd, target) in [
+            (encode_adr_imm(0x1000_0003, -0x24), 3, src_pc - 0x24),
+            (encode_adr_imm(0x1000_0004, 0x1235), 4, src_pc + 0x1235),
+        ] {
+            assert_eq!(adr_value(&[word], 0, src_pc), target);
+            let near = relocate(&[word], src_pc, src_pc + 0x8_0000).expect("ok");
+            assert_eq!(near.len(), 1);
+            assert!(is_adr(near[0]) && near[0] & 0x1F == rd);
+            assert_eq!(adr_value(&near, 0, src_pc + 0x8_0000), target);
+
+            let far = relocate(&[word], src_pc, 0x9000_0000_0000).expect("ok");
+            assert_eq!(far[0] & 0x1F, rd);
+            assert_eq!(adr_value(&far, 0, 0x9000_0000_0000), target);
+            // Execution skips the literal.
+            assert_eq!(far[1], encode_b_imm(3));
+        }
+    }
+
+    #[test]
+    fn relocate_adrp_near_and_far() {
+        let src_pc = 0x7f00_1234_5678;
+        // adrp x0, #-3 pages and adrp x16, #+0x10 pages
+        for (word, rd, target) in [
+            (encode_adr_imm(0x9000_0000, -3), 0, 0x7f00_1234_2000),
+            (encode_adr_imm(0x9000_0010, 0x10), 16, 0x7f00_1235_5000),
+        ] {
+            assert_eq!(adr_value(&[word], 0, src_pc), target);
+            let near_pc = 0x7f00_2000_0004;
+            let near = relocate(&[word], src_pc, near_pc).expect("ok");
+            assert_eq!(near.len(), 1);
+            assert!(is_adrp(near[0]) && near[0] & 0x1F == rd);
+            assert_eq!(adr_value(&near, 0, near_pc), target);
+
+            let far = relocate(&[word], src_pc, 0x1000).expect("ok");
+            assert!(is_ldr_literal(far[0]) && far[0] & 0x1F == rd);
+            assert_eq!(adr_value(&far, 0, 0x1000), target);
+        }
+    }
+
+    // Where the conditional branch at `out[0]` goes when taken and when not, given that its
+    // condition holds. Follows the inverted-branch expansion.
+    fn cond_taken_target(out: &[u32], pc: u64) -> u64 {
+        match out.len() {
+            1 => cond_branch_target(out[0], pc),
+            4 => branch_target(out, 0, pc),
+            _ => {
+                assert_eq!(
+                    cond_branch_target(out[0], pc),
+                    pc + 20,
+                    "skip to the next instruction"
+                );
+                branch_target(out, 1, pc + 4)
+            }
+        }
+    }
+
+    #[test]
+    fn relocate_conditional_branches() {
+        let src_pc = 0x4000_0000;
+        let b_cond = |cond: u32, imm19: i32| 0x5400_0000 | ((imm19 as u32 & 0x7FFFF) << 5) | cond;
+        let mut cases = Vec::new();
+        for cond in 0..16 {
+            cases.push(b_cond(cond, -8));
+        }
+        for base in [0x3400_0000u32, 0x3500_0000, 0xB400_0000, 0xB500_0000] {
+            // cbz/cbnz with w and x registers
+            cases.push(base | ((0x40 & 0x7FFFF) << 5) | 7);
+        }
+        for (b5, b40) in [(0u32, 0u32), (0, 31), (1, 0), (1, 31)] {
+            for op in [0x3600_0000u32, 0x3700_0000] {
+                // tbz/tbnz x9, #(b5:b40), #-0x100
+                cases.push(op | (b5 << 31) | (b40 << 19) | ((0x3FC0 & 0x3FFF) << 5) | 9);
+            }
+        }
+        for word in cases {
+            let target = cond_branch_target(word, src_pc);
+            let near_pc = src_pc + 0x2000;
+            let near = relocate(&[word], src_pc, near_pc).expect("ok");
+            assert_eq!(near.len(), 1, "{word:#x}");
+            assert_eq!(near[0] & !(0x7FFFF << 5), word & !(0x7FFFF << 5));
+            assert_eq!(cond_taken_target(&near, near_pc), target);
+
+            let far_pc = 0x9000_0000_0000;
+            let far = relocate(&[word], src_pc, far_pc).expect("ok");
+            assert!(far.len() > 1, "{word:#x}");
+            assert_eq!(cond_taken_target(&far, far_pc), target, "{word:#x}");
+            if let Some(inverted) = invert_cond_branch(word) {
+                // Same register and bit, opposite sense.
+                let offset = ((1u32 << cond_branch_bits(word)) - 1) << 5;
+                assert_eq!(far[0] & !offset, inverted & !offset);
+                assert_ne!(far[0] & !offset, word & !offset);
+            }
+        }
+    }
+
+    #[test]
+    fn relocate_literal_loads() {
+        let src_pc = 0x5000_0000;
+        // (V, opc, register form) for every allocated combination.
+        let forms = [
+            (false, 0b00, 0xB940_0000),
+            (false, 0b01, 0xF940_0000),
+            (false, 0b10, 0xB980_0000),
+            (false, 0b11, 0xF980_0000),
+            (true, 0b00, 0xBD40_0000),
+            (true, 0b01, 0xFD40_0000),
+            (true, 0b10, 0x3DC0_0000),
+        ];
+        for (v, opc, form) in forms {
+            for imm19 in [0x40, -0x40] {
+                let word = encode_ldr_literal(5, imm19, v, opc);
+                let target = (src_pc as i64 + imm19 as i64 * 4) as u64;
+
+                let near_pc = src_pc + 0x1000;
+                let near = relocate(&[word], src_pc, near_pc).expect("ok");
+                assert_eq!(near.len(), 1);
+                assert_eq!(near[0] & !(0x7FFFF << 5), word & !(0x7FFFF << 5));
+                let imm = sign_extend(imm19_from_word(near[0]) as i64, 19);
+                assert_eq!((near_pc as i64 + imm * 4) as u64, target);
+
+                let far = relocate(&[word], src_pc, 0x9000_0000_0000).expect("ok");
+                let base = if v || opc == 0b11 { 17 } else { 5 };
+                assert_eq!(far[0], encode_ldr_literal(base, 3, false, 1));
+                assert_eq!(far[1], form | (base << 5) | 5, "v={v} opc={opc}");
+                assert_eq!(far[2], encode_b_imm(3));
+                assert_eq!(far[3] as u64 | (far[4] as u64) << 32, target);
+            }
+        }
+        let unallocated = encode_ldr_literal(5, 0x40, true, 0b11);
+        assert!(matches!(
+            relocate(&[unallocated], src_pc, 0x9000_0000_0000),
+            Err(Error::RelocationFailed)
+        ));
+    }
+
     #[test]
-    fn relocate_adr_fallback_to_literal() {
-        let out = relocate(&[0x1000_0000], 0x1000_0000, 0x9000_0000_0000).expect("ok");
-        assert!(!out.is_empty());
+    fn emulator_builds_constants() {
+        let mut mem = emu::Memory::default();
+        // movz x0, #0x1234, lsl #48; movk x0, #0x5678, lsl #16; movz w1, #0xffff; movn x2, #0
+        mem.write_words(
+            0x1000,
+            &[0xD2E2_4680, 0xF2AA_CF00, 0x529F_FFE1, 0x9280_0002],
+        );
+        let mut cpu = emu::Cpu {
+            pc: 0x1000,
+            ..emu::Cpu::default()
+        };
+        cpu.run(&mem, 0x1000..0x1010, 8);
+        assert_eq!(cpu.x[0], 0x1234_0000_5678_0000);
+        assert_eq!(cpu.x[1], 0xFFFF);
+        assert_eq!(cpu.x[2], u64::MAX);
+        assert_eq!(cpu.pc, 0x1010);
     }
+
     #[test]
-    fn relocate_ldr_literal_fallback() {
-        let ldr_x0 = encode_ldr_literal(0, 1, false, 1);
-        let out = relocate(&[ldr_x0], 0x1000_0000, 0x9000_0000_0000).expect("ok");
-        assert!(!out.is_empty());
-        let _ = imm19_from_word(ldr_x0);
+    fn mov_loads_any_constant() {
+        let values = [
+            0,
+            u64::MAX,
+            1,
+            0xFFFF_0000,
+            0x1234_0000_5678_0000,
+            0xFFFF_FFFF_FFFF_FFFE,
+            0xFFFF_1234_FFFF_FFFF,
+            0x8000_0000_0000_0001,
+        ];
+        for value in values {
+            let words = encode_mov(3, value);
+            let mut mem = emu::Memory::default();
+            mem.write_words(0x1000, &words);
+            let mut cpu = emu::Cpu {
+                pc: 0x1000,
+                x: [u64::MAX / 3; 31],
+                ..emu::Cpu::default()
+            };
+            cpu.run(&mem, 0x1000..0x1000 + words.len() as u64 * 4, 8);
+            assert_eq!(cpu.x[3], value, "{value:#x}");
+        }
+        assert_eq!(encode_mov(0, 1), [0xD280_0020]);
+        assert_eq!(encode_mov(0, 0xFFFF_1234_FFFF_FFFF).len(), 1);
+    }
+
+    const SRC: u64 = 0x0000_7f12_3456_7000;
+    // Near (re-encoded in place), above and below out of every range.
+    const DSTS: [u64; 3] = [SRC + 0x4_0000, 0x0000_9000_0000_0000, 0x0000_0000_1000_0000];
+
+    fn start() -> emu::Cpu {
+        let mut cpu = emu::Cpu::default();
+        for (i, x) in cpu.x.iter_mut().enumerate() {
+            *x = 0x1111_0000_0000_0000 * (i as u64 % 7) + i as u64;
+        }
+        cpu
+    }
+
+    #[test]
+    fn emulated_branches_and_addresses() {
+        let sequences: [&[u32]; 6] = [
+            &[encode_b_imm(-0x100)],
+            &[OP_NOP, 0x9400_0000 | 0x2_0000],
+            &[
+                encode_adr_imm(0x1000_0003, -0x24),
+                encode_adr_imm(0x1000_0004, 0xF_FFFF),
+            ],
+            &[
+                encode_adr_imm(0x9000_0000, -3),
+                encode_adr_imm(0x9000_0010, 0x7_FFFF),
+            ],
+            // paciasp; adrp x1; add x1, x1, #0x10; autiasp
+            &[
+                OP_PACIASP,
+                encode_adr_imm(0x9000_0001, 9),
+                0x9100_4021,
+                0xD503_23BF,
+            ],
+            &[OP_BTI_C, encode_b_imm(0x40)],
+        ];
+        for words in sequences {
+            emu::assert_equivalent(words, SRC, &DSTS, &start());
+        }
+    }
+
+    #[test]
+    fn emulated_literal_loads() {
+        for (v, opc) in [
+            (false, 0),
+            (false, 1),
+            (false, 2),
+            (false, 3),
+            (true, 0),
+            (true, 1),
+            (true, 2),
+        ] {
+            for imm19 in [0x123, -0x3_0000] {
+                // Followed by a load through x5 to catch a clobbered base register.
+                let words = [encode_ldr_literal(5, imm19, v, opc), 0xF940_00A6];
+                emu::assert_equivalent(&words, SRC, &DSTS, &start());
+            }
+        }
+    }
+
+    #[test]
+    fn emulated_conditional_branches() {
+        let mut words = Vec::new();
+        for cond in 0..16 {
+            words.push(0x5400_0000 | ((0x7_FF00 & 0x7FFFF) << 5) | cond);
+        }
+        for base in [0x3400_0000u32, 0x3500_0000, 0xB400_0000, 0xB500_0000] {
+            for rt in [0, 1, 2] {
+                words.push(base | (0x4_0000 << 5) | rt);
+            }
+        }
+        for op in [0x3600_0000u32, 0x3700_0000] {
+            for bit in [0u32, 31, 32, 63] {
+                words.push(op | (bit >> 5) << 31 | (bit & 0x1F) << 19 | (0x2000 << 5) | 3);
+            }
+        }
+        let mut cpu = start();
+        // x0 = 0; x1 has only upper bits (w1 == 0); x2 nonzero in both widths.
+        cpu.x[0] = 0;
+        cpu.x[1] = 0xFFFF_0000_0000_0000;
+        cpu.x[2] = 0x8000_0001;
+        for word in words {
+            for flags in 0..16 {
+                cpu.nzcv = flags << 28;
+                for x3 in [0, u64::MAX, 0x8000_0000, 0x1_0000_0000] {
+                    cpu.x[3] = x3;
+                    emu::assert_equivalent(&[word, OP_NOP], SRC, &DSTS, &cpu);
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,272 @@
+//! Test-only interpreter for the A64 subset the relocator reads or writes: branches, `adr`/`adrp`,
+//! literal and register-offset loads, `cbz`/`tbz`, `add` (immediate), `movz`/`movk` and hints.
+//!
+//! Memory is a synthetic image: every byte that wasn't written reads as a function of its address,
+//! so literal loads see the same data wherever the code runs from.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Cpu {
+    pub x: [u64; 31],
+    pub sp: u64,
+    pub v: [u128; 32],
+    pub pc: u64,
+    /// Flags in bits 31..28, as in `NZCV`.
+    pub nzcv: u32,
+}
+
+#[derive(Default)]
+pub(crate) struct Memory {
+    written: HashMap<u64, u8>,
+}
+
+impl Memory {
+    pub fn write_words(&mut self, address: u64, words: &[u32]) {
+        for (i, w) in words.iter().enumerate() {
+            for (j, b) in w.to_le_bytes().into_iter().enumerate() {
+                self.written.insert(address + (i * 4 + j) as u64, b);
+            }
+        }
+    }
+
+    fn byte(&self, address: u64) -> u8 {
+        self.written.get(&address).copied().unwrap_or_else(|| {
+            let h = address.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            (h >> 56) as u8
+        })
+    }
+
+    fn read(&self, address: u64, len: usize) -> u128 {
+        (0..len).fold(0, |acc, i| {
+            acc | (self.byte(address.wrapping_add(i as u64)) as u128) << (8 * i)
+        })
+    }
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+impl Cpu {
+    fn xr(&self, r: u32) -> u64 {
+        if r == 31 { 0 } else { self.x[r as usize] }
+    }
+
+    fn set_x(&mut self, r: u32, value: u64) {
+        if r != 31 {
+            self.x[r as usize] = value;
+        }
+    }
+
+    // Base register of a load: 31 is `sp`, not `xzr`.
+    fn base(&self, r: u32) -> u64 {
+        if r == 31 { self.sp } else { self.x[r as usize] }
+    }
+
+    fn condition(&self, cond: u32) -> bool {
+        let n = self.nzcv >> 31 & 1 != 0;
+        let z = self.nzcv >> 30 & 1 != 0;
+        let c = self.nzcv >> 29 & 1 != 0;
+        let v = self.nzcv >> 28 & 1 != 0;
+        let r = match cond >> 1 {
+            0 => z,
+            1 => c,
+            2 => n,
+            3 => v,
+            4 => c && !z,
+            5 => n == v,
+            6 => n == v && !z,
+            _ => true,
+        };
+        if cond & 1 != 0 && cond != 0xF { !r } else { r }
+    }
+
+    /// Load `size` bytes into `rt`: a general-purpose register (zero- or, with `signed`,
+    /// sign-extended) or, with `simd`, a vector register.
+    fn load(&mut self, mem: &Memory, address: u64, rt: u32, size: usize, simd: bool, signed: bool) {
+        let value = mem.read(address, size);
+        if simd {
+            self.v[rt as usize] = value;
+        } else if signed {
+            self.set_x(rt, sign_extend(value as u64, size as u32 * 8) as u64);
+        } else {
+            self.set_x(rt, value as u64);
+        }
+    }
+
+    /// Execute one instruction. Returns `false` on `brk`.
+    pub fn step(&mut self, mem: &Memory) -> bool {
+        let pc = self.pc;
+        let w = mem.read(pc, 4) as u32;
+        let rt = w & 0x1F;
+        let rn = (w >> 5) & 0x1F;
+        let mut next = pc.wrapping_add(4);
+        if (w & 0xFFFF_F01F) == 0xD503_201F {
+            // nop, bti, pac/aut hints
+        } else if (w & 0xFFE0_001F) == 0xD420_0000 {
+            return false;
+        } else if (w & 0x7C00_0000) == 0x1400_0000 {
+            if w >> 31 != 0 {
+                self.x[30] = next;
+            }
+            next = pc.wrapping_add((sign_extend((w & 0x03FF_FFFF) as u64, 26) << 2) as u64);
+        } else if matches!(w & 0xFFFF_FC1F, 0xD61F_0000 | 0xD63F_0000 | 0xD65F_0000) {
+            let target = self.xr(rn);
+            if (w & 0xFFFF_FC1F) == 0xD63F_0000 {
+                self.x[30] = next;
+            }
+            next = target;
+        } else if (w & 0xFF00_0010) == 0x5400_0000 {
+            if self.condition(w & 0xF) {
+                next = pc.wrapping_add((sign_extend(((w >> 5) & 0x7FFFF) as u64, 19) << 2) as u64);
+            }
+        } else if (w & 0x7E00_0000) == 0x3400_0000 {
+            let value = if w >> 31 != 0 {
+                self.xr(rt)
+            } else {
+                self.xr(rt) as u32 as u64
+            };
+            if (value == 0) != (w >> 24 & 1 != 0) {
+                next = pc.wrapping_add((sign_extend(((w >> 5) & 0x7FFFF) as u64, 19) << 2) as u64);
+            }
+        } else if (w & 0x7E00_0000) == 0x3600_0000 {
+            let bit = (w >> 31) << 5 | (w >> 19) & 0x1F;
+            let set = self.xr(rt) >> bit & 1 != 0;
+            if set == (w >> 24 & 1 != 0) {
+                next = pc.wrapping_add((sign_extend(((w >> 5) & 0x3FFF) as u64, 14) << 2) as u64);
+            }
+        } else if (w & 0x1F00_0000) == 0x1000_0000 {
+            let imm = sign_extend((((w >> 5) & 0x7FFFF) << 2 | (w >> 29) & 3) as u64, 21);
+            let value = if w >> 31 != 0 {
+                (pc & !0xFFF).wrapping_add((imm << 12) as u64)
+            } else {
+                pc.wrapping_add(imm as u64)
+            };
+            self.set_x(rt, value);
+        } else if (w & 0x3B00_0000) == 0x1800_0000 {
+            let address =
+                pc.wrapping_add((sign_extend(((w >> 5) & 0x7FFFF) as u64, 19) << 2) as u64);
+            match (w >> 26 & 1 != 0, w >> 30) {
+                (false, 0) => self.load(mem, address, rt, 4, false, false),
+                (false, 1) => self.load(mem, address, rt, 8, false, false),
+                (false, 2) => self.load(mem, address, rt, 4, false, true),
+                (false, _) => {} // prfm
+                (true, 0) => self.load(mem, address, rt, 4, true, false),
+                (true, 1) => self.load(mem, address, rt, 8, true, false),
+                (true, 2) => self.load(mem, address, rt, 16, true, false),
+                _ => panic!("unallocated literal load {w:#010x}"),
+            }
+        } else if (w & 0x3B00_0000) == 0x3900_0000 {
+            // ldr/ldrsw/prfm (unsigned offset)
+            let size = w >> 30;
+            let simd = w >> 26 & 1 != 0;
+            let opc = (w >> 22) & 3;
+            let bytes = match (simd, size, opc) {
+                (true, 0, 3) => 16,
+                (_, s, _) => 1usize << s,
+            };
+            let address = self
+                .base(rn)
+                .wrapping_add((((w >> 10) & 0xFFF) as usize * bytes) as u64);
+            match (simd, size, opc) {
+                (false, 3, 2) => {} // prfm
+                (false, _, 1) => self.load(mem, address, rt, bytes, false, false),
+                (false, 2, 2) => self.load(mem, address, rt, bytes, false, true),
+                (true, 0, 3) | (true, _, 1) => self.load(mem, address, rt, bytes, true, false),
+                _ => panic!("unsupported load {w:#010x}"),
+            }
+        } else if (w & 0xFF80_0000) == 0x9100_0000 {
+            let imm = ((w >> 10) & 0xFFF) as u64;
+            let imm = if w >> 22 & 1 != 0 { imm << 12 } else { imm };
+            let base = if rn == 31 {
+                self.sp
+            } else {
+                self.x[rn as usize]
+            };
+            let value = base.wrapping_add(imm);
+            if rt == 31 {
+                self.sp = value;
+            } else {
+                self.x[rt as usize] = value;
+            }
+        } else if (w & 0x1F80_0000) == 0x1280_0000 && (w >> 29) & 3 != 1 {
+            let shift = ((w >> 21) & 3) * 16;
+            let imm = (((w >> 5) & 0xFFFF) as u64) << shift;
+            let value = match (w >> 29) & 3 {
+                0 => !imm,
+                2 => imm,
+                _ => (self.xr(rt) & !(0xFFFF << shift)) | imm,
+            };
+            let value = if w >> 31 != 0 {
+                value
+            } else {
+                value as u32 as u64
+            };
+            self.set_x(rt, value);
+        } else {
+            panic!("unsupported instruction {w:#010x} at {pc:#x}");
+        }
+        self.pc = next;
+        true
+    }
+
+    /// Step while `pc` stays in `code`, at most `limit` instructions.
+    pub fn run(&mut self, mem: &Memory, code: Range<u64>, limit: usize) {
+        for _ in 0..limit {
+            if !code.contains(&self.pc) || !self.step(mem) {
+                return;
+            }
+        }
+        panic!("no exit from {code:x?} after {limit} instructions");
+    }
+}
+
+/// Run `words` placed at `src`, and their relocation at `dst` followed by a jump back to the first
+/// instruction after them (as in a trampoline), from the same starting state. Returns both final
+/// states, taken when execution leaves the respective code.
+pub(super) fn run_both(words: &[u32], src: u64, dst: u64, start: &Cpu) -> (Cpu, Cpu) {
+    let relocated = super::relocate(words, src, dst).expect("relocate");
+    let resume = src + words.len() as u64 * 4;
+    let mut trampoline = relocated;
+    // ldr x17, #8; br x17; .quad resume
+    trampoline.extend([
+        0x5800_0051,
+        0xD61F_0220,
+        resume as u32,
+        (resume >> 32) as u32,
+    ]);
+
+    let mut mem = Memory::default();
+    mem.write_words(src, words);
+    mem.write_words(dst, &trampoline);
+
+    let mut original = start.clone();
+    original.pc = src;
+    original.run(&mem, src..resume, 64);
+    let mut moved = start.clone();
+    moved.pc = dst;
+    moved.run(&mem, dst..dst + trampoline.len() as u64 * 4, 64);
+    (original, moved)
+}
+
+/// Assert the relocation of `words` behaves like the original at every placement in `dsts`.
+/// `x16`/`x17` are scratch for the relocator, and `x30` differs after a relocated `bl`.
+pub(super) fn assert_equivalent(words: &[u32], src: u64, dsts: &[u64], start: &Cpu) {
+    for &dst in dsts {
+        let (mut original, mut moved) = run_both(words, src, dst, start);
+        for cpu in [&mut original, &mut moved] {
+            cpu.x[16] = 0;
+            cpu.x[17] = 0;
+            if words.iter().any(|w| w & 0xFC00_0000 == 0x9400_0000) {
+                cpu.x[30] = 0;
+            }
+        }
+        assert_eq!(
+            original, moved,
+            "{words:08x?} from {src:#x} relocated to {dst:#x}"
+        );
+    }
+}
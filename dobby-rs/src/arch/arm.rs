@@ -0,0 +1,939 @@
+#![allow(dead_code)]
+
+use crate::error::{Error, Result};
+use std::collections::VecDeque;
+
+#[cfg(test)]
+mod emu;
+
+const COND_AL: u32 = 0xE;
+const IP: u32 = 12;
+const LR: u32 = 14;
+const PC: u32 = 15;
+/// `ldr pc, [pc, #-4]`: jump to the word after it.
+pub(crate) const ARM_LDR_PC: u32 = 0xE51F_F004;
+// `add lr, pc, #4`: return past a following `ldr pc, [pc, #-4]; .word`.
+const ARM_ADD_LR_PC_4: u32 = 0xE28F_E004;
+// `add ip, pc, #1; bx ip`: switch to Thumb at the next word.
+pub(crate) const ARM_TO_THUMB: [u32; 2] = [0xE28F_C001, 0xE12F_FF1C];
+pub(crate) const THUMB_NOP: u16 = 0xBF00;
+const THUMB_BLX_IP: u16 = 0x4780 | (IP as u16) << 3;
+
+fn sign_extend(value: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (value << shift) >> shift
+}
+
+fn align4(value: u32) -> u32 {
+    value & !3
+}
+
+/// Whether a code pointer designates Thumb code (bit 0 set).
+pub(crate) fn is_thumb(address: usize) -> bool {
+    address & 1 != 0
+}
+
+// ---- A32 ----
+
+fn is_arm_b_bl(word: u32) -> bool {
+    word & 0x0E00_0000 == 0x0A00_0000 && word >> 28 != 0xF
+}
+
+fn is_arm_blx_imm(word: u32) -> bool {
+    word & 0xFE00_0000 == 0xFA00_0000
+}
+
+// Target of `b`/`bl`/`blx` (immediate) at `pc`; `blx` targets are Thumb, with bit 0 set.
+fn arm_branch_target(word: u32, pc: u32) -> u32 {
+    let offset = sign_extend((word & 0x00FF_FFFF) as i64, 24) << 2;
+    let target = pc.wrapping_add(8).wrapping_add(offset as u32);
+    if is_arm_blx_imm(word) {
+        (target + ((word >> 23) & 2)) | 1
+    } else {
+        target
+    }
+}
+
+// `add rd, pc, #imm` or `sub rd, pc, #imm`: the value written to `rd`.
+fn arm_adr_value(word: u32, pc: u32) -> Option<u32> {
+    let imm12 = word & 0xFFF;
+    let imm = (imm12 & 0xFF).rotate_right(2 * (imm12 >> 8));
+    let base = pc.wrapping_add(8);
+    match word & 0x0FFF_0000 {
+        0x028F_0000 => Some(base.wrapping_add(imm)),
+        0x024F_0000 => Some(base.wrapping_sub(imm)),
+        _ => None,
+    }
+}
+
+// `ldr`/`ldrb rt, [pc, #±imm12]`.
+fn is_arm_ldr_literal(word: u32) -> bool {
+    word & 0x0F3F_0000 == 0x051F_0000
+}
+
+// `ldrh`/`ldrsb`/`ldrsh`/`ldrd` with a PC base, which have no simple register form here.
+fn is_arm_misc_literal(word: u32) -> bool {
+    word & 0x0F6F_0090 == 0x014F_0090 && word & 0x60 != 0
+}
+
+// Any other data-processing or load/store instruction that reads `pc`: as a base, offset or
+// (shifted) operand register, or as the value stored. Relocated verbatim it would see the
+// trampoline's address.
+fn arm_reads_pc(word: u32) -> bool {
+    let reg = |lsb: u32| (word >> lsb) & 0xF == PC;
+    let (reg_shift, extra) = (word & 0x10 != 0, word & 0x90 == 0x90);
+    // Opcode 10xx without S: `mrs`, `msr`, `bx`, `movw`, `movt` and friends.
+    let misc = word & 0x0190_0000 == 0x0100_0000;
+    let store = word & (1 << 20) == 0;
+    match (word >> 25) & 7 {
+        0b000 if extra => word & 0x60 != 0 && (reg(16) || (word & (1 << 22) == 0 && reg(0))),
+        0b000 => !misc && (reg(16) || reg(0) || (reg_shift && reg(8))),
+        0b001 => !misc && reg(16),
+        0b010 => reg(16) || (store && reg(12)),
+        0b011 if !reg_shift => reg(16) || reg(0) || (store && reg(12)),
+        _ => false,
+    }
+}
+
+/// `b`, `bx`, `mov pc`, a load into `pc` or a `pop` of it, unconditionally: execution never falls
+/// through to the next instruction.
+pub(crate) fn arm_ends_flow(word: u32) -> bool {
+    word >> 28 == COND_AL
+        && (word & 0x0F00_0000 == 0x0A00_0000
+            || word & 0x0FFF_FFF0 == 0x012F_FF10
+            || word & 0x0FEF_F000 == 0x01A0_F000
+            || word & 0x0C10_F000 == 0x0410_F000
+            || word & 0x0E10_8000 == 0x0810_8000)
+}
+
+/// Relocate A32 `words` from `src_pc` to `dst_pc`. Out-of-range branches become `ldr pc`
+/// literals, and `adr` and literal loads load the original address from an inline literal.
+pub(crate) fn relocate_arm(words: &[u32], src_pc: u32, dst_pc: u32) -> Result<Vec<u32>> {
+    let mut out: Vec<u32> = Vec::with_capacity(words.len() * 4);
+    for (idx, word) in words.iter().copied().enumerate() {
+        let pc = src_pc + idx as u32 * 4;
+        let out_pc = dst_pc + out.len() as u32 * 4;
+        let cond = word >> 28;
+
+        if is_arm_b_bl(word) || is_arm_blx_imm(word) {
+            let target = arm_branch_target(word, pc);
+            let offset = (target & !1).wrapping_sub(out_pc + 8) as i32 as i64;
+            if (-0x200_0000..0x200_0000).contains(&offset) {
+                // `blx` keeps bit 1 of the offset in its H bit.
+                let (keep, h) = if is_arm_blx_imm(word) {
+                    (0xFE00_0000, ((offset as u32 >> 1) & 1) << 24)
+                } else {
+                    (0xFF00_0000, 0)
+                };
+                out.push((word & keep) | h | ((offset as u32 >> 2) & 0x00FF_FFFF));
+                continue;
+            }
+            let mut expansion = Vec::new();
+            if is_arm_blx_imm(word) || word & (1 << 24) != 0 {
+                expansion.push(ARM_ADD_LR_PC_4);
+            }
+            expansion.extend([ARM_LDR_PC, target]);
+            // b<!cond> past the expansion
+            if cond != COND_AL && cond != 0xF {
+                out.push((cond ^ 1) << 28 | 0x0A00_0000 | (expansion.len() as u32 - 1));
+            }
+            out.extend(expansion);
+            continue;
+        }
+        if let Some(value) = arm_adr_value(word, pc) {
+            let rd = (word >> 12) & 0xF;
+            if rd == PC {
+                return Err(Error::RelocationFailed);
+            }
+            // ldr<c> rd, [pc, #0]; b #0; .word value
+            out.extend([cond << 28 | 0x059F_0000 | rd << 12, 0xEA00_0000, value]);
+            continue;
+        }
+        if is_arm_ldr_literal(word) {
+            let imm = word & 0xFFF;
+            let base = pc.wrapping_add(8);
+            let address = if word & (1 << 23) != 0 {
+                base.wrapping_add(imm)
+            } else {
+                base.wrapping_sub(imm)
+            };
+            let rt = (word >> 12) & 0xF;
+            let byte = word & (1 << 22);
+            if rt == PC && byte != 0 {
+                return Err(Error::RelocationFailed);
+            }
+            // A load into `pc` goes through `ip`.
+            let reg = if rt == PC { IP } else { rt };
+            // ldr<c> reg, [pc, #4]; ldr<c>{b} rt, [reg]; b #0; .word address
+            out.extend([
+                cond << 28 | 0x059F_0004 | reg << 12,
+                cond << 28 | 0x0590_0000 | byte | reg << 16 | rt << 12,
+                0xEA00_0000,
+                address,
+            ]);
+            continue;
+        }
+        if is_arm_misc_literal(word) || (cond != 0xF && arm_reads_pc(word)) {
+            return Err(Error::RelocationFailed);
+        }
+        out.push(word);
+    }
+    if out.is_empty() {
+        return Err(Error::RelocationFailed);
+    }
+    Ok(out)
+}
+
+// ---- T32 ----
+
+fn is_wide(half: u16) -> bool {
+    half >> 11 >= 0b11101
+}
+
+/// Size of the Thumb instruction starting with halfword `half`.
+pub(crate) fn thumb_insn_len(half: u16) -> usize {
+    if is_wide(half) { 4 } else { 2 }
+}
+
+// First halfword in the high half, second in the low half; 16-bit instructions as is.
+fn read_thumb(code: &[u8]) -> Result<u32> {
+    let half = |i: usize| -> Result<u32> {
+        code.get(i..i + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as u32)
+            .ok_or(Error::DecodeFailed)
+    };
+    let first = half(0)?;
+    if is_wide(first as u16) {
+        Ok(first << 16 | half(2)?)
+    } else {
+        Ok(first)
+    }
+}
+
+// Conditions of the instructions an `it` covers; empty for other instructions.
+fn it_conditions(insn: u32) -> Vec<u32> {
+    if insn > 0xFFFF || insn & 0xFF00 != 0xBF00 || insn & 0xF == 0 {
+        return Vec::new();
+    }
+    let first = (insn >> 4) & 0xF;
+    let mask = insn & 0xF;
+    let count = 4 - mask.trailing_zeros();
+    (0..count)
+        .map(|k| match k {
+            0 => first,
+            k => (first & 0xE) | ((mask >> (4 - k)) & 1),
+        })
+        .collect()
+}
+
+// `bl` (T1) or `b.w` (T4) offset, relative to `pc + 4`; for `blx` (T2), to `Align(pc + 4, 4)`.
+fn thumb_bl_offset(insn: u32) -> i64 {
+    let (first, second) = (insn >> 16, insn & 0xFFFF);
+    let s = (first >> 10) & 1;
+    let i1 = !((second >> 13) ^ s) & 1;
+    let i2 = !((second >> 11) ^ s) & 1;
+    let imm = s << 24 | i1 << 23 | i2 << 22 | (first & 0x3FF) << 12 | (second & 0x7FF) << 1;
+    sign_extend(imm as i64, 25)
+}
+
+fn thumb_cond_w_offset(insn: u32) -> i64 {
+    let (first, second) = (insn >> 16, insn & 0xFFFF);
+    let imm = ((first >> 10) & 1) << 20
+        | ((second >> 11) & 1) << 19
+        | ((second >> 13) & 1) << 18
+        | (first & 0x3F) << 12
+        | (second & 0x7FF) << 1;
+    sign_extend(imm as i64, 21)
+}
+
+// `second` is the fixed bits of the second halfword: 0x9000 `b.w`, 0xD000 `bl`, 0xC000 `blx`.
+fn encode_thumb_bl(offset: i64, second: u32) -> u32 {
+    let imm = offset as u32;
+    let s = (imm >> 24) & 1;
+    let j1 = !((imm >> 23) ^ s) & 1;
+    let j2 = !((imm >> 22) ^ s) & 1;
+    let low = if second == 0xC000 {
+        imm & 0xFFC
+    } else {
+        imm & 0xFFE
+    };
+    (0xF000 | s << 10 | (imm >> 12) & 0x3FF) << 16 | second | j1 << 13 | j2 << 11 | low >> 1
+}
+
+fn encode_thumb_cond_w(cond: u32, offset: i64) -> u32 {
+    let imm = offset as u32;
+    (0xF000 | ((imm >> 20) & 1) << 10 | cond << 6 | (imm >> 12) & 0x3F) << 16
+        | 0x8000
+        | ((imm >> 18) & 1) << 13
+        | ((imm >> 19) & 1) << 11
+        | (imm >> 1) & 0x7FF
+}
+
+// Register-offset form (`ldr{b,h,sb,sh}.w rt, [rn, #0]`) of a 32-bit literal load's first
+// halfword.
+fn thumb_load_register_form(first: u32, rn: u32) -> u32 {
+    (first & 0xFF70) | 0x80 | rn
+}
+
+/// Thumb `b`/`b.w`, `bx`, `mov pc`, `pop {.., pc}` or a `ldr.w` into `pc` outside an IT block:
+/// execution never falls through to the next instruction.
+fn thumb_ends_flow(insn: u32) -> bool {
+    if insn > 0xFFFF {
+        let (first, second) = (insn >> 16, insn & 0xFFFF);
+        return (first & 0xF800 == 0xF000 && second & 0xD000 == 0x9000)
+            || (first & 0xFFF0 == 0xF8D0 && second >> 12 == PC)
+            || (first == 0xE8BD && second & 0x8000 != 0);
+    }
+    insn & 0xF800 == 0xE000
+        || insn & 0xFF87 == 0x4700
+        || insn & 0xFF87 == 0x4687
+        || insn & 0xFF00 == 0xBD00
+}
+
+/// Bytes of whole instructions covering at least `min` bytes of `code` (Thumb with `thumb`), never
+/// splitting an IT block. `PatchTooSmall` if the function ends before that, or if a stolen branch
+/// targets the middle of the stolen bytes, which the patch overwrites.
+pub(crate) fn steal_len(code: &[u8], thumb: bool, min: usize) -> Result<usize> {
+    let mut len = 0;
+    let mut in_it = 0usize;
+    while len < min || in_it > 0 {
+        if len > 0 && ends_flow_before(code, thumb, len)? {
+            return Err(Error::PatchTooSmall);
+        }
+        if !thumb {
+            len += 4;
+            continue;
+        }
+        let insn = read_thumb(&code[len..])?;
+        len += if insn > 0xFFFF { 4 } else { 2 };
+        in_it = match it_conditions(insn).len() {
+            0 => in_it.saturating_sub(1),
+            n => n,
+        };
+    }
+    if branches_into(code, thumb, len)? {
+        return Err(Error::PatchTooSmall);
+    }
+    Ok(len)
+}
+
+// Whether a `b`, `b<cond>` or `cbz`/`cbnz` in the first `len` bytes of `code` targets an offset
+// inside them other than 0, for `steal_len`.
+fn branches_into(code: &[u8], thumb: bool, len: usize) -> Result<bool> {
+    let inside = |target: u32| (1..len as u32).contains(&target);
+    let mut off = 0;
+    while off < len {
+        let pc = off as u32;
+        if !thumb {
+            let b = code.get(off..off + 4).ok_or(Error::DecodeFailed)?;
+            let word = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            if is_arm_b_bl(word) && word & (1 << 24) == 0 && inside(arm_branch_target(word, pc)) {
+                return Ok(true);
+            }
+            off += 4;
+            continue;
+        }
+        let insn = read_thumb(&code[off..])?;
+        off += if insn > 0xFFFF { 4 } else { 2 };
+        if thumb_branch_target(insn, pc).is_some_and(inside) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Target of a Thumb `b`/`b.w`, `b<cond>`/`b<cond>.w` or `cbz`/`cbnz` at `pc`, without the Thumb
+// bit.
+fn thumb_branch_target(insn: u32, pc: u32) -> Option<u32> {
+    let offset = if insn > 0xFFFF {
+        let (first, second) = (insn >> 16, insn & 0xFFFF);
+        if first & 0xF800 != 0xF000 {
+            return None;
+        } else if second & 0xD000 == 0x9000 {
+            thumb_bl_offset(insn)
+        } else if second & 0xD000 == 0x8000 && (first >> 6) & 0xE != 0xE {
+            thumb_cond_w_offset(insn)
+        } else {
+            return None;
+        }
+    } else if insn & 0xF000 == 0xD000 && (insn >> 8) & 0xF < 0xE {
+        sign_extend((insn & 0xFF) as i64, 8) * 2
+    } else if insn & 0xF800 == 0xE000 {
+        sign_extend((insn & 0x7FF) as i64, 11) * 2
+    } else if insn & 0xF500 == 0xB100 {
+        (((insn >> 9) & 1) << 6 | ((insn >> 3) & 0x1F) << 1) as i64
+    } else {
+        return None;
+    };
+    Some((pc + 4).wrapping_add(offset as u32))
+}
+
+// Whether the last instruction before `code[at..]` ends control flow, for `steal_len`.
+fn ends_flow_before(code: &[u8], thumb: bool, at: usize) -> Result<bool> {
+    if !thumb {
+        let b = code.get(at - 4..at).ok_or(Error::DecodeFailed)?;
+        return Ok(arm_ends_flow(u32::from_le_bytes([b[0], b[1], b[2], b[3]])));
+    }
+    // Walk from the start: Thumb can't be decoded backwards, and IT state matters.
+    let mut off = 0;
+    let mut it = VecDeque::new();
+    let mut last = (0, false);
+    while off < at {
+        let insn = read_thumb(&code[off..])?;
+        let conditional = it.pop_front().is_some();
+        it.extend(it_conditions(insn));
+        last = (insn, conditional);
+        off += if insn > 0xFFFF { 4 } else { 2 };
+    }
+    Ok(!last.1 && thumb_ends_flow(last.0))
+}
+
+/// Thumb code being written at `base`.
+struct Emitter {
+    base: u32,
+    out: Vec<u8>,
+}
+
+impl Emitter {
+    fn pc(&self) -> u32 {
+        self.base + self.out.len() as u32
+    }
+
+    fn half(&mut self, half: u32) {
+        self.out.extend((half as u16).to_le_bytes());
+    }
+
+    // A 32-bit instruction (first halfword high) or a 16-bit one.
+    fn insn(&mut self, insn: u32) {
+        if insn > 0xFFFF {
+            self.half(insn >> 16);
+        }
+        self.half(insn & 0xFFFF);
+    }
+
+    /// `ldr.w rt, [pc, #lit]`, then `then`, and a `b.n` past the literal unless execution can't
+    /// reach it; the literal is word-aligned, padded with `nop`s.
+    fn literal(&mut self, rt: u32, value: u32, then: &[u32], falls_through: bool) {
+        let pc = self.pc();
+        let len: u32 = 4 + then
+            .iter()
+            .map(|&i| if i > 0xFFFF { 4 } else { 2 })
+            .sum::<u32>();
+        let lit = (pc + len + falls_through as u32 * 2 + 3) & !3;
+        self.insn(0xF8DF_0000 | rt << 12 | (lit - align4(pc + 4)));
+        for &insn in then {
+            self.insn(insn);
+        }
+        if falls_through {
+            let b = self.pc();
+            self.half(0xE000 | ((lit + 4 - (b + 4)) >> 1));
+        }
+        while self.pc() < lit {
+            self.half(THUMB_NOP as u32);
+        }
+        self.out.extend(value.to_le_bytes());
+    }
+
+    /// Jump to `target` (bit 0 selecting Thumb).
+    fn jump(&mut self, target: u32) {
+        let offset = (target & !1).wrapping_sub(self.pc() + 4) as i32 as i64;
+        if is_thumb(target as usize) && (-0x100_0000..0x100_0000).contains(&offset) {
+            self.insn(encode_thumb_bl(offset, 0x9000));
+        } else {
+            self.literal(PC, target, &[], false);
+        }
+    }
+
+    /// Call `target` (bit 0 selecting Thumb), returning to the next instruction.
+    fn call(&mut self, target: u32) {
+        let pc = self.pc();
+        if is_thumb(target as usize) {
+            let offset = (target & !1).wrapping_sub(pc + 4) as i32 as i64;
+            if (-0x100_0000..0x100_0000).contains(&offset) {
+                return self.insn(encode_thumb_bl(offset, 0xD000));
+            }
+        } else {
+            let offset = target.wrapping_sub(align4(pc + 4)) as i32 as i64;
+            if target & 3 == 0 && (-0x100_0000..0x100_0000).contains(&offset) {
+                return self.insn(encode_thumb_bl(offset, 0xC000));
+            }
+        }
+        self.literal(IP, target, &[THUMB_BLX_IP as u32], true);
+    }
+
+    /// Run `emit` only when `cond` holds, by branching around it on the inverse.
+    fn conditional(&mut self, cond: u32, emit: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        if cond == COND_AL {
+            return emit(self);
+        }
+        let at = self.out.len();
+        self.half(0);
+        emit(self)?;
+        let skip = (self.out.len() - at - 4) as u32 >> 1;
+        if skip > 0x7F {
+            return Err(Error::RelocationFailed);
+        }
+        let b = 0xD000 | (cond ^ 1) << 8 | skip;
+        self.out[at..at + 2].copy_from_slice(&(b as u16).to_le_bytes());
+        Ok(())
+    }
+
+    /// Conditional branch: `b<cond>.w` within ±1 MiB, else around a jump.
+    fn branch(&mut self, cond: u32, target: u32) -> Result<()> {
+        let offset = (target & !1).wrapping_sub(self.pc() + 4) as i32 as i64;
+        if cond == COND_AL {
+            self.jump(target);
+        } else if (-0x10_0000..0x10_0000).contains(&offset) {
+            self.insn(encode_thumb_cond_w(cond, offset));
+        } else {
+            self.conditional(cond, |e| {
+                e.jump(target);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Relocate the whole Thumb instructions in `code` from `src_pc` to `dst_pc` (both without the
+/// Thumb bit). Branches are re-encoded or become `ldr.w pc` literals, `adr` and literal loads load
+/// the original address from an inline literal, and IT blocks are split so that each covered
+/// instruction gets its own `it` (or, when expanded, a branch around it on the inverse condition).
+/// `ip` is clobbered by far calls and by literal loads into `pc`.
+pub(crate) fn relocate_thumb(code: &[u8], src_pc: u32, dst_pc: u32) -> Result<Vec<u8>> {
+    let mut e = Emitter {
+        base: dst_pc,
+        out: Vec::with_capacity(code.len() * 4),
+    };
+    let mut it: VecDeque<u32> = VecDeque::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let insn = read_thumb(&code[offset..]).map_err(|_| Error::RelocationFailed)?;
+        let pc = src_pc + offset as u32;
+        let size = if insn > 0xFFFF { 4 } else { 2 };
+        offset += size;
+        let cond = it.pop_front().unwrap_or(COND_AL);
+        let conds = it_conditions(insn);
+        if !conds.is_empty() {
+            it.extend(conds);
+            continue;
+        }
+        let literal_base = align4(pc + 4);
+
+        if size == 2 {
+            let h = insn;
+            if h & 0xF000 == 0xD000 && (h >> 8) & 0xF < 0xE {
+                let target = (pc + 4).wrapping_add((sign_extend((h & 0xFF) as i64, 8) * 2) as u32);
+                e.branch((h >> 8) & 0xF, target | 1)?;
+            } else if h & 0xF800 == 0xE000 {
+                let target =
+                    (pc + 4).wrapping_add((sign_extend((h & 0x7FF) as i64, 11) * 2) as u32);
+                e.branch(cond, target | 1)?;
+            } else if h & 0xF500 == 0xB100 {
+                // cbz/cbnz rn, target: keep it if still in reach, else the inverse around a jump.
+                let target = pc + 4 + (((h >> 9) & 1) << 6 | ((h >> 3) & 0x1F) << 1);
+                let rn = h & 7;
+                e.conditional(cond, |e| {
+                    let reach = target.wrapping_sub(e.pc() + 4);
+                    if reach <= 126 {
+                        e.half(
+                            0xB100
+                                | (h & 0x800)
+                                | (reach >> 6) << 9
+                                | (reach >> 1 & 0x1F) << 3
+                                | rn,
+                        );
+                    } else {
+                        let at = e.out.len();
+                        e.half(0);
+                        e.jump(target | 1);
+                        let skip = (e.out.len() - at - 4) as u32;
+                        let inverse =
+                            0xB100 | (!h & 0x800) | (skip >> 6) << 9 | (skip >> 1 & 0x1F) << 3 | rn;
+                        e.out[at..at + 2].copy_from_slice(&(inverse as u16).to_le_bytes());
+                    }
+                    Ok(())
+                })?;
+            } else if h & 0xF800 == 0xA000 {
+                let value = literal_base + (h & 0xFF) * 4;
+                e.conditional(cond, |e| {
+                    e.literal((h >> 8) & 7, value, &[], true);
+                    Ok(())
+                })?;
+            } else if h & 0xF800 == 0x4800 {
+                let address = literal_base + (h & 0xFF) * 4;
+                let rt = (h >> 8) & 7;
+                e.conditional(cond, |e| {
+                    e.literal(rt, address, &[0xF8D0_0000 | rt << 16 | rt << 12], true);
+                    Ok(())
+                })?;
+            } else if h & 0xFC78 == 0x4478 {
+                // add/mov/cmp/bx/blx reading pc
+                return Err(Error::RelocationFailed);
+            } else {
+                copy(&mut e, cond, insn);
+            }
+            continue;
+        }
+
+        let (first, second) = (insn >> 16, insn & 0xFFFF);
+        if first & 0xF800 == 0xF000 && second & 0xD000 == 0x9000 {
+            let target = (pc + 4).wrapping_add(thumb_bl_offset(insn) as u32);
+            e.branch(cond, target | 1)?;
+        } else if first & 0xF800 == 0xF000 && second & 0xD000 == 0x8000 && (first >> 6) & 0xE != 0xE
+        {
+            let target = (pc + 4).wrapping_add(thumb_cond_w_offset(insn) as u32);
+            e.branch((first >> 6) & 0xF, target | 1)?;
+        } else if first & 0xF800 == 0xF000 && second & 0xD000 == 0xD000 {
+            let target = (pc + 4).wrapping_add(thumb_bl_offset(insn) as u32);
+            e.conditional(cond, |e| {
+                e.call(target | 1);
+                Ok(())
+            })?;
+        } else if first & 0xF800 == 0xF000 && second & 0xD001 == 0xC000 {
+            let target = literal_base.wrapping_add(thumb_bl_offset(insn) as u32);
+            e.conditional(cond, |e| {
+                e.call(target);
+                Ok(())
+            })?;
+        } else if matches!(first & 0xFBFF, 0xF20F | 0xF2AF) && second & 0x8000 == 0 {
+            let imm = ((first >> 10) & 1) << 11 | ((second >> 12) & 7) << 8 | (second & 0xFF);
+            let value = if first & 0xFBFF == 0xF20F {
+                literal_base.wrapping_add(imm)
+            } else {
+                literal_base.wrapping_sub(imm)
+            };
+            let rd = (second >> 8) & 0xF;
+            if rd == PC {
+                return Err(Error::RelocationFailed);
+            }
+            e.conditional(cond, |e| {
+                e.literal(rd, value, &[], true);
+                Ok(())
+            })?;
+        } else if matches!(first & 0xFF7F, 0xF85F | 0xF81F | 0xF83F | 0xF91F | 0xF93F) {
+            let imm = second & 0xFFF;
+            let address = if first & 0x80 != 0 {
+                literal_base.wrapping_add(imm)
+            } else {
+                literal_base.wrapping_sub(imm)
+            };
+            let rt = second >> 12;
+            let word = first & 0xFF7F == 0xF85F;
+            if rt == PC && !word {
+                // pld/pli: only a hint
+                continue;
+            }
+            let reg = if rt == PC { IP } else { rt };
+            let load = thumb_load_register_form(first, reg) << 16 | rt << 12;
+            e.conditional(cond, |e| {
+                e.literal(reg, address, &[load], rt != PC);
+                Ok(())
+            })?;
+        } else if first & 0xFE5F == 0xE85F
+            || first & 0xFF3F == 0xED1F
+            || (first == 0xE8DF && second & 0xFFE0 == 0xF000)
+        {
+            // ldrd/vldr literal, tbb/tbh [pc, rm]
+            return Err(Error::RelocationFailed);
+        } else {
+            copy(&mut e, cond, insn);
+        }
+    }
+    if e.out.is_empty() {
+        return Err(Error::RelocationFailed);
+    }
+    Ok(e.out)
+}
+
+// Copy a position-independent instruction, in an IT block of its own if it was in one.
+fn copy(e: &mut Emitter, cond: u32, insn: u32) {
+    if cond != COND_AL {
+        e.half(0xBF08 | cond << 4);
+    }
+    e.insn(insn);
+}
+
+/// Absolute Thumb jump placed at `from`: `ldr.w pc, [pc, #lit]` and the literal `to` (bit 0
+/// selecting the mode); 8 bytes, or 10 when `from` isn't word-aligned.
+pub(crate) fn thumb_abs_jump(from: u32, to: u32) -> Vec<u8> {
+    let mut e = Emitter {
+        base: from,
+        out: Vec::new(),
+    };
+    e.literal(PC, to, &[], false);
+    e.out
+}
+
+/// Thumb jump from `from` to `to` (bit 0 selecting the mode): `b.w` within ±16 MiB, else absolute.
+pub(crate) fn thumb_jump(from: u32, to: u32) -> Vec<u8> {
+    let mut e = Emitter {
+        base: from,
+        out: Vec::new(),
+    };
+    e.jump(to);
+    e.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn halves(insns: &[u32]) -> Vec<u8> {
+        let mut e = Emitter {
+            base: 0,
+            out: Vec::new(),
+        };
+        for &insn in insns {
+            e.insn(insn);
+        }
+        e.out
+    }
+
+    fn arm_b(cond: u32, link: bool, offset: i64) -> u32 {
+        cond << 28 | 0x0A00_0000 | (link as u32) << 24 | ((offset >> 2) as u32 & 0x00FF_FFFF)
+    }
+
+    #[test]
+    fn mode_from_pointer() {
+        assert!(is_thumb(0x1001) && !is_thumb(0x1000));
+    }
+
+    #[test]
+    fn thumb_immediates_round_trip() {
+        for offset in [-0x100_0000, -2, 2, 0x12_3456, 0xFF_FFFE] {
+            assert_eq!(thumb_bl_offset(encode_thumb_bl(offset, 0xD000)), offset);
+            assert_eq!(thumb_bl_offset(encode_thumb_bl(offset, 0x9000)), offset);
+        }
+        for offset in [-0x100_0000, 4, 0xFF_FFFC] {
+            assert_eq!(thumb_bl_offset(encode_thumb_bl(offset, 0xC000)), offset);
+        }
+        for offset in [-0x10_0000, -2, 0x800, 0xF_FFFE] {
+            assert_eq!(thumb_cond_w_offset(encode_thumb_cond_w(1, offset)), offset);
+        }
+    }
+
+    #[test]
+    fn it_blocks() {
+        // itte eq: eq, eq, ne
+        assert_eq!(it_conditions(0xBF06), [0, 0, 1]);
+        // it ne
+        assert_eq!(it_conditions(0xBF18), [1]);
+        // ittet gt: gt, gt, le, gt
+        assert_eq!(it_conditions(0xBFC5), [12, 12, 13, 12]);
+        assert!(it_conditions(THUMB_NOP as u32).is_empty());
+
+        // push {r7, lr}; ittt eq; moveq r0, #1; moveq r1, #2; moveq r2, #3; mov r3, #4
+        let code = halves(&[0xB580, 0xBF02, 0x2001, 0x2102, 0x2203, 0x2304]);
+        // The patch covers the `it`, so the whole block is stolen.
+        assert_eq!(steal_len(&code, true, 4).unwrap(), 10);
+        assert_eq!(steal_len(&code, true, 2).unwrap(), 2);
+    }
+
+    #[test]
+    fn steal_stops_at_returns() {
+        // bx lr; nop
+        let code = halves(&[0x4770, THUMB_NOP as u32, THUMB_NOP as u32]);
+        assert!(matches!(
+            steal_len(&code, true, 4),
+            Err(Error::PatchTooSmall)
+        ));
+        // it eq; bxeq lr; nop: the return is conditional
+        let code = halves(&[0xBF08, 0x4770, THUMB_NOP as u32]);
+        assert_eq!(steal_len(&code, true, 6).unwrap(), 6);
+        // ARM: bx lr
+        let code: Vec<u8> = [0xE12F_FF1Eu32, 0xE320_F000]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        assert!(matches!(
+            steal_len(&code, false, 8),
+            Err(Error::PatchTooSmall)
+        ));
+        assert!(!arm_ends_flow(0x012F_FF1E));
+    }
+
+    #[test]
+    fn steal_stops_at_internal_branches() {
+        // cbz r0, +4; nop; nop; nop
+        let nop = THUMB_NOP as u32;
+        let code = halves(&[0xB100, nop, nop, nop]);
+        assert!(matches!(
+            steal_len(&code, true, 6),
+            Err(Error::PatchTooSmall)
+        ));
+        assert_eq!(steal_len(&code, true, 4).unwrap(), 4);
+        // nop; bne.n +0; nop; nop
+        let code = halves(&[nop, 0xD100, nop, nop]);
+        assert!(matches!(
+            steal_len(&code, true, 8),
+            Err(Error::PatchTooSmall)
+        ));
+        assert_eq!(steal_len(&code, true, 6).unwrap(), 6);
+        // Branching back to the start is fine: nop; bne.n -6; b.n -8
+        let code = halves(&[nop, 0xD1FD, 0xE7FC]);
+        assert_eq!(steal_len(&code, true, 6).unwrap(), 6);
+        // beq.w +2
+        let code = halves(&[encode_thumb_cond_w(0, 2), nop, nop]);
+        assert!(matches!(
+            steal_len(&code, true, 8),
+            Err(Error::PatchTooSmall)
+        ));
+        assert_eq!(steal_len(&code, true, 6).unwrap(), 6);
+        // ARM: beq +8; nop; nop
+        let code: Vec<u8> = [arm_b(0, false, 0), 0xE320_F000, 0xE320_F000]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        assert!(matches!(
+            steal_len(&code, false, 12),
+            Err(Error::PatchTooSmall)
+        ));
+        assert_eq!(steal_len(&code, false, 8).unwrap(), 8);
+        // bl is a call, not a jump within the function.
+        let code: Vec<u8> = [arm_b(0xE, true, 0), 0xE320_F000, 0xE320_F000]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        assert_eq!(steal_len(&code, false, 12).unwrap(), 12);
+    }
+
+    #[test]
+    fn absolute_jumps() {
+        assert_eq!(thumb_abs_jump(0x1000, 0x1234_5679).len(), 8);
+        let jump = thumb_abs_jump(0x1002, 0x1234_5679);
+        assert_eq!(jump.len(), 10);
+        assert_eq!(&jump[6..], &0x1234_5679u32.to_le_bytes());
+        // ldr.w pc, [pc, #4]
+        assert_eq!(&jump[..4], &[0xDF, 0xF8, 0x04, 0xF0]);
+        assert_eq!(thumb_jump(0x1000, 0x2001).len(), 4);
+        assert_eq!(thumb_jump(0x1000, 0x2000).len(), 8);
+    }
+
+    const SRC: u32 = 0x4012_3000;
+    // Near (re-encoded in place), beyond Thumb/ARM branch reach, and not word-aligned.
+    const DSTS: [u32; 3] = [SRC + 0x8_0000, 0xB000_0000, 0xB000_0002];
+
+    fn start() -> emu::Cpu {
+        let mut cpu = emu::Cpu::default();
+        for (i, r) in cpu.r.iter_mut().enumerate() {
+            *r = 0x1111_1111u32.wrapping_mul(i as u32 % 7) + i as u32;
+        }
+        cpu
+    }
+
+    #[test]
+    fn emulated_arm() {
+        let sequences: [&[u32]; 7] = [
+            &[arm_b(COND_AL, false, -0x100)],
+            // mov r0, #1; bl +0x2000
+            &[0xE3A0_0001, arm_b(COND_AL, true, 0x2000)],
+            // beq / blne
+            &[arm_b(0, false, 0x40)],
+            &[arm_b(1, true, -0x40)],
+            // blx (to Thumb) +0x102
+            &[0xFB00_0040],
+            // adr r2, #0x40; sub r3, pc, #8
+            &[0xE28F_2040, 0xE24F_3008],
+            // ldr r0, [pc, #8]; ldrbne r1, [pc, #-4]; ldr pc, [pc, #-4]
+            &[0xE59F_0008, 0x155F_1004, 0xE51F_F004],
+        ];
+        for words in sequences {
+            for nzcv in [0, 0x4000_0000] {
+                let mut cpu = start();
+                cpu.nzcv = nzcv;
+                emu::assert_equivalent_arm(words, SRC, &DSTS, &cpu);
+            }
+        }
+    }
+
+    #[test]
+    fn emulated_arm_register_operands() {
+        // add r3, r2, r3; ldr r4, [r1, r0]: copied as they are.
+        emu::assert_equivalent_arm(&[0xE082_3003, 0xE791_4000], SRC, &DSTS, &start());
+        // add r3, pc, r3; ldr r3, [pc, r3]; ldr r3, [r2, pc]
+        for word in [0xE08F_3003u32, 0xE79F_3003, 0xE792_300F] {
+            // Copied, they would compute from the trampoline's address.
+            let run = |at: u32| {
+                let mut mem = emu::Memory::default();
+                mem.write(at, &word.to_le_bytes());
+                let mut cpu = start();
+                cpu.pc = at;
+                cpu.run(&mem, at..at + 4, 2);
+                cpu.r[3]
+            };
+            assert_ne!(run(SRC), run(DSTS[0]));
+            assert!(matches!(
+                relocate_arm(&[word], SRC, DSTS[0]),
+                Err(Error::RelocationFailed)
+            ));
+        }
+        // mov r0, pc; cmp pc, #0; str pc, [sp, #-4]; strh r0, [pc, r1]; add r0, r1, pc, lsl r2
+        for word in [
+            0xE1A0_000F,
+            0xE35F_0000,
+            0xE50D_F004,
+            0xE18F_00B1,
+            0xE081_021F,
+        ] {
+            assert!(matches!(
+                relocate_arm(&[word], SRC, DSTS[0]),
+                Err(Error::RelocationFailed)
+            ));
+        }
+        // bx lr; movw r0, #0xffff; push {r4, lr}; pop {r4, pc}: no `pc` operand.
+        for word in [0xE12F_FF1E, 0xE30F_0FFF, 0xE92D_4010, 0xE8BD_8010] {
+            assert!(relocate_arm(&[word], SRC, DSTS[0]).is_ok());
+        }
+    }
+
+    #[test]
+    fn emulated_thumb() {
+        let sequences: [&[u32]; 10] = [
+            // b.n -0x20; b.w +0x1000
+            &[0xE7F0],
+            &[encode_thumb_bl(0x1000, 0x9000)],
+            // beq.n +0x10; bne.w -0x800
+            &[0xD008],
+            &[encode_thumb_cond_w(1, -0x800)],
+            // bl +0x200; blx -0x1000 (to ARM)
+            &[encode_thumb_bl(0x200, 0xD000)],
+            &[THUMB_NOP as u32, encode_thumb_bl(-0x1000, 0xC000)],
+            // cbz r0, +0x20; cbnz r1, +0x4
+            &[0xB180],
+            &[0xB909],
+            // adr r1, #0x10; ldr r2, [pc, #8]; adr.w r3, #-0x20; ldr.w r4, [pc, #0x100]
+            &[0xA104, 0x4A02, 0xF2AF_0320, 0xF8DF_4100],
+            // ldrsh.w r5, [pc, #-2]; ldr.w pc, [pc, #4]
+            &[0xF93F_5002, 0xF8DF_F004],
+        ];
+        for insns in sequences {
+            for (nzcv, r0) in [(0, 0), (0x4000_0000, 5)] {
+                let mut cpu = start();
+                cpu.nzcv = nzcv;
+                cpu.r[0] = r0;
+                cpu.r[1] = r0;
+                emu::assert_equivalent_thumb(&halves(insns), SRC, &DSTS, &cpu);
+            }
+        }
+    }
+
+    #[test]
+    fn emulated_it_blocks() {
+        let sequences: [&[u32]; 3] = [
+            // itte eq; moveq r0, #1; moveq r1, #2; movne r2, #3
+            &[0xBF06, 0x2001, 0x2102, 0x2203],
+            // ite ne; ldrne r2, [pc, #4]; beq.w +0x4000 (last in the block)
+            &[0xBF14, 0x4A01, encode_thumb_bl(0x4000, 0x9000)],
+            // itt eq; adreq r1, #8; bleq +0x1000
+            &[0xBF04, 0xA102, encode_thumb_bl(0x1000, 0xD000)],
+        ];
+        for insns in sequences {
+            for nzcv in [0, 0x4000_0000] {
+                let mut cpu = start();
+                cpu.nzcv = nzcv;
+                emu::assert_equivalent_thumb(&halves(insns), SRC, &DSTS, &cpu);
+            }
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! Test-only interpreter for the A32 and T32 subset the relocator reads or writes: branches (with
+//! interworking), `add`/`sub`/`mov` immediates, `adr`, word, byte and halfword loads, and IT blocks;
+//! plus the A32 `add` and `ldr` register forms.
+//!
+//! Memory is a synthetic image: every byte that wasn't written reads as a function of its address,
+//! so loads see the same data wherever the code runs from.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct Cpu {
+    /// `r0`..`r14`; `r[15]` is unused, the PC is `pc`.
+    pub r: [u32; 16],
+    pub pc: u32,
+    pub thumb: bool,
+    pub nzcv: u32,
+    /// ITSTATE, as in the CPSR.
+    pub it: u32,
+}
+
+#[derive(Default)]
+pub(super) struct Memory {
+    written: HashMap<u32, u8>,
+}
+
+impl Memory {
+    pub fn write(&mut self, address: u32, bytes: &[u8]) {
+        for (i, b) in bytes.iter().enumerate() {
+            self.written.insert(address + i as u32, *b);
+        }
+    }
+
+    fn byte(&self, address: u32) -> u8 {
+        self.written.get(&address).copied().unwrap_or_else(|| {
+            let h = (address as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            (h >> 56) as u8
+        })
+    }
+
+    fn read(&self, address: u32, len: usize) -> u32 {
+        (0..len).fold(0, |acc, i| {
+            acc | (self.byte(address.wrapping_add(i as u32)) as u32) << (8 * i)
+        })
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+impl Cpu {
+    fn holds(&self, cond: u32) -> bool {
+        let n = self.nzcv & 0x8000_0000 != 0;
+        let z = self.nzcv & 0x4000_0000 != 0;
+        let c = self.nzcv & 0x2000_0000 != 0;
+        let v = self.nzcv & 0x1000_0000 != 0;
+        let base = match cond >> 1 {
+            0 => z,
+            1 => c,
+            2 => n,
+            3 => v,
+            4 => c && !z,
+            5 => n == v,
+            6 => n == v && !z,
+            _ => return true,
+        };
+        if cond & 1 != 0 { !base } else { base }
+    }
+
+    // `pc` as an operand: 8 ahead in ARM state, 4 in Thumb.
+    fn reg(&self, r: u32) -> u32 {
+        match r {
+            15 if self.thumb => self.pc + 4,
+            15 => self.pc + 8,
+            r => self.r[r as usize],
+        }
+    }
+
+    // Branch with interworking: bit 0 selects Thumb.
+    fn bx(&mut self, target: u32) -> u32 {
+        self.thumb = target & 1 != 0;
+        target & !1
+    }
+
+    /// Execute one instruction.
+    pub fn step(&mut self, mem: &Memory) {
+        if self.thumb {
+            self.step_thumb(mem);
+        } else {
+            self.step_arm(mem);
+        }
+    }
+
+    fn step_arm(&mut self, mem: &Memory) {
+        let pc = self.pc;
+        let w = mem.read(pc, 4);
+        let mut next = pc + 4;
+        let rd = (w >> 12) & 0xF;
+        let rn = (w >> 16) & 0xF;
+        if w >> 28 == 0xF {
+            assert_eq!(
+                w & 0xFE00_0000,
+                0xFA00_0000,
+                "unsupported {w:#010x} at {pc:#x}"
+            );
+            // blx (immediate)
+            self.r[14] = next;
+            let offset = sign_extend(w & 0x00FF_FFFF, 24) << 2 | (w >> 23) & 2;
+            self.thumb = true;
+            self.pc = (pc + 8).wrapping_add(offset);
+            return;
+        }
+        if !self.holds(w >> 28) {
+            self.pc = next;
+            return;
+        }
+        if w & 0x0E00_0000 == 0x0A00_0000 {
+            if w & (1 << 24) != 0 {
+                self.r[14] = next;
+            }
+            next = (pc + 8).wrapping_add(sign_extend(w & 0x00FF_FFFF, 24) << 2);
+        } else if w & 0x0FFF_FFD0 == 0x012F_FF10 {
+            // bx / blx (register)
+            let target = self.reg(w & 0xF);
+            if w & 0x20 != 0 {
+                self.r[14] = next;
+            }
+            next = self.bx(target);
+        } else if w & 0x0F30_0000 == 0x0510_0000 {
+            // ldr{b} rt, [rn, #±imm12]
+            let imm = w & 0xFFF;
+            let base = self.reg(rn);
+            let address = if w & (1 << 23) != 0 {
+                base.wrapping_add(imm)
+            } else {
+                base.wrapping_sub(imm)
+            };
+            let value = mem.read(address, if w & (1 << 22) != 0 { 1 } else { 4 });
+            if rd == 15 {
+                next = self.bx(value);
+            } else {
+                self.r[rd as usize] = value;
+            }
+        } else if w & 0x0FF0_0FF0 == 0x0790_0000 || w & 0x0FF0_0FF0 == 0x0080_0000 {
+            // ldr rt, [rn, rm] / add rd, rn, rm
+            let sum = self.reg(rn).wrapping_add(self.reg(w & 0xF));
+            assert_ne!(rd, 15, "unsupported {w:#010x} at {pc:#x}");
+            self.r[rd as usize] = if w & 0x0400_0000 != 0 {
+                mem.read(sum, 4)
+            } else {
+                sum
+            };
+        } else if w & 0x0FE0_0000 == 0x0280_0000 || w & 0x0FE0_0000 == 0x0240_0000 {
+            // add / sub rd, rn, #imm
+            let imm12 = w & 0xFFF;
+            let imm = (imm12 & 0xFF).rotate_right(2 * (imm12 >> 8));
+            let value = if w & 0x0FE0_0000 == 0x0280_0000 {
+                self.reg(rn).wrapping_add(imm)
+            } else {
+                self.reg(rn).wrapping_sub(imm)
+            };
+            assert_ne!(rd, 15, "unsupported {w:#010x} at {pc:#x}");
+            self.r[rd as usize] = value;
+        } else if w & 0x0FFF_0000 == 0x03A0_0000 {
+            let imm12 = w & 0xFFF;
+            self.r[rd as usize] = (imm12 & 0xFF).rotate_right(2 * (imm12 >> 8));
+        } else if w & 0x0FFF_FFFF != 0x0320_F000 {
+            panic!("unsupported instruction {w:#010x} at {pc:#x}");
+        }
+        self.pc = next;
+    }
+
+    fn step_thumb(&mut self, mem: &Memory) {
+        let pc = self.pc;
+        let h = mem.read(pc, 2);
+        let wide = h >> 11 >= 0b11101;
+        let mut next = pc + if wide { 4 } else { 2 };
+        let it = self.it;
+        if it & 0xF != 0 {
+            // ITAdvance
+            self.it = if it & 7 == 0 {
+                0
+            } else {
+                (it & 0xE0) | ((it << 1) & 0x1F)
+            };
+            if !self.holds(it >> 4) {
+                self.pc = next;
+                return;
+            }
+        }
+        if wide {
+            next = self.step_thumb_wide(mem, h << 16 | mem.read(pc + 2, 2), next);
+        } else if h & 0xFF00 == 0xBF00 && h & 0xF != 0 {
+            self.it = h & 0xFF;
+        } else if h & 0xF000 == 0xD000 && (h >> 8) & 0xF < 0xE {
+            if self.holds((h >> 8) & 0xF) {
+                next = (pc + 4).wrapping_add(sign_extend(h & 0xFF, 8) << 1);
+            }
+        } else if h & 0xF800 == 0xE000 {
+            next = (pc + 4).wrapping_add(sign_extend(h & 0x7FF, 11) << 1);
+        } else if h & 0xF500 == 0xB100 {
+            let zero = self.r[(h & 7) as usize] == 0;
+            if zero == (h & 0x800 == 0) {
+                next = pc + 4 + (((h >> 9) & 1) << 6 | ((h >> 3) & 0x1F) << 1);
+            }
+        } else if h & 0xF800 == 0xA000 {
+            self.r[((h >> 8) & 7) as usize] = ((pc + 4) & !3) + (h & 0xFF) * 4;
+        } else if h & 0xF800 == 0x4800 {
+            self.r[((h >> 8) & 7) as usize] = mem.read(((pc + 4) & !3) + (h & 0xFF) * 4, 4);
+        } else if h & 0xF800 == 0x2000 {
+            self.r[((h >> 8) & 7) as usize] = h & 0xFF;
+        } else if h & 0xFF07 == 0x4700 {
+            // bx / blx rm
+            let target = self.reg((h >> 3) & 0xF);
+            if h & 0x80 != 0 {
+                self.r[14] = next | 1;
+            }
+            next = self.bx(target);
+        } else if h != 0xBF00 {
+            panic!("unsupported instruction {h:#06x} at {pc:#x}");
+        }
+        self.pc = next;
+    }
+
+    fn step_thumb_wide(&mut self, mem: &Memory, insn: u32, next: u32) -> u32 {
+        let pc = self.pc;
+        let (first, second) = (insn >> 16, insn & 0xFFFF);
+        let bl_offset = || {
+            let s = (first >> 10) & 1;
+            let i1 = !((second >> 13) ^ s) & 1;
+            let i2 = !((second >> 11) ^ s) & 1;
+            let imm = s << 24 | i1 << 23 | i2 << 22 | (first & 0x3FF) << 12 | (second & 0x7FF) << 1;
+            sign_extend(imm, 25)
+        };
+        if first & 0xF800 == 0xF000 && second & 0x8000 != 0 {
+            match second & 0x5000 {
+                // b<cond>.w
+                0x0000 => {
+                    let cond = (first >> 6) & 0xF;
+                    assert!(cond < 0xE, "unsupported {insn:#010x} at {pc:#x}");
+                    if !self.holds(cond) {
+                        return next;
+                    }
+                    let imm = ((first >> 10) & 1) << 20
+                        | ((second >> 11) & 1) << 19
+                        | ((second >> 13) & 1) << 18
+                        | (first & 0x3F) << 12
+                        | (second & 0x7FF) << 1;
+                    return (pc + 4).wrapping_add(sign_extend(imm, 21));
+                }
+                0x1000 => return (pc + 4).wrapping_add(bl_offset()),
+                0x5000 => {
+                    self.r[14] = next | 1;
+                    return (pc + 4).wrapping_add(bl_offset());
+                }
+                _ => {
+                    self.r[14] = next | 1;
+                    self.thumb = false;
+                    return ((pc + 4) & !3).wrapping_add(bl_offset());
+                }
+            }
+        }
+        if first & 0xFB5F == 0xF20F && second & 0x8000 == 0 {
+            // adr.w
+            let imm = ((first >> 10) & 1) << 11 | ((second >> 12) & 7) << 8 | (second & 0xFF);
+            let base = (pc + 4) & !3;
+            self.r[((second >> 8) & 0xF) as usize] = if first & 0xA0 != 0 {
+                base.wrapping_sub(imm)
+            } else {
+                base.wrapping_add(imm)
+            };
+            return next;
+        }
+        if first & 0xFE00 == 0xF800 && first & 0x10 != 0 {
+            // ldr{b,h,sb,sh}.w rt, [rn, #imm12] / [pc, #±imm12]
+            let rn = first & 0xF;
+            let imm = second & 0xFFF;
+            let address = if rn == 15 {
+                let base = (pc + 4) & !3;
+                if first & 0x80 != 0 {
+                    base.wrapping_add(imm)
+                } else {
+                    base.wrapping_sub(imm)
+                }
+            } else {
+                assert!(first & 0x80 != 0, "unsupported {insn:#010x} at {pc:#x}");
+                self.r[rn as usize].wrapping_add(imm)
+            };
+            let size = 1 << ((first >> 5) & 3);
+            let mut value = mem.read(address, size);
+            if first & 0x100 != 0 {
+                value = sign_extend(value, size as u32 * 8);
+            }
+            let rt = second >> 12;
+            if rt == 15 {
+                return self.bx(value);
+            }
+            self.r[rt as usize] = value;
+            return next;
+        }
+        panic!("unsupported instruction {insn:#010x} at {pc:#x}");
+    }
+
+    /// Step while `pc` stays in `code`, at most `limit` instructions.
+    pub fn run(&mut self, mem: &Memory, code: Range<u32>, limit: usize) {
+        for _ in 0..limit {
+            if !code.contains(&self.pc) {
+                return;
+            }
+            self.step(mem);
+        }
+        panic!("no exit from {code:x?} after {limit} instructions");
+    }
+}
+
+// Run the original at `src` and the trampoline at `dst` from the same state, until each leaves its
+// code; `ip` is scratch for the relocator, and link registers differ after a relocated call.
+fn compare(mem: &Memory, src: Range<u32>, dst: Range<u32>, thumb: bool, start: &Cpu) {
+    let mut original = start.clone();
+    original.pc = src.start;
+    original.thumb = thumb;
+    let mut moved = original.clone();
+    moved.pc = dst.start;
+    original.run(mem, src.clone(), 64);
+    moved.run(mem, dst.clone(), 64);
+    for cpu in [&mut original, &mut moved] {
+        cpu.r[12] = 0;
+        if cpu.r[14] != start.r[14] {
+            cpu.r[14] = 0;
+        }
+    }
+    assert_eq!(original, moved, "{src:#x?} relocated to {dst:#x?}");
+}
+
+/// Assert the relocation of A32 `words` behaves like the original at every placement in `dsts`,
+/// followed by a jump back to the first instruction after them.
+pub(super) fn assert_equivalent_arm(words: &[u32], src: u32, dsts: &[u32], start: &Cpu) {
+    let code: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let resume = src + code.len() as u32;
+    for &dst in dsts.iter().filter(|&&d| d & 3 == 0) {
+        let mut tramp = super::relocate_arm(words, src, dst).expect("relocate");
+        tramp.extend([super::ARM_LDR_PC, resume]);
+        let tramp: Vec<u8> = tramp.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut mem = Memory::default();
+        mem.write(src, &code);
+        mem.write(dst, &tramp);
+        compare(
+            &mem,
+            src..resume,
+            dst..dst + tramp.len() as u32,
+            false,
+            start,
+        );
+    }
+}
+
+/// As `assert_equivalent_arm`, for Thumb `code`.
+pub(super) fn assert_equivalent_thumb(code: &[u8], src: u32, dsts: &[u32], start: &Cpu) {
+    let resume = src + code.len() as u32;
+    for &dst in dsts {
+        let mut tramp = super::relocate_thumb(code, src, dst).expect("relocate");
+        let back = dst + tramp.len() as u32;
+        tramp.extend(super::thumb_jump(back, resume | 1));
+        let mut mem = Memory::default();
+        mem.write(src, code);
+        mem.write(dst, &tramp);
+        compare(
+            &mem,
+            src..resume,
+            dst..dst + tramp.len() as u32,
+            true,
+            start,
+        );
+    }
+}
@@ -1,2 +1,10 @@
 #[cfg(any(target_arch = "aarch64", test))]
 pub(crate) mod aarch64;
+#[cfg(any(target_arch = "arm", test))]
+pub(crate) mod arm;
+#[cfg(any(target_arch = "riscv64", test))]
+pub(crate) mod riscv64;
+#[cfg(any(target_arch = "x86", test))]
+pub(crate) mod x86;
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod x86_64;
@@ -0,0 +1,625 @@
+#![allow(dead_code)]
+
+use crate::error::{Error, Result};
+
+#[cfg(test)]
+mod emu;
+
+const OP_AUIPC: u32 = 0x17;
+const OP_JAL: u32 = 0x6F;
+const OP_JALR: u32 = 0x67;
+const OP_BRANCH: u32 = 0x63;
+const OP_ADDI: u32 = 0x13;
+const OP_LD: u32 = (3 << 12) | 0x03;
+pub(crate) const OP_NOP: u32 = 0x13;
+pub(crate) const OP_C_NOP: u16 = 0x0001;
+/// Scratch register of expansions and patches: `t1`, which PLT stubs clobber as well.
+pub(crate) const T1: u32 = 6;
+
+fn sign_extend(value: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (value << shift) >> shift
+}
+
+fn rd(insn: u32) -> u32 {
+    (insn >> 7) & 0x1F
+}
+
+fn rs1(insn: u32) -> u32 {
+    (insn >> 15) & 0x1F
+}
+
+fn is_compressed(insn: u32) -> bool {
+    insn & 3 != 3
+}
+
+fn insn_size(insn: u32) -> usize {
+    if is_compressed(insn) { 2 } else { 4 }
+}
+
+fn u_imm(insn: u32) -> i64 {
+    (insn & 0xFFFF_F000) as i32 as i64
+}
+
+fn j_imm(insn: u32) -> i64 {
+    let imm = ((insn >> 31) & 1) << 20
+        | ((insn >> 21) & 0x3FF) << 1
+        | ((insn >> 20) & 1) << 11
+        | ((insn >> 12) & 0xFF) << 12;
+    sign_extend(imm as i64, 21)
+}
+
+fn b_imm(insn: u32) -> i64 {
+    let imm = ((insn >> 31) & 1) << 12
+        | ((insn >> 25) & 0x3F) << 5
+        | ((insn >> 8) & 0xF) << 1
+        | ((insn >> 7) & 1) << 11;
+    sign_extend(imm as i64, 13)
+}
+
+// `c.j`: offset[11|4|9:8|10|6|7|3:1|5] in bits 12..2.
+fn cj_imm(insn: u32) -> i64 {
+    let imm = ((insn >> 12) & 1) << 11
+        | ((insn >> 11) & 1) << 4
+        | ((insn >> 9) & 3) << 8
+        | ((insn >> 8) & 1) << 10
+        | ((insn >> 7) & 1) << 6
+        | ((insn >> 6) & 1) << 7
+        | ((insn >> 3) & 7) << 1
+        | ((insn >> 2) & 1) << 5;
+    sign_extend(imm as i64, 12)
+}
+
+// `c.beqz`/`c.bnez`: offset[8|4:3] in bits 12..10, offset[7:6|2:1|5] in bits 6..2.
+fn cb_imm(insn: u32) -> i64 {
+    let imm = ((insn >> 12) & 1) << 8
+        | ((insn >> 10) & 3) << 3
+        | ((insn >> 5) & 3) << 6
+        | ((insn >> 3) & 3) << 1
+        | ((insn >> 2) & 1) << 5;
+    sign_extend(imm as i64, 9)
+}
+
+fn encode_u(op: u32, rd: u32, imm20: i64) -> u32 {
+    ((imm20 as u32) << 12) | (rd << 7) | op
+}
+
+// `op` carries the opcode and funct3.
+fn encode_i(op: u32, rd: u32, rs1: u32, imm12: i64) -> u32 {
+    (((imm12 as u32) & 0xFFF) << 20) | (rs1 << 15) | (rd << 7) | op
+}
+
+fn encode_j(rd: u32, offset: i64) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 20) & 1) << 31
+        | ((imm >> 1) & 0x3FF) << 21
+        | ((imm >> 11) & 1) << 20
+        | ((imm >> 12) & 0xFF) << 12
+        | (rd << 7)
+        | OP_JAL
+}
+
+fn with_b_offset(insn: u32, offset: i64) -> u32 {
+    let imm = offset as u32;
+    (insn & 0x01FF_F07F)
+        | ((imm >> 12) & 1) << 31
+        | ((imm >> 5) & 0x3F) << 25
+        | ((imm >> 1) & 0xF) << 8
+        | ((imm >> 11) & 1) << 7
+}
+
+fn is_auipc(insn: u32) -> bool {
+    !is_compressed(insn) && insn & 0x7F == OP_AUIPC
+}
+fn is_jal(insn: u32) -> bool {
+    !is_compressed(insn) && insn & 0x7F == OP_JAL
+}
+fn is_jalr(insn: u32) -> bool {
+    !is_compressed(insn) && insn & 0x707F == OP_JALR
+}
+fn is_branch(insn: u32) -> bool {
+    // funct3 010 and 011 are unallocated.
+    !is_compressed(insn) && insn & 0x7F == OP_BRANCH && !matches!((insn >> 12) & 7, 2 | 3)
+}
+fn is_c_j(insn: u32) -> bool {
+    is_compressed(insn) && insn & 0xE003 == 0xA001
+}
+// `c.beqz` or `c.bnez`.
+fn is_c_branch(insn: u32) -> bool {
+    is_compressed(insn) && insn & 0xC003 == 0xC001
+}
+fn is_c_jr(insn: u32) -> bool {
+    is_compressed(insn) && insn & 0xF07F == 0x8002 && rd(insn) != 0
+}
+
+/// Whether `insn` writes `t1`. Compressed forms with a 3-bit register field only name `x8`..`x15`.
+fn writes_t1(insn: u32) -> bool {
+    if rd(insn) != T1 {
+        return false;
+    }
+    if is_compressed(insn) {
+        return match (insn & 3, (insn >> 13) & 7) {
+            // c.addi, c.addiw, c.li, c.lui; c.slli, c.lwsp, c.ldsp
+            (1, 0..=3) | (2, 0 | 2 | 3) => true,
+            // c.mv, c.add (`c.jr`/`c.jalr` have no rs2)
+            (2, 4) => (insn >> 2) & 0x1F != 0,
+            _ => false,
+        };
+    }
+    match insn & 0x7F {
+        // stores, branches, FP loads and fused multiply-adds
+        0x23 | 0x27 | 0x63 | 0x07 | 0x43 | 0x47 | 0x4B | 0x4F => false,
+        // FP ops: only compares, conversions to integer, `fmv.x`/`fclass`
+        0x53 => matches!(insn >> 27, 0x14 | 0x18 | 0x1C),
+        _ => true,
+    }
+}
+
+/// `jr t1` or `c.jr t1`.
+fn is_jr_t1(insn: u32) -> bool {
+    (is_jalr(insn) && rd(insn) == 0 && rs1(insn) == T1) || (is_c_jr(insn) && rd(insn) == T1)
+}
+
+/// Split a PC-relative offset into the `auipc` immediate and the low 12 bits of the `addi`/`jalr`
+/// after it; `None` beyond ±2 GiB.
+fn split_hi_lo(offset: i64) -> Option<(i64, i64)> {
+    let hi = (offset + 0x800) >> 12;
+    (-(1 << 19)..1 << 19)
+        .contains(&hi)
+        .then_some((hi, offset - (hi << 12)))
+}
+
+/// Size of the instruction starting with halfword `half`: 2 for RVC, 4 otherwise, `None` for
+/// 48-bit and longer encodings.
+pub(crate) fn insn_len(half: u16) -> Option<usize> {
+    match half & 0x1F {
+        h if h & 3 != 3 => Some(2),
+        0x1F => None,
+        _ => Some(4),
+    }
+}
+
+/// The instruction at the start of `code`, zero-extended if compressed.
+pub(crate) fn read_insn(code: &[u8]) -> Result<u32> {
+    let half = match code {
+        [a, b, ..] => u16::from_le_bytes([*a, *b]),
+        _ => return Err(Error::DecodeFailed),
+    };
+    match (insn_len(half), code) {
+        (Some(2), _) => Ok(half as u32),
+        (Some(4), [a, b, c, d, ..]) => Ok(u32::from_le_bytes([*a, *b, *c, *d])),
+        _ => Err(Error::DecodeFailed),
+    }
+}
+
+/// `j`, `jr` or `ret` (compressed or not): execution never falls through to the next instruction.
+pub(crate) fn ends_flow(insn: u32) -> bool {
+    ((is_jal(insn) || is_jalr(insn)) && rd(insn) == 0) || is_c_j(insn) || is_c_jr(insn)
+}
+
+/// Target of a `jal`, conditional branch, `c.j` or `c.beqz`/`c.bnez` placed at `pc`.
+pub(crate) fn branch_target(insn: u32, pc: u64) -> Option<u64> {
+    let offset = if is_jal(insn) {
+        j_imm(insn)
+    } else if is_branch(insn) {
+        b_imm(insn)
+    } else if is_c_j(insn) {
+        cj_imm(insn)
+    } else if is_c_branch(insn) {
+        cb_imm(insn)
+    } else {
+        return None;
+    };
+    Some(pc.wrapping_add(offset as u64))
+}
+
+/// `auipc x<reg>, hi; jalr x0, lo(x<reg>)` placed at `from`, if `to` is within ±2 GiB.
+pub(crate) fn encode_auipc_jalr(from: u64, to: u64, reg: u32) -> Option<[u32; 2]> {
+    let (hi, lo) = split_hi_lo(to.wrapping_sub(from) as i64)?;
+    Some([encode_u(OP_AUIPC, reg, hi), encode_i(OP_JALR, 0, reg, lo)])
+}
+
+/// `auipc x<reg>, 0; ld x<reg>, lit(x<reg>); jr x<reg>` placed at `from`, followed by `to` in an
+/// aligned literal: 20 to 26 bytes depending on the alignment of `from`.
+pub(crate) fn encode_abs_jump(from: u64, to: u64, reg: u32) -> Vec<u8> {
+    let mut e = Emitter::new(from);
+    e.literal(reg, to, Some(encode_i(OP_JALR, 0, reg, 0)), false);
+    e.out
+}
+
+/// Jump from `from` to `to` through `reg`: `auipc`+`jalr` within ±2 GiB, else absolute.
+pub(crate) fn encode_jump(from: u64, to: u64, reg: u32) -> Vec<u8> {
+    let mut e = Emitter::new(from);
+    e.jump(to, 0, reg);
+    e.out
+}
+
+/// Relocated code being written at `base`.
+struct Emitter {
+    base: u64,
+    out: Vec<u8>,
+    /// A far expansion went through `t1`.
+    clobbered_t1: bool,
+}
+
+impl Emitter {
+    fn new(base: u64) -> Self {
+        Self {
+            base,
+            out: Vec::new(),
+            clobbered_t1: false,
+        }
+    }
+
+    fn pc(&self) -> u64 {
+        self.base + self.out.len() as u64
+    }
+
+    fn word(&mut self, insn: u32) {
+        self.out.extend(insn.to_le_bytes());
+    }
+
+    /// `auipc rd, 0; ld rd, lit(rd)`, then `then`, and a `j` past the literal unless execution
+    /// can't reach it; the literal is 8-byte aligned, padded with zeros.
+    fn literal(&mut self, rd: u32, value: u64, then: Option<u32>, falls_through: bool) {
+        let pc = self.pc();
+        let insns = 2 + then.is_some() as u64 + falls_through as u64;
+        let lit = (pc + insns * 4 + 7) & !7;
+        self.word(encode_u(OP_AUIPC, rd, 0));
+        self.word(encode_i(OP_LD, rd, rd, (lit - pc) as i64));
+        if let Some(insn) = then {
+            self.word(insn);
+        }
+        if falls_through {
+            let j = self.pc();
+            self.word(encode_j(0, (lit + 8 - j) as i64));
+        }
+        self.out.resize((lit - self.base) as usize, 0);
+        self.out.extend(value.to_le_bytes());
+    }
+
+    /// `rd = value`, as `auipc` (+ `addi`) within ±2 GiB, else from a literal.
+    fn address(&mut self, rd: u32, value: u64) {
+        match split_hi_lo(value.wrapping_sub(self.pc()) as i64) {
+            Some((hi, lo)) => {
+                self.word(encode_u(OP_AUIPC, rd, hi));
+                if lo != 0 {
+                    self.word(encode_i(OP_ADDI, rd, rd, lo));
+                }
+            }
+            None => self.literal(rd, value, None, true),
+        }
+    }
+
+    /// Jump to `target`, linking `link` (`x0` for none), through `scratch`.
+    fn jump(&mut self, target: u64, link: u32, scratch: u32) {
+        self.clobbered_t1 |= scratch == T1;
+        match split_hi_lo(target.wrapping_sub(self.pc()) as i64) {
+            Some((hi, lo)) => {
+                self.word(encode_u(OP_AUIPC, scratch, hi));
+                self.word(encode_i(OP_JALR, link, scratch, lo));
+            }
+            None => self.literal(
+                scratch,
+                target,
+                Some(encode_i(OP_JALR, link, scratch, 0)),
+                link != 0,
+            ),
+        }
+    }
+
+    /// `jal` to `target`, or an `auipc`/literal jump through the link register itself (`t1` for
+    /// a plain `j`) beyond ±1 MiB.
+    fn jal(&mut self, link: u32, target: u64) {
+        let offset = target.wrapping_sub(self.pc()) as i64;
+        if (-0x10_0000..0x10_0000).contains(&offset) {
+            self.word(encode_j(link, offset));
+        } else {
+            self.jump(target, link, if link != 0 { link } else { T1 });
+        }
+    }
+
+    /// Conditional branch `insn` (32-bit form) to `target`, or the inverted branch around a jump
+    /// beyond ±4 KiB.
+    fn branch(&mut self, insn: u32, target: u64) {
+        let offset = target.wrapping_sub(self.pc()) as i64;
+        if (-0x1000..0x1000).contains(&offset) {
+            self.word(with_b_offset(insn, offset));
+            return;
+        }
+        let at = self.out.len();
+        self.word(0);
+        self.jump(target, 0, T1);
+        // beq/bne, blt/bge and bltu/bgeu differ in the low bit of funct3.
+        let skip = with_b_offset(insn ^ (1 << 12), (self.out.len() - at) as i64);
+        self.out[at..at + 4].copy_from_slice(&skip.to_le_bytes());
+    }
+}
+
+/// Relocate the whole instructions in `code` from `src_pc` to `dst_pc`. `auipc` is replaced by the
+/// exact value it computed, so the `addi`/load/`jalr` completing the pair needs no change, wherever
+/// it is. Compressed jumps and branches become their 32-bit forms; `t1` is clobbered by far
+/// expansions and by the jump back from a trampoline, so code that sets `t1` is refused, except
+/// for the `auipc t1; jr t1` of a tail call.
+pub(crate) fn relocate(code: &[u8], src_pc: u64, dst_pc: u64) -> Result<Vec<u8>> {
+    relocate_tracked(code, src_pc, dst_pc).map(|(out, _)| out)
+}
+
+/// [`relocate`], also reporting whether a far expansion clobbered `t1`.
+fn relocate_tracked(code: &[u8], src_pc: u64, dst_pc: u64) -> Result<(Vec<u8>, bool)> {
+    let mut e = Emitter::new(dst_pc);
+    let mut offset = 0;
+    while offset < code.len() {
+        let insn = read_insn(&code[offset..]).map_err(|_| Error::RelocationFailed)?;
+        let pc = src_pc + offset as u64;
+        let size = insn_size(insn);
+        offset += size;
+        if writes_t1(insn) {
+            // The value is rebuilt exactly and consumed by the final jump.
+            let rest = &code[offset..];
+            let tail = is_auipc(insn)
+                && read_insn(rest).is_ok_and(|j| is_jr_t1(j) && insn_size(j) == rest.len());
+            if !tail {
+                return Err(Error::RelocationFailed);
+            }
+        }
+
+        if is_auipc(insn) && rd(insn) != 0 {
+            e.address(rd(insn), pc.wrapping_add(u_imm(insn) as u64));
+        } else if is_jal(insn) {
+            e.jal(rd(insn), pc.wrapping_add(j_imm(insn) as u64));
+        } else if is_branch(insn) {
+            e.branch(insn, pc.wrapping_add(b_imm(insn) as u64));
+        } else if is_c_j(insn) {
+            e.jal(0, pc.wrapping_add(cj_imm(insn) as u64));
+        } else if is_c_branch(insn) {
+            // beq/bne rs1', x0
+            let rs1 = 8 + ((insn >> 7) & 7);
+            let funct3 = (insn >> 13) & 1;
+            let branch = (rs1 << 15) | (funct3 << 12) | OP_BRANCH;
+            e.branch(branch, pc.wrapping_add(cb_imm(insn) as u64));
+        } else {
+            e.out.extend(&insn.to_le_bytes()[..size]);
+        }
+    }
+    if e.out.is_empty() {
+        return Err(Error::RelocationFailed);
+    }
+    Ok((e.out, e.clobbered_t1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_c_j(offset: i64) -> u32 {
+        let imm = offset as u32;
+        0xA001
+            | ((imm >> 11) & 1) << 12
+            | ((imm >> 4) & 1) << 11
+            | ((imm >> 8) & 3) << 9
+            | ((imm >> 10) & 1) << 8
+            | ((imm >> 6) & 1) << 7
+            | ((imm >> 7) & 1) << 6
+            | ((imm >> 1) & 7) << 3
+            | ((imm >> 5) & 1) << 2
+    }
+
+    // `c.beqz`/`c.bnez` on x8..x15.
+    fn encode_c_branch(bnez: bool, rs1: u32, offset: i64) -> u32 {
+        let imm = offset as u32;
+        0xC001
+            | (bnez as u32) << 13
+            | ((imm >> 8) & 1) << 12
+            | ((imm >> 3) & 3) << 10
+            | (rs1 - 8) << 7
+            | ((imm >> 6) & 3) << 5
+            | ((imm >> 1) & 3) << 3
+            | ((imm >> 5) & 1) << 2
+    }
+
+    fn encode_branch(funct3: u32, rs1: u32, rs2: u32, offset: i64) -> u32 {
+        with_b_offset(
+            (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | OP_BRANCH,
+            offset,
+        )
+    }
+
+    fn bytes(insns: &[u32]) -> Vec<u8> {
+        insns
+            .iter()
+            .flat_map(|&i| i.to_le_bytes()[..insn_size(i)].to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn branch_targets() {
+        const PC: u64 = 0x4000_0000;
+        assert_eq!(branch_target(encode_j(1, -8), PC), Some(PC - 8));
+        assert_eq!(
+            branch_target(encode_branch(0, 10, 11, 12), PC),
+            Some(PC + 12)
+        );
+        assert_eq!(branch_target(encode_c_j(6), PC), Some(PC + 6));
+        assert_eq!(
+            branch_target(encode_c_branch(true, 8, -4), PC),
+            Some(PC - 4)
+        );
+        assert_eq!(branch_target(encode_i(OP_JALR, 0, 1, 0), PC), None);
+        assert_eq!(branch_target(OP_NOP, PC), None);
+    }
+
+    #[test]
+    fn immediates_round_trip() {
+        for offset in [-0x10_0000, -2, 2, 0x7FE, 0x800, 0xF_FFFE] {
+            assert_eq!(j_imm(encode_j(1, offset)), offset);
+        }
+        for offset in [-0x1000, -2, 2, 0x7FE, 0x800, 0xFFE] {
+            assert_eq!(b_imm(encode_branch(0, 10, 11, offset)), offset);
+        }
+        for offset in [-0x800, -2, 2, 0x7FE] {
+            assert_eq!(cj_imm(encode_c_j(offset)), offset);
+        }
+        for offset in [-0x100, -2, 2, 0xFE] {
+            assert_eq!(cb_imm(encode_c_branch(true, 9, offset)), offset);
+        }
+        for offset in [0, 0x7FF, 0x800, -0x8000_0000, 0x7FFF_F7FF] {
+            let (hi, lo) = split_hi_lo(offset).expect("in range");
+            assert_eq!((hi << 12) + lo, offset);
+        }
+        assert_eq!(split_hi_lo(0x7FFF_F800), None);
+    }
+
+    #[test]
+    fn mixed_lengths_copy_unchanged() {
+        // c.addi a0, 1; addi a1, a1, 2; c.mv a2, a0; c.jr ra
+        let code = bytes(&[0x0505, encode_i(OP_ADDI, 11, 11, 2), 0x862A, 0x8082]);
+        assert_eq!(code.len(), 10);
+        assert_eq!(relocate(&code, 0x1000, 0x9000_0000_0000).expect("ok"), code);
+        assert!(ends_flow(0x8082) && !ends_flow(0x862A));
+        assert!(ends_flow(encode_j(0, 8)) && !ends_flow(encode_j(1, 8)));
+        assert!(ends_flow(encode_c_j(8)));
+        assert_eq!(insn_len(0x001F), None);
+        assert!(read_insn(&[0x13, 0x00]).is_err());
+    }
+
+    #[test]
+    fn code_setting_t1_is_refused() {
+        let refused: [&[u32]; 5] = [
+            // li t1, -4096; add sp, sp, t1 (a large-frame prologue)
+            &[encode_u(0x37, T1, -1), 0x0061_0133],
+            // addi t1, sp, 16
+            &[encode_i(OP_ADDI, T1, 2, 16)],
+            // c.li t1, 3; c.mv t1, a0
+            &[0x430D],
+            &[0x832A],
+            // auipc t1, 0x10; jr t1; nop: the jump isn't the last stolen instruction
+            &[
+                encode_u(OP_AUIPC, T1, 0x10),
+                encode_i(OP_JALR, 0, T1, 0),
+                OP_NOP,
+            ],
+        ];
+        for insns in refused {
+            assert!(
+                matches!(
+                    relocate(&bytes(insns), SRC, SRC + 0x800),
+                    Err(Error::RelocationFailed)
+                ),
+                "{insns:08x?}"
+            );
+        }
+        let allowed: [&[u32]; 4] = [
+            // add sp, sp, t1; sd t1, 0(sp); beq t1, a0: reading it is fine
+            &[0x0061_0133],
+            &[0x0061_3023],
+            &[encode_branch(0, T1, 10, 8)],
+            // auipc t1, 0x10; jr 0x20(t1): a tail call
+            &[encode_u(OP_AUIPC, T1, 0x10), encode_i(OP_JALR, 0, T1, 0x20)],
+        ];
+        for insns in allowed {
+            assert!(
+                relocate(&bytes(insns), SRC, SRC + 0x800).is_ok(),
+                "{insns:08x?}"
+            );
+        }
+        // auipc t1; c.jr t1
+        let tail = bytes(&[encode_u(OP_AUIPC, T1, 0x10), 0x8302]);
+        assert!(relocate(&tail, SRC, 0x20_0000_0000).is_ok());
+        emu::assert_equivalent(&tail, SRC, &DSTS, &start());
+    }
+
+    #[test]
+    fn compact_jumps_reach_their_targets() {
+        let from = 0x3F_0000_1002;
+        for to in [from + 0x7FFF_F7FF, from - 0x8000_0000, from + 8] {
+            let [auipc, jalr] = encode_auipc_jalr(from, to, T1).expect("in range");
+            assert_eq!(
+                from.wrapping_add(u_imm(auipc) as u64)
+                    .wrapping_add((jalr as i32 >> 20) as u64),
+                to
+            );
+        }
+        assert_eq!(encode_auipc_jalr(from, from + 0x8000_0000, T1), None);
+        // The literal is aligned whatever the placement.
+        for (from, len) in [(0x1000, 24), (0x1002, 22), (0x1004, 20), (0x1006, 26)] {
+            let jump = encode_abs_jump(from, 0x1122_3344_5566_7788, T1);
+            assert_eq!(jump.len(), len);
+            assert_eq!((from + len as u64 - 8) % 8, 0);
+        }
+    }
+
+    const SRC: u64 = 0x0000_3f12_3456_7000;
+    // Near (re-encoded in place), out of `jal`/branch range but within `auipc` reach, out of every
+    // range, and 2-byte aligned.
+    const DSTS: [u64; 4] = [
+        SRC + 0x800,
+        SRC + 0x4000_0000,
+        0x0000_0020_0000_0000,
+        0x0000_0020_0000_0002,
+    ];
+
+    fn start() -> emu::Cpu {
+        let mut cpu = emu::Cpu::default();
+        for (i, x) in cpu.x.iter_mut().enumerate().skip(1) {
+            *x = 0x1111_0000_0000_0000 * (i as u64 % 7) + i as u64;
+        }
+        cpu
+    }
+
+    #[test]
+    fn emulated_auipc_pairs() {
+        let sequences: [&[u32]; 5] = [
+            // auipc a0, 0x12345
+            &[encode_u(OP_AUIPC, 10, 0x12345)],
+            // auipc a0, -1; addi a0, a0, 0x7f0
+            &[encode_u(OP_AUIPC, 10, -1), encode_i(OP_ADDI, 10, 10, 0x7F0)],
+            // auipc t0, 3; ld a1, -8(t0)
+            &[encode_u(OP_AUIPC, 5, 3), encode_i(OP_LD, 11, 5, -8)],
+            // c.nop; auipc a2, 0x10 (its pair would be after the stolen range)
+            &[OP_C_NOP as u32, encode_u(OP_AUIPC, 12, 0x10)],
+            // auipc ra, 0x100; jalr ra, 0x10(ra)
+            &[encode_u(OP_AUIPC, 1, 0x100), encode_i(OP_JALR, 1, 1, 0x10)],
+        ];
+        for insns in sequences {
+            emu::assert_equivalent(&bytes(insns), SRC, &DSTS, &start());
+        }
+    }
+
+    #[test]
+    fn emulated_jumps() {
+        let sequences: [&[u32]; 4] = [
+            &[encode_j(0, -0x800)],
+            &[encode_j(1, 0xF_FFFE)],
+            &[OP_C_NOP as u32, encode_c_j(0x40)],
+            // c.li a5, 3; jal t0, -4
+            &[0x478D, encode_j(5, -4)],
+        ];
+        for insns in sequences {
+            emu::assert_equivalent(&bytes(insns), SRC, &DSTS, &start());
+        }
+    }
+
+    #[test]
+    fn emulated_conditional_branches() {
+        let mut sequences = Vec::new();
+        for funct3 in [0, 1, 4, 5, 6, 7] {
+            // b<cond> a0, a1 / a0, a0
+            sequences.push(vec![encode_branch(funct3, 10, 11, -0x20)]);
+            sequences.push(vec![encode_branch(funct3, 10, 10, 0xFFE)]);
+        }
+        for bnez in [false, true] {
+            sequences.push(vec![encode_c_branch(bnez, 10, 0x80)]);
+            sequences.push(vec![OP_C_NOP as u32, encode_c_branch(bnez, 8, -0x100)]);
+        }
+        for insns in sequences {
+            for (a0, a1) in [(1u64, 2u64), (0, 0), (u64::MAX, 1)] {
+                let mut cpu = start();
+                cpu.x[10] = a0;
+                cpu.x[11] = a1;
+                cpu.x[8] = a0;
+                emu::assert_equivalent(&bytes(&insns), SRC, &DSTS, &cpu);
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Test-only interpreter for the RV64GC subset the relocator reads or writes: `lui`/`auipc`,
+//! `jal`/`jalr`, conditional branches, loads, `addi`, and the compressed jumps, branches, `c.li`,
+//! `c.addi`, `c.mv` and `c.jr`.
+//!
+//! Memory is a synthetic image: every byte that wasn't written reads as a function of its address,
+//! so loads see the same data wherever the code runs from.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct Cpu {
+    pub x: [u64; 32],
+    pub pc: u64,
+}
+
+#[derive(Default)]
+pub(super) struct Memory {
+    written: HashMap<u64, u8>,
+}
+
+impl Memory {
+    pub fn write(&mut self, address: u64, bytes: &[u8]) {
+        for (i, b) in bytes.iter().enumerate() {
+            self.written.insert(address + i as u64, *b);
+        }
+    }
+
+    fn byte(&self, address: u64) -> u8 {
+        self.written.get(&address).copied().unwrap_or_else(|| {
+            let h = address.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            (h >> 56) as u8
+        })
+    }
+
+    fn read(&self, address: u64, len: usize) -> u64 {
+        (0..len).fold(0, |acc, i| {
+            acc | (self.byte(address.wrapping_add(i as u64)) as u64) << (8 * i)
+        })
+    }
+}
+
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+impl Cpu {
+    fn set(&mut self, r: u32, value: u64) {
+        if r != 0 {
+            self.x[r as usize] = value;
+        }
+    }
+
+    fn reg(&self, r: u32) -> u64 {
+        self.x[r as usize]
+    }
+
+    fn compare(&self, funct3: u32, a: u64, b: u64) -> bool {
+        match funct3 {
+            0 => a == b,
+            1 => a != b,
+            4 => (a as i64) < b as i64,
+            5 => a as i64 >= b as i64,
+            6 => a < b,
+            7 => a >= b,
+            _ => panic!("unallocated branch funct3 {funct3}"),
+        }
+    }
+
+    /// Execute one instruction. Returns `false` on `ebreak`.
+    pub fn step(&mut self, mem: &Memory) -> bool {
+        let pc = self.pc;
+        let half = mem.read(pc, 2) as u32;
+        if half & 3 != 3 {
+            return self.step_compressed(half);
+        }
+        let w = mem.read(pc, 4) as u32;
+        let rd = (w >> 7) & 0x1F;
+        let rs1 = (w >> 15) & 0x1F;
+        let rs2 = (w >> 20) & 0x1F;
+        let funct3 = (w >> 12) & 7;
+        let i_imm = sign_extend((w >> 20) as u64, 12);
+        let mut next = pc.wrapping_add(4);
+        match w & 0x7F {
+            0x37 => self.set(rd, sign_extend((w & 0xFFFF_F000) as u64, 32)),
+            0x17 => self.set(
+                rd,
+                pc.wrapping_add(sign_extend((w & 0xFFFF_F000) as u64, 32)),
+            ),
+            0x6F => {
+                let imm = ((w >> 31) & 1) << 20
+                    | ((w >> 21) & 0x3FF) << 1
+                    | ((w >> 20) & 1) << 11
+                    | ((w >> 12) & 0xFF) << 12;
+                self.set(rd, next);
+                next = pc.wrapping_add(sign_extend(imm as u64, 21));
+            }
+            0x67 if funct3 == 0 => {
+                let target = self.reg(rs1).wrapping_add(i_imm) & !1;
+                self.set(rd, next);
+                next = target;
+            }
+            0x63 => {
+                if self.compare(funct3, self.reg(rs1), self.reg(rs2)) {
+                    let imm = ((w >> 31) & 1) << 12
+                        | ((w >> 25) & 0x3F) << 5
+                        | ((w >> 8) & 0xF) << 1
+                        | ((w >> 7) & 1) << 11;
+                    next = pc.wrapping_add(sign_extend(imm as u64, 13));
+                }
+            }
+            0x03 => {
+                let address = self.reg(rs1).wrapping_add(i_imm);
+                let size = 1 << (funct3 & 3);
+                let value = mem.read(address, size);
+                let value = if funct3 & 4 != 0 || size == 8 {
+                    value
+                } else {
+                    sign_extend(value, size as u32 * 8)
+                };
+                self.set(rd, value);
+            }
+            0x13 if funct3 == 0 => self.set(rd, self.reg(rs1).wrapping_add(i_imm)),
+            0x73 if w == 0x0010_0073 => return false,
+            _ => panic!("unsupported instruction {w:#010x} at {pc:#x}"),
+        }
+        self.pc = next;
+        true
+    }
+
+    fn step_compressed(&mut self, h: u32) -> bool {
+        let pc = self.pc;
+        let mut next = pc.wrapping_add(2);
+        let rd = (h >> 7) & 0x1F;
+        let rs2 = (h >> 2) & 0x1F;
+        let imm6 = sign_extend(((h >> 7) & 0x20 | (h >> 2) & 0x1F) as u64, 6);
+        match (h & 3, h >> 13) {
+            // c.nop / c.addi
+            (1, 0) => self.set(rd, self.reg(rd).wrapping_add(imm6)),
+            (1, 2) => self.set(rd, imm6),
+            (1, 5) => {
+                let imm = ((h >> 12) & 1) << 11
+                    | ((h >> 11) & 1) << 4
+                    | ((h >> 9) & 3) << 8
+                    | ((h >> 8) & 1) << 10
+                    | ((h >> 7) & 1) << 6
+                    | ((h >> 6) & 1) << 7
+                    | ((h >> 3) & 7) << 1
+                    | ((h >> 2) & 1) << 5;
+                next = pc.wrapping_add(sign_extend(imm as u64, 12));
+            }
+            (1, 6 | 7) => {
+                let value = self.reg(8 + ((h >> 7) & 7));
+                if (value == 0) == (h >> 13 == 6) {
+                    let imm = ((h >> 12) & 1) << 8
+                        | ((h >> 10) & 3) << 3
+                        | ((h >> 5) & 3) << 6
+                        | ((h >> 3) & 3) << 1
+                        | ((h >> 2) & 1) << 5;
+                    next = pc.wrapping_add(sign_extend(imm as u64, 9));
+                }
+            }
+            (2, 4) if h == 0x9002 => return false,
+            (2, 4) if rs2 != 0 => {
+                let base = if h >> 12 & 1 != 0 { self.reg(rd) } else { 0 };
+                self.set(rd, base.wrapping_add(self.reg(rs2)));
+            }
+            (2, 4) => {
+                // c.jr / c.jalr
+                let target = self.reg(rd);
+                if h >> 12 & 1 != 0 {
+                    self.set(1, next);
+                }
+                next = target;
+            }
+            _ => panic!("unsupported compressed instruction {h:#06x} at {pc:#x}"),
+        }
+        self.pc = next;
+        true
+    }
+
+    /// Step while `pc` stays in `code`, at most `limit` instructions.
+    pub fn run(&mut self, mem: &Memory, code: Range<u64>, limit: usize) {
+        for _ in 0..limit {
+            if !code.contains(&self.pc) || !self.step(mem) {
+                return;
+            }
+        }
+        panic!("no exit from {code:x?} after {limit} instructions");
+    }
+}
+
+/// Run `code` placed at `src`, and its relocation at `dst`, from the same starting state. Returns
+/// both final states, taken when execution leaves the respective code (falling off the end of the
+/// relocation counts as reaching the first instruction after `code`, as through a trampoline's
+/// jump back), and whether a far expansion clobbered `t1`.
+pub(super) fn run_both(code: &[u8], src: u64, dst: u64, start: &Cpu) -> (Cpu, Cpu, bool) {
+    let (relocated, clobbered_t1) = super::relocate_tracked(code, src, dst).expect("relocate");
+    let resume = src + code.len() as u64;
+    let back = dst + relocated.len() as u64;
+
+    let mut mem = Memory::default();
+    mem.write(src, code);
+    mem.write(dst, &relocated);
+
+    let mut original = start.clone();
+    original.pc = src;
+    original.run(&mem, src..resume, 64);
+    let mut moved = start.clone();
+    moved.pc = dst;
+    moved.run(&mem, dst..back, 64);
+    if moved.pc == back {
+        moved.pc = resume;
+    }
+    (original, moved, clobbered_t1)
+}
+
+/// Assert the relocation of `code` behaves like the original at every placement in `dsts`. `t1` is
+/// only compared where no far expansion used it, and link registers differ after a relocated call.
+pub(super) fn assert_equivalent(code: &[u8], src: u64, dsts: &[u64], start: &Cpu) {
+    let mut links = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let insn = super::read_insn(&code[offset..]).expect("decode");
+        if super::is_jal(insn) || super::is_jalr(insn) {
+            links.push(super::rd(insn));
+        }
+        offset += super::insn_size(insn);
+    }
+    for &dst in dsts {
+        let (mut original, mut moved, clobbered_t1) = run_both(code, src, dst, start);
+        for cpu in [&mut original, &mut moved] {
+            for &r in links.iter().chain(clobbered_t1.then_some(&super::T1)) {
+                cpu.x[r as usize] = 0;
+            }
+        }
+        assert_eq!(
+            original, moved,
+            "{code:02x?} from {src:#x} relocated to {dst:#x}"
+        );
+    }
+}
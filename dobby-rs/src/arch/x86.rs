@@ -0,0 +1,202 @@
+#![allow(dead_code)]
+
+use crate::error::{Error, Result};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, Decoder, DecoderOptions, FlowControl, Instruction,
+    InstructionBlock, Register,
+};
+
+// `jmp rel32` wraps around the 32-bit address space, so it reaches the detour from anywhere and
+// no relay or near allocation is needed.
+pub(crate) const JMP_LEN: usize = 5;
+pub(crate) const TRAMP_SIZE: usize = 128;
+
+pub(crate) fn rel_jmp(from: u32, dest: u32) -> [u8; JMP_LEN] {
+    let disp = dest.wrapping_sub(from.wrapping_add(JMP_LEN as u32));
+    let mut b = [0u8; JMP_LEN];
+    b[0] = 0xE9;
+    b[1..5].copy_from_slice(&disp.to_le_bytes());
+    b
+}
+
+/// Decode whole instructions covering the 5-byte patch at `ip`.
+pub(crate) fn steal(bytes: &[u8], ip: u32) -> Result<(Vec<Instruction>, usize)> {
+    let mut decoder = Decoder::with_ip(32, bytes, ip as u64, DecoderOptions::NONE);
+    let mut insns = Vec::new();
+    let mut len = 0usize;
+    while len < JMP_LEN {
+        let i = decoder.decode();
+        if i.is_invalid() {
+            return Err(Error::DecodeFailed);
+        }
+        len += i.len();
+        insns.push(i);
+    }
+    if !fits_inline(&insns, ip, len) {
+        return Err(Error::PatchTooSmall);
+    }
+    Ok((insns, len))
+}
+
+/// There is no EIP-relative addressing on i686; PIC code reads its own address with a `call`
+/// instead. Relocated as-is, that `call` would push a trampoline address, so replace
+/// `call $+5` (followed by `pop reg`) with a push of the original return address, and a call to a
+/// `mov reg, [esp]; ret` thunk (`__x86.get_pc_thunk.*`) with `mov reg, <return address>`.
+///
+/// `read` returns the first four bytes at a call target.
+pub(crate) fn rewrite_pc_thunks(
+    insns: &mut [Instruction],
+    read: impl Fn(u32) -> [u8; 4],
+) -> Result<()> {
+    for i in insns.iter_mut() {
+        if i.code() != Code::Call_rel32_32 {
+            continue;
+        }
+        let ret = i.next_ip32();
+        let target = i.near_branch32();
+        let mut new = if target == ret {
+            Instruction::with1(Code::Pushd_imm32, ret)
+        } else if let Some(reg) = pc_thunk_register(read(target)) {
+            Instruction::with2(Code::Mov_r32_imm32, reg, ret)
+        } else {
+            continue;
+        }
+        .map_err(|_| Error::EncodeFailed)?;
+        new.set_ip32(i.ip32());
+        *i = new;
+    }
+    Ok(())
+}
+
+// `mov r32, [esp]` (8B /r with mod=00, rm=100, SIB 0x24) followed by `ret`.
+fn pc_thunk_register(bytes: [u8; 4]) -> Option<Register> {
+    const REGS: [Register; 8] = [
+        Register::EAX,
+        Register::ECX,
+        Register::EDX,
+        Register::EBX,
+        Register::ESP,
+        Register::EBP,
+        Register::ESI,
+        Register::EDI,
+    ];
+    match bytes {
+        [0x8B, modrm, 0x24, 0xC3] if modrm & 0xC7 == 0x04 && modrm != 0x24 => {
+            Some(REGS[(modrm >> 3) as usize])
+        }
+        _ => None,
+    }
+}
+
+/// Encode `insns` at `tramp`, followed by a jump back to `resume`.
+pub(crate) fn relocate(insns: &[Instruction], resume: u32, tramp: u32) -> Result<Vec<u8>> {
+    let mut block = insns.to_vec();
+    block.push(
+        Instruction::with_branch(Code::Jmp_rel32_32, resume as u64)
+            .map_err(|_| Error::EncodeFailed)?,
+    );
+    let code = BlockEncoder::encode(
+        32,
+        InstructionBlock::new(&block, tramp as u64),
+        BlockEncoderOptions::NONE,
+    )
+    .map_err(|_| Error::EncodeFailed)?
+    .code_buffer;
+    if code.len() > TRAMP_SIZE {
+        return Err(Error::EncodeFailed);
+    }
+    Ok(code)
+}
+
+// Same rule as on x86_64: no stolen instruction but the last may end control flow, and no stolen
+// branch may land inside the patched range.
+fn fits_inline(insns: &[Instruction], start: u32, len: usize) -> bool {
+    let (start, end) = (start as u64, start as u64 + len as u64);
+    let terminal = insns[..insns.len() - 1].iter().any(|i| {
+        matches!(
+            i.flow_control(),
+            FlowControl::Return
+                | FlowControl::UnconditionalBranch
+                | FlowControl::IndirectBranch
+                | FlowControl::Interrupt
+                | FlowControl::Exception
+        )
+    });
+    let internal = insns.iter().any(|i| {
+        matches!(
+            i.flow_control(),
+            FlowControl::UnconditionalBranch | FlowControl::ConditionalBranch
+        ) && (start + 1..end).contains(&i.near_branch_target())
+    });
+    !terminal && !internal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(from: u32, to: u32) -> [u8; 5] {
+        let mut b = rel_jmp(from, to);
+        b[0] = 0xE8;
+        b
+    }
+
+    fn decode(code: &[u8], ip: u32) -> Vec<Instruction> {
+        Decoder::with_ip(32, code, ip as u64, DecoderOptions::NONE)
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn call_pop_pushes_original_address() {
+        let ip = 0x0804_8000;
+        // push ebp; call $+5; pop ebx
+        let mut code = vec![0x55];
+        code.extend(call(ip + 1, ip + 6));
+        code.push(0x5B);
+        let (mut insns, len) = steal(&code, ip).unwrap();
+        assert_eq!(len, 6);
+        rewrite_pc_thunks(&mut insns, |_| unreachable!()).unwrap();
+        let out = decode(&relocate(&insns, ip + 6, 0x4000_0000).unwrap(), 0x4000_0000);
+        assert_eq!(out[0].code(), Code::Push_r32);
+        assert_eq!(out[1].code(), Code::Pushd_imm32);
+        assert_eq!(out[1].immediate32(), ip + 6);
+        // The `pop` runs in the original code and sees the original address.
+        assert_eq!(out[2].near_branch32(), ip + 6);
+    }
+
+    #[test]
+    fn get_pc_thunk_becomes_mov() {
+        let ip = 0x0804_8000;
+        let thunk = 0x0804_9000;
+        let other = 0x0804_a000;
+        // call __x86.get_pc_thunk.si; call other
+        let mut code = call(ip, thunk).to_vec();
+        code.extend(call(ip + 5, other));
+        let (mut insns, len) = steal(&code, ip).unwrap();
+        assert_eq!(len, 5);
+        insns.extend(decode(&code[5..], ip + 5));
+        rewrite_pc_thunks(&mut insns, |a| match a {
+            0x0804_9000 => [0x8B, 0x34, 0x24, 0xC3], // mov esi, [esp]; ret
+            _ => [0x55, 0x89, 0xE5, 0x90],           // push ebp; mov ebp, esp
+        })
+        .unwrap();
+        let tramp = 0x7000_0000;
+        let out = decode(&relocate(&insns, ip + 10, tramp).unwrap(), tramp);
+        assert_eq!(out[0].code(), Code::Mov_r32_imm32);
+        assert_eq!(out[0].op0_register(), Register::ESI);
+        assert_eq!(out[0].immediate32(), ip + 5);
+        // An ordinary call stays a call, re-targeted for the trampoline.
+        assert_eq!(out[1].code(), Code::Call_rel32_32);
+        assert_eq!(out[1].near_branch32(), other);
+        assert_eq!(out[2].near_branch32(), ip + 10);
+    }
+
+    #[test]
+    fn patch_jumps_across_the_address_space() {
+        let from = 0xF000_0000;
+        let to = 0x1000_0000;
+        let b = rel_jmp(from, to);
+        assert_eq!(decode(&b, from)[0].near_branch32(), to);
+    }
+}
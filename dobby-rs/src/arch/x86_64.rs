@@ -0,0 +1,131 @@
+use crate::options::PatchAtomicity;
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// `jmp $`: parks threads that reach a patch while the rest of it is written.
+const JMP_SELF: [u8; 2] = [0xEB, 0xFE];
+
+/// Write `bytes` over code at `address` (which must be writable) so that threads fetching it
+/// concurrently never see half of a new instruction.
+///
+/// A patch within one aligned 8- or 16-byte block is merged into the block and published with a
+/// single `lock cmpxchg` or `lock cmpxchg16b`. A longer one first stores `jmp $` over its first two
+/// bytes the same way, then writes the rest and finally the real first two bytes. If even those
+/// straddle a 16-byte boundary, the bytes are copied as they are.
+pub(crate) unsafe fn write_code(address: *mut u8, bytes: &[u8]) -> PatchAtomicity {
+    if store_atomic(address, bytes) {
+        return PatchAtomicity::Atomic;
+    }
+    if store_atomic(address, &JMP_SELF) {
+        // x86 keeps stores in order, so the tail is visible before the head is released.
+        ptr::copy_nonoverlapping(bytes[2..].as_ptr(), address.add(2), bytes.len() - 2);
+        store_atomic(address, &bytes[..2]);
+        return PatchAtomicity::TwoPhase;
+    }
+    ptr::copy_nonoverlapping(bytes.as_ptr(), address, bytes.len());
+    PatchAtomicity::NonAtomic
+}
+
+fn merge(block: &mut [u8], offset: usize, bytes: &[u8]) {
+    block[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// Store `bytes` at `address` with one atomic compare-exchange of the aligned 8- or 16-byte block
+/// holding all of them; `false` (nothing written) if there is no such block.
+unsafe fn store_atomic(address: *mut u8, bytes: &[u8]) -> bool {
+    let start = address as usize;
+    let last = start + bytes.len().max(1) - 1;
+    if start & !7 == last & !7 {
+        let block = &*((start & !7) as *const AtomicU64);
+        let _ = block.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| {
+            let mut new = old.to_le_bytes();
+            merge(&mut new, start & 7, bytes);
+            Some(u64::from_le_bytes(new))
+        });
+        return true;
+    }
+    if start & !15 == last & !15 && std::is_x86_feature_detected!("cmpxchg16b") {
+        let block = (start & !15) as *mut u128;
+        let mut old = ptr::read_volatile(block);
+        loop {
+            let mut new = old.to_le_bytes();
+            merge(&mut new, start & 15, bytes);
+            match cmpxchg16b(block, old, u128::from_le_bytes(new)) {
+                Ok(()) => return true,
+                Err(current) => old = current,
+            }
+        }
+    }
+    false
+}
+
+/// `lock cmpxchg16b [dst]`; the value found there on failure.
+unsafe fn cmpxchg16b(dst: *mut u128, old: u128, new: u128) -> Result<(), u128> {
+    let (mut lo, mut hi) = (old as u64, (old >> 64) as u64);
+    let swapped: u8;
+    // rbx is reserved by LLVM, so the low half of `new` is swapped in and out around the exchange.
+    asm!(
+        "xchg {new_lo}, rbx",
+        "lock cmpxchg16b xmmword ptr [{dst}]",
+        "sete {swapped}",
+        "mov rbx, {new_lo}",
+        new_lo = inout(reg) new as u64 => _,
+        dst = in(reg) dst,
+        swapped = out(reg_byte) swapped,
+        inout("rax") lo,
+        inout("rdx") hi,
+        in("rcx") (new >> 64) as u64,
+        options(nostack),
+    );
+    if swapped != 0 {
+        Ok(())
+    } else {
+        Err((hi as u128) << 64 | lo as u128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(64))]
+    struct Code([u8; 64]);
+
+    // Write `bytes` at `offset` into a buffer of 0xCC and check only they changed.
+    fn write_at(offset: usize, bytes: &[u8]) -> PatchAtomicity {
+        let mut code = Code([0xCC; 64]);
+        let atomicity = unsafe { write_code(code.0.as_mut_ptr().add(offset), bytes) };
+        let mut expected = [0xCC; 64];
+        expected[offset..offset + bytes.len()].copy_from_slice(bytes);
+        assert_eq!(code.0, expected);
+        atomicity
+    }
+
+    #[test]
+    fn picks_the_widest_safe_write() {
+        let jmp = [0xE9, 1, 2, 3, 4];
+        let abs = [0xFF, 0x25, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(write_at(0, &jmp), PatchAtomicity::Atomic);
+        assert_eq!(write_at(3, &jmp), PatchAtomicity::Atomic);
+        // Across an 8-byte boundary, within a 16-byte block
+        assert_eq!(write_at(6, &jmp), PatchAtomicity::Atomic);
+        assert_eq!(write_at(2, &abs), PatchAtomicity::Atomic);
+        assert_eq!(write_at(12, &jmp), PatchAtomicity::TwoPhase);
+        assert_eq!(write_at(4, &abs), PatchAtomicity::TwoPhase);
+        assert_eq!(write_at(31, &abs), PatchAtomicity::NonAtomic);
+        assert_eq!(write_at(15, &[0xCC]), PatchAtomicity::Atomic);
+    }
+
+    #[test]
+    fn cmpxchg16b_reports_the_current_value() {
+        #[repr(align(16))]
+        struct Block(u128);
+        let mut block = Block(5);
+        unsafe {
+            assert_eq!(cmpxchg16b(&mut block.0, 6, 7), Err(5));
+            assert_eq!(cmpxchg16b(&mut block.0, 5, u128::MAX - 1), Ok(()));
+        }
+        assert_eq!(block.0, u128::MAX - 1);
+    }
+}
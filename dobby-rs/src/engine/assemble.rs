@@ -0,0 +1,178 @@
+//! Byte patches built from instructions: iced-x86's assembler on x86, a small encoder on aarch64.
+//!
+//! Every patch is padded with NOPs to the end of the last instruction it overlaps, so no partial
+//! instruction is left behind, and goes through [`code_patch_guarded`] to keep the original bytes.
+//! A patch that would cover a return or jump before its last instruction is refused: the code
+//! after it may not belong to the same function.
+
+use super::patch::{PatchGuard, code_patch_guarded};
+use crate::error::{Error, Result};
+use core::ffi::c_void;
+
+#[cfg(any(target_arch = "aarch64", test))]
+mod aarch64;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
+
+#[cfg(target_arch = "aarch64")]
+use aarch64 as isa;
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::Arm64Assembler;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use x86 as isa;
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+mod isa {
+    use crate::error::{Error, Result};
+
+    pub(super) const MAX_INSN_LEN: usize = 0;
+
+    pub(super) fn entry_prefix(_code: &[u8]) -> &'static [u8] {
+        &[]
+    }
+    pub(super) fn return_value(_ip: u64, _value: u64) -> Result<Vec<u8>> {
+        Err(Error::UnsupportedPlatform)
+    }
+    pub(super) fn branch(_ip: u64, _target: u64) -> Result<Vec<u8>> {
+        Err(Error::UnsupportedPlatform)
+    }
+    pub(super) fn nops(_len: usize) -> Vec<u8> {
+        Vec::new()
+    }
+    pub(super) fn instruction_end(_code: &[u8], _ip: u64, _len: usize) -> Result<usize> {
+        Err(Error::UnsupportedPlatform)
+    }
+    pub(super) fn flow_ends_before(_code: &[u8], _ip: u64, _len: usize) -> Result<bool> {
+        Err(Error::UnsupportedPlatform)
+    }
+}
+
+unsafe fn current(address: *mut c_void, len: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(address as *const u8, len + isa::MAX_INSN_LEN)
+}
+
+unsafe fn patch_whole(address: *mut c_void, mut code: Vec<u8>) -> Result<PatchGuard> {
+    let original = current(address, code.len());
+    if isa::flow_ends_before(original, address as u64, code.len())? {
+        return Err(Error::PatchTooSmall);
+    }
+    let end = isa::instruction_end(original, address as u64, code.len())?;
+    code.extend(isa::nops(end - code.len()));
+    code_patch_guarded(address, &code)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(super) unsafe fn patch_instructions(
+    address: *mut c_void,
+    build: impl FnOnce(
+        &mut iced_x86::code_asm::CodeAssembler,
+    ) -> core::result::Result<(), iced_x86::IcedError>,
+) -> Result<PatchGuard> {
+    patch_whole(address, isa::assemble(address as u64, build)?)
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(super) unsafe fn patch_instructions(
+    address: *mut c_void,
+    build: impl FnOnce(&mut Arm64Assembler) -> Result<()>,
+) -> Result<PatchGuard> {
+    patch_whole(address, isa::assemble(address as u64, build)?)
+}
+
+pub(super) unsafe fn patch_return_value(function: *mut c_void, value: u64) -> Result<PatchGuard> {
+    let mut code = isa::entry_prefix(current(function, 0)).to_vec();
+    code.extend(isa::return_value(
+        function as u64 + code.len() as u64,
+        value,
+    )?);
+    patch_whole(function, code)
+}
+
+pub(super) unsafe fn patch_branch(
+    address: *mut c_void,
+    target: *const c_void,
+) -> Result<PatchGuard> {
+    patch_whole(address, isa::branch(address as u64, target as u64)?)
+}
+
+pub(super) unsafe fn nop_out(address: *mut c_void, len: usize) -> Result<PatchGuard> {
+    if len == 0 {
+        return Err(Error::InvalidInput);
+    }
+    if isa::instruction_end(current(address, len), address as u64, len)? != len {
+        return Err(Error::InvalidInput);
+    }
+    code_patch_guarded(address, &isa::nops(len))
+}
+
+#[cfg(all(test, unix, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::platform::unix;
+    use iced_x86::code_asm::eax;
+
+    // mov eax, 7; add eax, ecx; ret
+    const FUNCTION: [u8; 8] = [0xB8, 7, 0, 0, 0, 0x01, 0xC8, 0xC3];
+
+    unsafe fn call(p: *const u8, arg: u32) -> u32 {
+        let f: extern "sysv64" fn(u32, u32, u32, u32) -> u32 = core::mem::transmute(p);
+        // The argument is in ecx as the fourth parameter.
+        f(0, 0, 0, arg)
+    }
+
+    #[test]
+    fn patches_whole_instructions_and_revert() {
+        unsafe {
+            let p = unix::alloc_executable(4096).expect("alloc") as *mut u8;
+            let f = p as *mut c_void;
+            core::ptr::copy_nonoverlapping(FUNCTION.as_ptr(), p, FUNCTION.len());
+            // A branch target: mov eax, 99; ret
+            core::ptr::copy_nonoverlapping([0xB8, 99, 0, 0, 0, 0xC3].as_ptr(), p.add(64), 6);
+            // A short function: xor eax, eax; ret
+            core::ptr::copy_nonoverlapping([0x31, 0xC0, 0xC3].as_ptr(), p.add(128), 3);
+            let short = p.add(128) as *mut c_void;
+            assert_eq!(call(p, 1), 8);
+
+            let g = patch_return_value(f, 42).expect("return value");
+            // Padded over `add eax, ecx` too.
+            assert_eq!(g.original_bytes(), &FUNCTION[..7]);
+            assert_eq!(call(p, 1), 42);
+            g.revert().expect("revert");
+            assert_eq!(call(p, 1), 8);
+
+            // `mov eax, 3` (5 bytes) covers all of `mov eax, 7` only.
+            let g = patch_instructions(f, |asm| asm.mov(eax, 3)).expect("asm");
+            assert_eq!(g.len(), 5);
+            assert_eq!(call(p, 1), 4);
+            drop(g);
+            // `xor eax, eax` (2 bytes) is padded to the end of `mov eax, 7`.
+            let g = patch_instructions(f, |asm| asm.xor(eax, eax)).expect("asm");
+            assert_eq!(g.len(), 5);
+            assert_eq!(call(p, 1), 1);
+            drop(g);
+
+            assert!(matches!(nop_out(f, 6), Err(Error::InvalidInput)));
+            let g = nop_out(p.add(5) as *mut c_void, 2).expect("nop");
+            assert_eq!(call(p, 1), 7);
+            drop(g);
+
+            let g = patch_branch(f, p.add(64) as *const c_void).expect("branch");
+            assert_eq!(g.len(), 5);
+            assert_eq!(call(p, 1), 99);
+            drop(g);
+            assert_eq!(core::slice::from_raw_parts(p, 8), &FUNCTION);
+
+            // A patch must not run past the `ret` of a shorter function.
+            assert!(matches!(
+                patch_return_value(short, 42),
+                Err(Error::PatchTooSmall)
+            ));
+            assert!(matches!(
+                patch_branch(short, p.add(64) as *const c_void),
+                Err(Error::PatchTooSmall)
+            ));
+            assert_eq!(call(short as *const u8, 1), 0);
+            unix::free_executable(f, 4096).expect("free");
+        }
+    }
+}
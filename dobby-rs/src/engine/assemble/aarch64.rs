@@ -0,0 +1,227 @@
+#![allow(dead_code)]
+
+use crate::arch::aarch64;
+use crate::error::{Error, Result};
+
+pub(super) const MAX_INSN_LEN: usize = 4;
+
+/// Builds A64 code for [`patch_instructions`](crate::patch_instructions), at the address it will
+/// be patched over.
+///
+/// Registers are numbers (`0` for `x0` up to `30` for `x30`). Branches are checked against the
+/// reach of their encoding; anything else can be emitted as a raw word with [`Self::emit`].
+#[derive(Debug)]
+pub struct Arm64Assembler {
+    ip: u64,
+    words: Vec<u32>,
+}
+
+impl Arm64Assembler {
+    pub(super) fn new(ip: u64) -> Self {
+        Self {
+            ip,
+            words: Vec::new(),
+        }
+    }
+    /// Address of the next instruction.
+    pub fn ip(&self) -> u64 {
+        self.ip + self.words.len() as u64 * 4
+    }
+    pub fn emit(&mut self, word: u32) -> Result<()> {
+        self.words.push(word);
+        Ok(())
+    }
+    pub fn nop(&mut self) -> Result<()> {
+        self.emit(aarch64::OP_NOP)
+    }
+    pub fn ret(&mut self) -> Result<()> {
+        self.emit(aarch64::OP_RET)
+    }
+    /// Load `value` into `x<rd>` with `movz`/`movn` and `movk`.
+    pub fn mov(&mut self, rd: u32, value: u64) -> Result<()> {
+        if rd > 30 {
+            return Err(Error::InvalidInput);
+        }
+        self.words.extend(aarch64::encode_mov(rd, value));
+        Ok(())
+    }
+    /// `b target`, if within ±128 MiB.
+    pub fn b(&mut self, target: u64) -> Result<()> {
+        let b = aarch64::encode_b(self.ip(), target).ok_or(Error::EncodeFailed)?;
+        self.emit(b)
+    }
+    /// `bl target`, if within ±128 MiB.
+    pub fn bl(&mut self, target: u64) -> Result<()> {
+        let b = aarch64::encode_b(self.ip(), target).ok_or(Error::EncodeFailed)?;
+        self.emit(b | 0x8000_0000)
+    }
+    pub fn br(&mut self, rn: u32) -> Result<()> {
+        self.register_branch(0xD61F_0000, rn)
+    }
+    pub fn blr(&mut self, rn: u32) -> Result<()> {
+        self.register_branch(0xD63F_0000, rn)
+    }
+    fn register_branch(&mut self, op: u32, rn: u32) -> Result<()> {
+        if rn > 30 {
+            return Err(Error::InvalidInput);
+        }
+        self.emit(op | (rn << 5))
+    }
+    fn bytes(&self) -> Vec<u8> {
+        self.words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+}
+
+pub(super) fn assemble(
+    ip: u64,
+    build: impl FnOnce(&mut Arm64Assembler) -> Result<()>,
+) -> Result<Vec<u8>> {
+    let mut asm = Arm64Assembler::new(ip);
+    build(&mut asm)?;
+    Ok(asm.bytes())
+}
+
+/// `bti c` if `code` starts with a landing pad. A `paciasp`/`pacibsp` is replaced too, as a patch
+/// that returns early would never authenticate the return address it signs.
+pub(super) fn entry_prefix(code: &[u8]) -> &'static [u8] {
+    const BTI_C: [u8; 4] = aarch64::OP_BTI_C.to_le_bytes();
+    match code.first_chunk::<4>() {
+        Some(w) if aarch64::is_landing_pad(u32::from_le_bytes(*w)) => &BTI_C,
+        _ => &[],
+    }
+}
+
+/// Return `value` in `x0` at `ip`.
+pub(super) fn return_value(ip: u64, value: u64) -> Result<Vec<u8>> {
+    assemble(ip, |asm| {
+        asm.mov(0, value)?;
+        asm.ret()
+    })
+}
+
+/// `b` from `ip` to `target`, or a literal load of the address into `x17` and `ret x17`, which
+/// (unlike `br`) doesn't need a BTI landing pad at `target`.
+pub(super) fn branch(ip: u64, target: u64) -> Result<Vec<u8>> {
+    assemble(ip, |asm| {
+        if asm.b(target).is_ok() {
+            return Ok(());
+        }
+        // ldr x17, #8; ret x17; .quad target
+        asm.emit(0x5800_0051)?;
+        asm.emit(aarch64::OP_RET_X17)?;
+        asm.emit(target as u32)?;
+        asm.emit((target >> 32) as u32)
+    })
+}
+
+pub(super) fn nops(len: usize) -> Vec<u8> {
+    aarch64::OP_NOP.to_le_bytes().repeat(len / 4)
+}
+
+pub(super) fn instruction_end(_code: &[u8], _ip: u64, len: usize) -> Result<usize> {
+    Ok(len.next_multiple_of(4))
+}
+
+/// Whether an instruction of `code` that ends before `len` is a `b`, `br` or `ret`.
+pub(super) fn flow_ends_before(code: &[u8], _ip: u64, len: usize) -> Result<bool> {
+    Ok(code
+        .chunks_exact(4)
+        .take(len.saturating_sub(1) / 4)
+        .any(|w| aarch64::ends_flow(u32::from_le_bytes(w.try_into().unwrap()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::aarch64::emu::{Cpu, Memory};
+
+    const IP: u64 = 0x7f12_3456_0000;
+    const LR: u64 = 0x7f00_0000_4000;
+
+    fn words(code: &[u8]) -> Vec<u32> {
+        code.chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Run `code` placed at `IP`, called from `LR`, until it leaves it.
+    fn run(code: &[u8]) -> Cpu {
+        let mut mem = Memory::default();
+        mem.write_words(IP, &words(code));
+        let mut cpu = Cpu {
+            pc: IP,
+            ..Cpu::default()
+        };
+        cpu.x[30] = LR;
+        cpu.run(&mem, IP..IP + code.len() as u64, 8);
+        cpu
+    }
+
+    #[test]
+    fn emulated_branches_reach_their_target() {
+        for target in [IP + 0x40, IP - 0x7FF_FFFC, IP + 0x800_0000, 0x1234_5678] {
+            let code = branch(IP, target).unwrap();
+            let near = aarch64::encode_b(IP, target).is_some();
+            assert_eq!(code.len(), if near { 4 } else { 16 }, "{target:#x}");
+            if !near {
+                assert_eq!(words(&code)[1], aarch64::OP_RET_X17);
+            }
+            assert_eq!(run(&code).pc, target, "{target:#x}");
+        }
+    }
+
+    #[test]
+    fn emulated_return_value() {
+        for value in [
+            0,
+            42,
+            0xFFFF_FFFF,
+            0x1234_5678_9ABC_DEF0,
+            0xFFFF_0000_FFFF_1234,
+            u64::MAX,
+        ] {
+            let cpu = run(&return_value(IP, value).unwrap());
+            assert_eq!(cpu.x[0], value);
+            assert_eq!(cpu.pc, LR);
+        }
+    }
+
+    #[test]
+    fn emulated_entry_prefix_keeps_the_landing_pad() {
+        // bti c, bti jc, paciasp, pacibsp; then stp x29, x30, [sp, #-16]!
+        for (first, pad) in [
+            (aarch64::OP_BTI_C, true),
+            (0xD503_24DF, true),
+            (0xD503_233F, true),
+            (0xD503_237F, true),
+            (0xA9BF_7BFD, false),
+        ] {
+            let mut code = entry_prefix(&first.to_le_bytes()).to_vec();
+            assert_eq!(code.len(), if pad { 4 } else { 0 }, "{first:#010x}");
+            code.extend(return_value(IP + code.len() as u64, 7).unwrap());
+            if pad {
+                assert_eq!(words(&code)[0], aarch64::OP_BTI_C);
+            }
+            let cpu = run(&code);
+            assert_eq!(cpu.x[0], 7);
+            assert_eq!(cpu.pc, LR);
+        }
+    }
+
+    #[test]
+    fn flow_must_not_end_before_the_last_instruction() {
+        let code: Vec<u8> = [0xD280_0000, aarch64::OP_RET, aarch64::OP_NOP]
+            .iter()
+            .flat_map(|w: &u32| w.to_le_bytes())
+            .collect();
+        assert!(!flow_ends_before(&code, IP, 8).unwrap());
+        assert!(flow_ends_before(&code, IP, 9).unwrap());
+        assert!(flow_ends_before(&code, IP, 12).unwrap());
+        // paciasp; retaa; nop: a pac-ret leaf
+        let code: Vec<u8> = [0xD503_233F, 0xD65F_0BFF, aarch64::OP_NOP]
+            .iter()
+            .flat_map(|w: &u32| w.to_le_bytes())
+            .collect();
+        assert!(flow_ends_before(&code, IP, 12).unwrap());
+    }
+}
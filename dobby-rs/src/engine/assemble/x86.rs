@@ -0,0 +1,208 @@
+use crate::error::{Error, Result};
+use iced_x86::code_asm::{CodeAssembler, eax, edx, rax};
+use iced_x86::{Decoder, DecoderOptions, FlowControl, IcedError};
+
+const BITNESS: u32 = usize::BITS;
+pub(super) const MAX_INSN_LEN: usize = 15;
+
+#[cfg(target_arch = "x86_64")]
+const ENDBR: [u8; 4] = [0xF3, 0x0F, 0x1E, 0xFA];
+#[cfg(target_arch = "x86")]
+const ENDBR: [u8; 4] = [0xF3, 0x0F, 0x1E, 0xFB];
+
+// The recommended NOP of each length from 1 to 9 bytes.
+const NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0F, 0x1F, 0x00],
+    &[0x0F, 0x1F, 0x40, 0x00],
+    &[0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+pub(super) fn assemble(
+    ip: u64,
+    build: impl FnOnce(&mut CodeAssembler) -> core::result::Result<(), IcedError>,
+) -> Result<Vec<u8>> {
+    let mut asm = CodeAssembler::new(BITNESS).map_err(|_| Error::EncodeFailed)?;
+    build(&mut asm).map_err(|_| Error::EncodeFailed)?;
+    asm.assemble(ip).map_err(|_| Error::EncodeFailed)
+}
+
+/// `endbr64` (`endbr32`) if `code` starts with it.
+pub(super) fn entry_prefix(code: &[u8]) -> &'static [u8] {
+    if code.starts_with(&ENDBR) {
+        &ENDBR
+    } else {
+        &[]
+    }
+}
+
+/// Return `value` in `rax` (`edx:eax`) at `ip`.
+pub(super) fn return_value(ip: u64, value: u64) -> Result<Vec<u8>> {
+    assemble(ip, |asm| {
+        if BITNESS == 32 {
+            asm.mov(eax, value as u32)?;
+            if value >> 32 != 0 {
+                asm.mov(edx, (value >> 32) as u32)?;
+            }
+        } else if let Ok(value) = u32::try_from(value) {
+            // Writing `eax` clears the upper half.
+            asm.mov(eax, value)?;
+        } else {
+            asm.mov(rax, value)?;
+        }
+        asm.ret()
+    })
+}
+
+/// `jmp rel32` from `ip` to `target`, or `jmp [rip]` and the address if out of range.
+pub(super) fn branch(ip: u64, target: u64) -> Result<Vec<u8>> {
+    let disp = target.wrapping_sub(ip.wrapping_add(5)) as i64;
+    if BITNESS == 32 || i32::try_from(disp).is_ok() {
+        let mut b = vec![0xE9];
+        b.extend((disp as i32).to_le_bytes());
+        return Ok(b);
+    }
+    let mut b = vec![0xFF, 0x25, 0, 0, 0, 0];
+    b.extend(target.to_le_bytes());
+    Ok(b)
+}
+
+/// `len` bytes of NOPs, as few instructions as possible.
+pub(super) fn nops(len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let n = (len - out.len()).min(NOPS.len());
+        out.extend(NOPS[n - 1]);
+    }
+    out
+}
+
+/// The end of the first instruction of `code` (at `ip`) that ends at or past `len`.
+pub(super) fn instruction_end(code: &[u8], ip: u64, len: usize) -> Result<usize> {
+    let mut decoder = Decoder::with_ip(BITNESS, code, ip, DecoderOptions::NONE);
+    let mut end = 0;
+    while end < len {
+        let i = decoder.decode();
+        if i.is_invalid() {
+            return Err(Error::DecodeFailed);
+        }
+        end += i.len();
+    }
+    Ok(end)
+}
+
+/// Whether an instruction of `code` (at `ip`) that ends before `len` never falls through: a
+/// return, jump, interrupt or trap.
+pub(super) fn flow_ends_before(code: &[u8], ip: u64, len: usize) -> Result<bool> {
+    let mut decoder = Decoder::with_ip(BITNESS, code, ip, DecoderOptions::NONE);
+    let mut end = 0;
+    while end < len {
+        let i = decoder.decode();
+        if i.is_invalid() {
+            return Err(Error::DecodeFailed);
+        }
+        end += i.len();
+        let terminal = matches!(
+            i.flow_control(),
+            FlowControl::Return
+                | FlowControl::UnconditionalBranch
+                | FlowControl::IndirectBranch
+                | FlowControl::Interrupt
+                | FlowControl::Exception
+        );
+        if terminal && end < len {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use iced_x86::{Code, Instruction};
+
+    fn decode(code: &[u8], ip: u64) -> Vec<Instruction> {
+        Decoder::with_ip(BITNESS, code, ip, DecoderOptions::NONE)
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn nops_are_whole_instructions() {
+        for len in 1..=30 {
+            let code = nops(len);
+            assert_eq!(code.len(), len);
+            let insns = decode(&code, 0);
+            assert!(insns.iter().all(|i| i.code() == Code::Nopd
+                || i.code() == Code::Nopw
+                || i.code() == Code::Nop_rm32
+                || i.code() == Code::Nop_rm16));
+            assert_eq!(insns.len(), len.div_ceil(9));
+        }
+    }
+
+    #[test]
+    fn branches_reach_their_target() {
+        let ip = 0x7000_1000;
+        for target in [ip + 0x40, ip - 0x100_0000, 0x7fff_0000_0000] {
+            let code = branch(ip, target).unwrap();
+            let insn = decode(&code, ip)[0];
+            if code.len() == 5 {
+                assert_eq!(insn.near_branch_target(), target);
+            } else {
+                assert_eq!(insn.code(), Code::Jmp_rm64);
+                assert_eq!(insn.ip_rel_memory_address(), ip + 6);
+                assert_eq!(code[6..], target.to_le_bytes());
+            }
+        }
+        assert_eq!(branch(ip, 0x7fff_0000_0000).unwrap().len(), 14);
+    }
+
+    #[test]
+    fn return_value_is_shortest_mov() {
+        let code = return_value(0x1000, 1).unwrap();
+        assert_eq!(code, [0xB8, 1, 0, 0, 0, 0xC3]);
+        let code = return_value(0x1000, 0x1_0000_0000).unwrap();
+        let insns = decode(&code, 0x1000);
+        assert_eq!(insns[0].code(), Code::Mov_r64_imm64);
+        assert_eq!(insns[0].immediate64(), 0x1_0000_0000);
+        assert_eq!(insns[1].code(), Code::Retnq);
+    }
+
+    #[test]
+    fn instruction_end_rounds_up_to_a_boundary() {
+        // push rbp; mov rbp, rsp; sub rsp, 0x20; mov eax, 1
+        let code = [
+            0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x20, 0xB8, 1, 0, 0, 0,
+        ];
+        assert_eq!(instruction_end(&code, 0, 1).unwrap(), 1);
+        assert_eq!(instruction_end(&code, 0, 2).unwrap(), 4);
+        assert_eq!(instruction_end(&code, 0, 5).unwrap(), 8);
+        assert_eq!(instruction_end(&code, 0, 9).unwrap(), 13);
+        assert!(matches!(
+            instruction_end(&[0x06], 0, 1),
+            Err(Error::DecodeFailed)
+        ));
+        assert_eq!(entry_prefix(&[0xF3, 0x0F, 0x1E, 0xFA, 0x55]), ENDBR);
+        assert!(entry_prefix(&code).is_empty());
+    }
+
+    #[test]
+    fn flow_must_not_end_before_the_last_instruction() {
+        // xor eax, eax; ret; int3; jmp rel32
+        let code = [0x31, 0xC0, 0xC3, 0xCC, 0xE9, 0, 0, 0, 0];
+        // Ending on the `ret` itself is fine.
+        assert!(!flow_ends_before(&code, 0, 2).unwrap());
+        assert!(!flow_ends_before(&code, 0, 3).unwrap());
+        assert!(flow_ends_before(&code, 0, 4).unwrap());
+        assert!(!flow_ends_before(&code[3..], 3, 1).unwrap());
+        assert!(flow_ends_before(&code[3..], 3, 2).unwrap());
+        assert!(!flow_ends_before(&code[4..], 4, 5).unwrap());
+    }
+}
@@ -1,34 +1,63 @@
+use crate::allocator::ExecutableAllocator;
 use crate::error::Result;
+use crate::options::{HookOptions, PatchAtomicity, PatchStyle};
 use core::ffi::{c_char, c_void};
 
 pub(crate) struct HookBuild {
     pub(crate) trampoline: *mut c_void,
     pub(crate) trampoline_size: usize,
     pub(crate) original: Vec<u8>,
-    pub(crate) patch_len: usize,
+    /// Bytes to write at the target; the same length as `original`.
+    pub(crate) patch: Vec<u8>,
+    /// The patch is a trap instruction; the target must be registered with the SIGTRAP handler.
+    pub(crate) breakpoint: bool,
+    /// Offset of the trap instruction within the patch.
+    pub(crate) trap_offset: usize,
+    /// Jump written at the target (never `Auto`); meaningless for breakpoints.
+    pub(crate) style: PatchStyle,
+    /// The target's image is marked for CET indirect branch tracking (x86_64 ELF only).
+    pub(crate) ibt: bool,
 }
 
 pub(crate) trait Backend: Sync {
-    unsafe fn code_patch(&self, address: *mut c_void, buffer: *const u8, size: usize)
-    -> Result<()>;
-    unsafe fn hook_build(&self, address: *mut c_void, fake_func: *mut c_void) -> Result<HookBuild>;
-    unsafe fn hook_destroy(
+    /// Write `size` bytes from `buffer` over code at `address`, as atomically as the target allows.
+    unsafe fn code_patch(
         &self,
         address: *mut c_void,
-        original: &[u8],
-        patch_len: usize,
-        trampoline: *mut c_void,
-        trampoline_size: usize,
-    ) -> Result<()>;
+        buffer: *const u8,
+        size: usize,
+    ) -> Result<PatchAtomicity>;
+    unsafe fn restore_patch(&self, address: *mut c_void, original: &[u8]) -> Result<()>;
+    /// Build the trampoline for `address` and compute the patch bytes, without touching the target.
+    unsafe fn hook_build(
+        &self,
+        address: *mut c_void,
+        fake_func: *mut c_void,
+        options: &HookOptions,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild>;
+    /// Where the code of the function at `address` starts, for patching and verifying it. The same
+    /// address except for Thumb function pointers, which have bit 0 set.
+    fn code_address(&self, address: *mut c_void) -> *mut c_void {
+        address
+    }
+    /// Resolve a symbol; `load` allows loading `image_name` if it isn't loaded yet.
     unsafe fn symbol_resolver(
         &self,
         image_name: *const c_char,
         symbol_name: *const c_char,
+        load: bool,
     ) -> *mut c_void;
 }
 
 #[cfg(all(unix, target_arch = "aarch64"))]
 mod unix_aarch64;
+#[cfg(all(unix, target_arch = "arm"))]
+mod unix_arm;
+#[cfg(all(unix, target_arch = "riscv64"))]
+mod unix_riscv64;
+#[cfg(all(unix, target_arch = "x86"))]
+mod unix_x86;
 #[cfg(all(unix, target_arch = "x86_64"))]
 mod unix_x86_64;
 #[cfg(all(windows, target_arch = "x86_64"))]
@@ -49,31 +78,55 @@ pub(crate) fn get() -> &'static dyn Backend {
     {
         &unix_aarch64::BACKEND
     }
+    #[cfg(all(unix, target_arch = "x86"))]
+    {
+        &unix_x86::BACKEND
+    }
+    #[cfg(all(unix, target_arch = "arm"))]
+    {
+        &unix_arm::BACKEND
+    }
+    #[cfg(all(unix, target_arch = "riscv64"))]
+    {
+        &unix_riscv64::BACKEND
+    }
     #[cfg(not(any(
         all(windows, target_arch = "x86_64"),
         all(unix, target_arch = "x86_64"),
-        all(unix, target_arch = "aarch64")
+        all(unix, target_arch = "aarch64"),
+        all(unix, target_arch = "x86"),
+        all(unix, target_arch = "arm"),
+        all(unix, target_arch = "riscv64")
     )))]
     {
         struct Unsupported;
         impl Backend for Unsupported {
-            unsafe fn code_patch(&self, _a: *mut c_void, _b: *const u8, _s: usize) -> Result<()> {
+            unsafe fn code_patch(
+                &self,
+                _a: *mut c_void,
+                _b: *const u8,
+                _s: usize,
+            ) -> Result<PatchAtomicity> {
                 Err(crate::error::Error::UnsupportedPlatform)
             }
-            unsafe fn hook_build(&self, _a: *mut c_void, _f: *mut c_void) -> Result<HookBuild> {
+            unsafe fn restore_patch(&self, _a: *mut c_void, _o: &[u8]) -> Result<()> {
                 Err(crate::error::Error::UnsupportedPlatform)
             }
-            unsafe fn hook_destroy(
+            unsafe fn hook_build(
                 &self,
                 _a: *mut c_void,
-                _o: &[u8],
-                _p: usize,
-                _t: *mut c_void,
-                _ts: usize,
-            ) -> Result<()> {
+                _f: *mut c_void,
+                _o: &HookOptions,
+                _al: &dyn ExecutableAllocator,
+            ) -> Result<HookBuild> {
                 Err(crate::error::Error::UnsupportedPlatform)
             }
-            unsafe fn symbol_resolver(&self, _i: *const c_char, _s: *const c_char) -> *mut c_void {
+            unsafe fn symbol_resolver(
+                &self,
+                _i: *const c_char,
+                _s: *const c_char,
+                _l: bool,
+            ) -> *mut c_void {
                 core::ptr::null_mut()
             }
         }
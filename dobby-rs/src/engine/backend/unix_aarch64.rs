@@ -1,6 +1,8 @@
 use super::{Backend, HookBuild};
+use crate::allocator::ExecutableAllocator;
 use crate::arch::aarch64;
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::options::{HookMode, HookOptions, PatchAtomicity, PatchStyle};
 use crate::platform;
 use core::ffi::{c_char, c_void};
 use core::ptr;
@@ -8,9 +10,38 @@ use core::ptr;
 pub(crate) static BACKEND: UnixAarch64 = UnixAarch64;
 pub(crate) struct UnixAarch64;
 
+/// A trampoline block: relocated code, the jump back, and optionally a relay to the detour.
+struct Trampoline {
+    base: *mut c_void,
+    size: usize,
+    relay: u64,
+}
+
+/// The instructions a patch replaces. A BTI landing pad at the entry is left in place (or, for
+/// `paciasp`/`pacibsp`, replaced by `bti c`) and the patch goes right after it, so indirect calls
+/// still land on a valid pad. Signing moves into the trampoline, where it runs with the caller's
+/// LR and SP, so the original epilogue still authenticates.
+struct Stolen {
+    original: Vec<u8>,
+    /// Where the patch proper starts.
+    site: u64,
+    /// Instructions copied into the trampoline and where they came from; a kept `bti` is not.
+    words: Vec<u32>,
+    from: u64,
+    /// Written before the patch proper, over the landing pad.
+    pad: Option<u32>,
+}
+
 impl UnixAarch64 {
-    const JMP_STUB_SIZE: usize = 16;
-    const PATCH_LEN: usize = 16;
+    // Resume stub (`b`, or `ldr x17, #8; ret x17; .quad`) and relay (`bti c; ldr x17, #8; br x17;
+    // .quad`).
+    const RESUME_SIZE: usize = 16;
+    const RELAY_SIZE: usize = 20;
+    const BRK_0: u32 = 0xD420_0000;
+    // Reach of `b`, and of `adrp` with a margin for the block's own size.
+    const B_RANGE: usize = 0x7FF_FFFF;
+    const PAGE_RANGE: usize = 0xFFFF_0000;
+
     fn abs_jmp(dest: u64) -> [u8; 16] {
         let ldr_x17_lit_8: u32 = 0x58000000 | (2 << 5) | 17;
         let br_x17: u32 = 0xD61F0000 | (17 << 5);
@@ -20,6 +51,238 @@ impl UnixAarch64 {
         buf[8..16].copy_from_slice(&dest.to_le_bytes());
         buf
     }
+
+    // Back into the original function, which has no landing pad there: `b`, or `ret` when out of
+    // range, never `br`.
+    fn resume_jmp(from: u64, dest: u64) -> Vec<u8> {
+        if let Some(b) = aarch64::encode_b(from, dest) {
+            return b.to_le_bytes().to_vec();
+        }
+        let mut buf = Self::abs_jmp(dest);
+        buf[4..8].copy_from_slice(&aarch64::OP_RET_X17.to_le_bytes());
+        buf.to_vec()
+    }
+
+    unsafe fn steal(address: *mut c_void, count: usize) -> Stolen {
+        let read = |i: usize| {
+            let b = core::slice::from_raw_parts((address as *const u8).add(i * 4), 4);
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        };
+        let first = read(0);
+        let pad = aarch64::is_landing_pad(first);
+        let total = count + pad as usize;
+        let mut words: Vec<u32> = (0..total).map(read).collect();
+        let mut from = address as u64;
+        if aarch64::is_bti(first) {
+            words.remove(0);
+            from += 4;
+        }
+        Stolen {
+            original: core::slice::from_raw_parts(address as *const u8, total * 4).to_vec(),
+            site: address as u64 + pad as u64 * 4,
+            words,
+            from,
+            pad: pad.then(|| {
+                if aarch64::is_bti(first) {
+                    first
+                } else {
+                    aarch64::OP_BTI_C
+                }
+            }),
+        }
+    }
+
+    /// Copy `words` from `address` into a new trampoline, relocated against the trampoline's own
+    /// address, behind a `bti c` and followed by a jump to `resume` and, with `relay_to`, a relay
+    /// there for compact patches to branch to.
+    ///
+    /// With `near`, the block must lie within that many bytes of `address`; `Ok(None)` means the
+    /// allocator had no such slot. Far placements expand PC-relative instructions, so the size
+    /// comes from a first pass and the block is reallocated if the real placement needs more.
+    unsafe fn build_trampoline(
+        words: &[u32],
+        address: u64,
+        resume: u64,
+        allocator: &dyn ExecutableAllocator,
+        near: Option<usize>,
+        relay_to: Option<u64>,
+    ) -> Result<Option<Trampoline>> {
+        let stubs = 4
+            + Self::RESUME_SIZE
+            + if relay_to.is_some() {
+                Self::RELAY_SIZE
+            } else {
+                0
+            };
+        let mut size = aarch64::relocate(words, address, address)?.len() * 4 + stubs;
+        loop {
+            let tramp = match near {
+                Some(range) => match allocator.alloc_near(size, address as usize, range)? {
+                    Some(p) => p,
+                    None => return Ok(None),
+                },
+                None => allocator.alloc(size)?,
+            };
+            let relocated = match aarch64::relocate(words, address, tramp as u64 + 4) {
+                Ok(r) => r,
+                Err(e) => {
+                    let _ = allocator.free(tramp, size);
+                    return Err(e);
+                }
+            };
+            let needed = relocated.len() * 4 + stubs;
+            if needed > size {
+                let _ = allocator.free(tramp, size);
+                size = needed;
+                continue;
+            }
+            let mut code = vec![aarch64::OP_BTI_C];
+            code.extend(relocated);
+            let mut bytes: Vec<u8> = code.iter().flat_map(|w| w.to_le_bytes()).collect();
+            bytes.extend(Self::resume_jmp(tramp as u64 + bytes.len() as u64, resume));
+            let relay = tramp as u64 + bytes.len() as u64;
+            if let Some(dest) = relay_to {
+                bytes.extend(aarch64::OP_BTI_C.to_le_bytes());
+                bytes.extend(Self::abs_jmp(dest));
+            }
+            ptr::copy_nonoverlapping(bytes.as_ptr(), tramp as *mut u8, bytes.len());
+            platform::unix::flush_icache(tramp, bytes.len());
+            return Ok(Some(Trampoline {
+                base: tramp,
+                size,
+                relay,
+            }));
+        }
+    }
+
+    /// Patch with the shortest style `style` allows, or fail with `EncodeFailed` if no relay could
+    /// be placed within reach of a compact one. With `near_first`, an absolute patch still tries a
+    /// trampoline near the target before one anywhere.
+    unsafe fn inline_build(
+        address: *mut c_void,
+        fake_func: *mut c_void,
+        style: PatchStyle,
+        near_first: bool,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild> {
+        let candidates: &[PatchStyle] = match style {
+            PatchStyle::Auto => &[PatchStyle::Relative, PatchStyle::Page, PatchStyle::Absolute],
+            PatchStyle::Relative => &[PatchStyle::Relative],
+            PatchStyle::Page => &[PatchStyle::Page],
+            PatchStyle::Absolute => &[PatchStyle::Absolute],
+        };
+        for &style in candidates {
+            if let Some(build) =
+                Self::inline_build_with(address, fake_func, style, near_first, allocator)?
+            {
+                return Ok(build);
+            }
+        }
+        Err(Error::EncodeFailed)
+    }
+
+    unsafe fn inline_build_with(
+        address: *mut c_void,
+        fake_func: *mut c_void,
+        style: PatchStyle,
+        near_first: bool,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<Option<HookBuild>> {
+        let (count, near) = match style {
+            PatchStyle::Relative => (1, Some(Self::B_RANGE)),
+            PatchStyle::Page => (3, Some(Self::PAGE_RANGE)),
+            _ => (4, None),
+        };
+        let stolen = Self::steal(address, count);
+        // The function ends (or jumps away) before the patch does.
+        let body = &stolen.words[stolen.words.len() - count..];
+        if body[..count - 1].iter().any(|w| aarch64::ends_flow(*w)) {
+            return Err(Error::PatchTooSmall);
+        }
+        // A stolen branch back into the patch would land in the middle of the new jump.
+        let patched = stolen.site + 1..stolen.site + count as u64 * 4;
+        if aarch64::branches_into(&stolen.words, stolen.from, patched) {
+            return Err(Error::PatchTooSmall);
+        }
+        let resume = address as u64 + stolen.original.len() as u64;
+        let relay_to = near.map(|_| fake_func as u64);
+        let mut placement = near.or(near_first.then_some(Self::PAGE_RANGE));
+        let tramp = loop {
+            match Self::build_trampoline(
+                &stolen.words,
+                stolen.from,
+                resume,
+                allocator,
+                placement,
+                relay_to,
+            )? {
+                Some(tramp) => break tramp,
+                // An absolute patch only prefers a near trampoline.
+                None if near.is_none() && placement.is_some() => placement = None,
+                None => return Ok(None),
+            }
+        };
+        let jump = match style {
+            PatchStyle::Relative => {
+                aarch64::encode_b(stolen.site, tramp.relay).map(|w| w.to_le_bytes().to_vec())
+            }
+            PatchStyle::Page => aarch64::encode_adrp_add_br(stolen.site, tramp.relay, 17)
+                .map(|words| words.iter().flat_map(|w| w.to_le_bytes()).collect()),
+            _ => Some(Self::abs_jmp(fake_func as u64).to_vec()),
+        };
+        let Some(jump) = jump else {
+            // The allocator handed out a block outside the range it was asked for.
+            let _ = allocator.free(tramp.base, tramp.size);
+            return Ok(None);
+        };
+        let mut patch: Vec<u8> = stolen
+            .pad
+            .map(u32::to_le_bytes)
+            .into_iter()
+            .flatten()
+            .collect();
+        patch.extend(jump);
+        Ok(Some(HookBuild {
+            trampoline: tramp.base,
+            trampoline_size: tramp.size,
+            original: stolen.original,
+            patch,
+            breakpoint: false,
+            trap_offset: 0,
+            style,
+            ibt: false,
+        }))
+    }
+
+    /// Replace only the first instruction (after a landing pad) with `brk #0`; the trampoline runs
+    /// the stolen instructions (relocated) and continues after them.
+    unsafe fn breakpoint_build(
+        address: *mut c_void,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild> {
+        let stolen = Self::steal(address, 1);
+        let resume = address as u64 + stolen.original.len() as u64;
+        let tramp =
+            Self::build_trampoline(&stolen.words, stolen.from, resume, allocator, None, None)?
+                .ok_or(Error::RelocationFailed)?;
+        let mut patch: Vec<u8> = stolen
+            .pad
+            .map(u32::to_le_bytes)
+            .into_iter()
+            .flatten()
+            .collect();
+        patch.extend(Self::BRK_0.to_le_bytes());
+        Ok(HookBuild {
+            trampoline: tramp.base,
+            trampoline_size: tramp.size,
+            original: stolen.original,
+            trap_offset: (stolen.site - address as u64) as usize,
+            patch,
+            breakpoint: true,
+            style: PatchStyle::Absolute,
+            ibt: false,
+        })
+    }
 }
 
 impl Backend for UnixAarch64 {
@@ -28,60 +291,68 @@ impl Backend for UnixAarch64 {
         address: *mut c_void,
         buffer: *const u8,
         size: usize,
-    ) -> Result<()> {
+    ) -> Result<PatchAtomicity> {
         platform::unix::code_patch(address, buffer, size)
     }
-    unsafe fn hook_build(&self, address: *mut c_void, fake_func: *mut c_void) -> Result<HookBuild> {
-        let stolen = core::slice::from_raw_parts(address as *const u8, Self::PATCH_LEN);
-        let mut words = [0u32; 4];
-        for i in 0..4 {
-            words[i] = u32::from_le_bytes([
-                stolen[i * 4],
-                stolen[i * 4 + 1],
-                stolen[i * 4 + 2],
-                stolen[i * 4 + 3],
-            ]);
-        }
-        let relocated = aarch64::relocate(&words, address as u64, 0x1000)?;
-        let tramp_size = 256usize;
-        let tramp = platform::unix::alloc_executable(tramp_size)?;
-        let mut offset = 0usize;
-        for w in relocated {
-            ptr::copy_nonoverlapping(w.to_le_bytes().as_ptr(), (tramp as *mut u8).add(offset), 4);
-            offset += 4;
-        }
-        let jb = Self::abs_jmp(address as u64 + Self::PATCH_LEN as u64);
-        ptr::copy_nonoverlapping(
-            jb.as_ptr(),
-            (tramp as *mut u8).add(offset),
-            Self::JMP_STUB_SIZE,
-        );
-        platform::unix::flush_icache(tramp, offset + Self::JMP_STUB_SIZE);
-        let detour = Self::abs_jmp(fake_func as u64);
-        platform::unix::code_patch(address, detour.as_ptr(), detour.len())?;
-        Ok(HookBuild {
-            trampoline: tramp,
-            trampoline_size: tramp_size,
-            original: stolen.to_vec(),
-            patch_len: Self::PATCH_LEN,
-        })
+    unsafe fn restore_patch(&self, address: *mut c_void, original: &[u8]) -> Result<()> {
+        platform::unix::restore_patch(address, original, original.len())
     }
-    unsafe fn hook_destroy(
+    unsafe fn hook_build(
         &self,
         address: *mut c_void,
-        original: &[u8],
-        patch_len: usize,
-        trampoline: *mut c_void,
-        trampoline_size: usize,
-    ) -> Result<()> {
-        platform::unix::restore_patch(address, original, patch_len)?;
-        platform::unix::free_executable(trampoline, trampoline_size)
+        fake_func: *mut c_void,
+        options: &HookOptions,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild> {
+        let (style, near_first) = (options.patch_style, options.near_first());
+        match options.mode {
+            HookMode::Inline => {
+                Self::inline_build(address, fake_func, style, near_first, allocator)
+            }
+            HookMode::Breakpoint if !platform::unix::TRAP_HOOKS => Err(Error::UnsupportedPlatform),
+            HookMode::Breakpoint => Self::breakpoint_build(address, allocator),
+            HookMode::Auto => {
+                match Self::inline_build(address, fake_func, style, near_first, allocator) {
+                    Err(e @ (Error::PatchTooSmall | Error::RelocationFailed))
+                        if platform::unix::TRAP_HOOKS =>
+                    {
+                        Self::breakpoint_build(address, allocator).map_err(|_| e)
+                    }
+                    r => r,
+                }
+            }
+        }
     }
     unsafe fn symbol_resolver(
         &self,
         image_name: *const c_char,
         symbol_name: *const c_char,
+        load: bool,
     ) -> *mut c_void {
-        platform::unix::symbol_resolver(image_name, symbol_name)
+        platform::unix::symbol_resolver(image_name, symbol_name, load)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::DefaultAllocator;
+
+    #[test]
+    fn branches_into_the_patch_are_refused() {
+        // cbz x0, entry+8; nop; nop; ret
+        let code = [
+            0xB400_0040,
+            aarch64::OP_NOP,
+            aarch64::OP_NOP,
+            aarch64::OP_RET,
+        ];
+        let address = code.as_ptr() as *mut c_void;
+        let allocator = DefaultAllocator::default();
+        for style in [PatchStyle::Page, PatchStyle::Absolute] {
+            let build =
+                unsafe { UnixAarch64::inline_build(address, address, style, false, &allocator) };
+            assert!(matches!(build, Err(Error::PatchTooSmall)), "{style:?}");
+        }
     }
 }
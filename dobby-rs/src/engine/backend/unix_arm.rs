@@ -0,0 +1,124 @@
+use super::{Backend, HookBuild};
+use crate::allocator::ExecutableAllocator;
+use crate::arch::arm;
+use crate::error::{Error, Result};
+use crate::options::{HookMode, HookOptions, PatchAtomicity, PatchStyle};
+use crate::platform;
+use core::ffi::{c_char, c_void};
+use core::ptr;
+
+pub(crate) static BACKEND: UnixArm = UnixArm;
+pub(crate) struct UnixArm;
+
+// Stolen code grows by at most a literal load per instruction, with IT blocks stolen whole.
+const TRAMP_SIZE: usize = 256;
+
+impl UnixArm {
+    /// Relocated code for a trampoline at `tramp`, followed by the jump back to `resume`. Thumb
+    /// trampolines start with an ARM-state switch to Thumb, so the trampoline pointer itself is a
+    /// plain (ARM) code address.
+    fn trampoline_code(code: &[u8], thumb: bool, site: u32, tramp: u32) -> Result<Vec<u8>> {
+        let resume = site + code.len() as u32;
+        let mut out = Vec::new();
+        if thumb {
+            out.extend(arm::ARM_TO_THUMB.iter().flat_map(|w| w.to_le_bytes()));
+            let base = tramp + out.len() as u32;
+            out.extend(arm::relocate_thumb(code, site, base)?);
+            let back = tramp + out.len() as u32;
+            out.extend(arm::thumb_jump(back, resume | 1));
+        } else {
+            let words: Vec<u32> = code
+                .chunks_exact(4)
+                .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+                .collect();
+            let relocated = arm::relocate_arm(&words, site, tramp)?;
+            out.extend(
+                relocated
+                    .iter()
+                    .chain(&[arm::ARM_LDR_PC, resume])
+                    .flat_map(|w| w.to_le_bytes()),
+            );
+        }
+        if out.len() > TRAMP_SIZE {
+            return Err(Error::EncodeFailed);
+        }
+        Ok(out)
+    }
+}
+
+impl Backend for UnixArm {
+    unsafe fn code_patch(
+        &self,
+        address: *mut c_void,
+        buffer: *const u8,
+        size: usize,
+    ) -> Result<PatchAtomicity> {
+        platform::unix::code_patch(address, buffer, size)
+    }
+    unsafe fn restore_patch(&self, address: *mut c_void, original: &[u8]) -> Result<()> {
+        platform::unix::restore_patch(address, original, original.len())
+    }
+    fn code_address(&self, address: *mut c_void) -> *mut c_void {
+        (address as usize & !1) as *mut c_void
+    }
+    unsafe fn hook_build(
+        &self,
+        address: *mut c_void,
+        fake_func: *mut c_void,
+        options: &HookOptions,
+        alloc: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild> {
+        // Only the absolute `ldr pc` jump reaches the detour from anywhere.
+        if !matches!(options.patch_style, PatchStyle::Auto | PatchStyle::Absolute)
+            || options.mode == HookMode::Breakpoint
+        {
+            return Err(Error::UnsupportedPlatform);
+        }
+        let thumb = arm::is_thumb(address as usize);
+        let site = self.code_address(address) as u32;
+        let mut patch = if thumb {
+            arm::thumb_abs_jump(site, fake_func as u32)
+        } else {
+            [arm::ARM_LDR_PC, fake_func as u32]
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect()
+        };
+        let bytes = core::slice::from_raw_parts(site as *const u8, 32);
+        let stolen = arm::steal_len(bytes, thumb, patch.len())?;
+
+        let tramp = alloc.alloc(TRAMP_SIZE)?;
+        let code = match Self::trampoline_code(&bytes[..stolen], thumb, site, tramp as u32) {
+            Ok(code) => code,
+            Err(e) => {
+                let _ = alloc.free(tramp, TRAMP_SIZE);
+                return Err(e);
+            }
+        };
+        ptr::copy_nonoverlapping(code.as_ptr(), tramp as *mut u8, code.len());
+        platform::unix::flush_icache(tramp, code.len());
+
+        // Thumb may steal past the jump to finish an instruction or IT block.
+        while patch.len() < stolen {
+            patch.extend(arm::THUMB_NOP.to_le_bytes());
+        }
+        Ok(HookBuild {
+            trampoline: tramp,
+            trampoline_size: TRAMP_SIZE,
+            original: bytes[..stolen].to_vec(),
+            patch,
+            breakpoint: false,
+            trap_offset: 0,
+            style: PatchStyle::Absolute,
+            ibt: false,
+        })
+    }
+    unsafe fn symbol_resolver(
+        &self,
+        image_name: *const c_char,
+        symbol_name: *const c_char,
+        load: bool,
+    ) -> *mut c_void {
+        platform::unix::symbol_resolver(image_name, symbol_name, load)
+    }
+}
@@ -0,0 +1,251 @@
+use super::{Backend, HookBuild};
+use crate::allocator::ExecutableAllocator;
+use crate::arch::riscv64;
+use crate::error::{Error, Result};
+use crate::options::{HookMode, HookOptions, PatchAtomicity, PatchStyle};
+use crate::platform;
+use core::ffi::{c_char, c_void};
+use core::ptr;
+
+pub(crate) static BACKEND: UnixRiscv64 = UnixRiscv64;
+pub(crate) struct UnixRiscv64;
+
+/// A trampoline block: relocated code, the jump back, and optionally a relay to the detour.
+struct Trampoline {
+    base: *mut c_void,
+    size: usize,
+    relay: u64,
+}
+
+impl UnixRiscv64 {
+    // Longest absolute jump (a literal load and `jr`, with alignment padding).
+    const JUMP_SIZE: usize = 26;
+    // Reach of `auipc`+`jalr`, with a margin for the block's own size.
+    const NEAR_RANGE: usize = 0x7FFF_0000;
+
+    /// Whole instructions covering at least `len` bytes at `address`.
+    unsafe fn steal(address: *mut c_void, len: usize) -> Result<Vec<u8>> {
+        let mut code = Vec::new();
+        let mut last = 0;
+        let mut targets = Vec::new();
+        while code.len() < len {
+            let at = (address as *const u8).add(code.len());
+            let half = u16::from_le_bytes([*at, *at.add(1)]);
+            let size = riscv64::insn_len(half).ok_or(Error::DecodeFailed)?;
+            // The function ends (or jumps away) before the patch does.
+            if !code.is_empty() && riscv64::ends_flow(last) {
+                return Err(Error::PatchTooSmall);
+            }
+            code.extend_from_slice(core::slice::from_raw_parts(at, size));
+            last = riscv64::read_insn(&code[code.len() - size..])?;
+            targets.extend(riscv64::branch_target(last, at as u64));
+        }
+        // A stolen branch back into the patch would land in the middle of the new jump.
+        let patched = address as u64 + 1..address as u64 + code.len() as u64;
+        if targets.iter().any(|t| patched.contains(t)) {
+            return Err(Error::PatchTooSmall);
+        }
+        Ok(code)
+    }
+
+    /// Copy `code` from `address` into a new trampoline, relocated against the trampoline's own
+    /// address and followed by a jump to `resume` and, with `relay_to`, a relay there for compact
+    /// patches to jump to.
+    ///
+    /// With `near`, the block must lie within that many bytes of `address`; `Ok(None)` means the
+    /// allocator had no such slot. Far placements expand PC-relative instructions, so the size
+    /// comes from a first pass and the block is reallocated if the real placement needs more.
+    unsafe fn build_trampoline(
+        code: &[u8],
+        address: u64,
+        resume: u64,
+        allocator: &dyn ExecutableAllocator,
+        near: Option<usize>,
+        relay_to: Option<u64>,
+    ) -> Result<Option<Trampoline>> {
+        let stubs = Self::JUMP_SIZE * (1 + relay_to.is_some() as usize);
+        let mut size = riscv64::relocate(code, address, address)?.len() + stubs;
+        loop {
+            let tramp = match near {
+                Some(range) => match allocator.alloc_near(size, address as usize, range)? {
+                    Some(p) => p,
+                    None => return Ok(None),
+                },
+                None => allocator.alloc(size)?,
+            };
+            let mut bytes = match riscv64::relocate(code, address, tramp as u64) {
+                Ok(r) => r,
+                Err(e) => {
+                    let _ = allocator.free(tramp, size);
+                    return Err(e);
+                }
+            };
+            let needed = bytes.len() + stubs;
+            if needed > size {
+                let _ = allocator.free(tramp, size);
+                size = needed;
+                continue;
+            }
+            bytes.extend(riscv64::encode_jump(
+                tramp as u64 + bytes.len() as u64,
+                resume,
+                riscv64::T1,
+            ));
+            let relay = tramp as u64 + bytes.len() as u64;
+            if let Some(dest) = relay_to {
+                bytes.extend(riscv64::encode_abs_jump(relay, dest, riscv64::T1));
+            }
+            ptr::copy_nonoverlapping(bytes.as_ptr(), tramp as *mut u8, bytes.len());
+            platform::unix::flush_icache(tramp, bytes.len());
+            return Ok(Some(Trampoline {
+                base: tramp,
+                size,
+                relay,
+            }));
+        }
+    }
+
+    /// Patch with the shortest style `style` allows, or fail with `EncodeFailed` if no relay could
+    /// be placed within reach of a compact one. With `near_first`, an absolute patch still tries a
+    /// trampoline near the target before one anywhere.
+    unsafe fn inline_build(
+        address: *mut c_void,
+        fake_func: *mut c_void,
+        style: PatchStyle,
+        near_first: bool,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild> {
+        let candidates: &[PatchStyle] = match style {
+            PatchStyle::Auto => &[PatchStyle::Relative, PatchStyle::Absolute],
+            PatchStyle::Relative => &[PatchStyle::Relative],
+            PatchStyle::Absolute => &[PatchStyle::Absolute],
+            PatchStyle::Page => return Err(Error::UnsupportedPlatform),
+        };
+        for &style in candidates {
+            if let Some(build) =
+                Self::inline_build_with(address, fake_func, style, near_first, allocator)?
+            {
+                return Ok(build);
+            }
+        }
+        Err(Error::EncodeFailed)
+    }
+
+    unsafe fn inline_build_with(
+        address: *mut c_void,
+        fake_func: *mut c_void,
+        style: PatchStyle,
+        near_first: bool,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<Option<HookBuild>> {
+        let site = address as u64;
+        let (len, near) = match style {
+            PatchStyle::Relative => (8, Some(Self::NEAR_RANGE)),
+            _ => (
+                riscv64::encode_abs_jump(site, fake_func as u64, riscv64::T1).len(),
+                None,
+            ),
+        };
+        let code = Self::steal(address, len)?;
+        let resume = site + code.len() as u64;
+        let relay_to = near.map(|_| fake_func as u64);
+        let mut placement = near.or(near_first.then_some(Self::NEAR_RANGE));
+        let tramp = loop {
+            match Self::build_trampoline(&code, site, resume, allocator, placement, relay_to)? {
+                Some(tramp) => break tramp,
+                // An absolute patch only prefers a near trampoline.
+                None if near.is_none() && placement.is_some() => placement = None,
+                None => return Ok(None),
+            }
+        };
+        let jump = match style {
+            PatchStyle::Relative => riscv64::encode_auipc_jalr(site, tramp.relay, riscv64::T1)
+                .map(|words| words.iter().flat_map(|w| w.to_le_bytes()).collect()),
+            _ => Some(riscv64::encode_abs_jump(
+                site,
+                fake_func as u64,
+                riscv64::T1,
+            )),
+        };
+        let Some(mut patch) = jump else {
+            // The allocator handed out a block outside the range it was asked for.
+            let _ = allocator.free(tramp.base, tramp.size);
+            return Ok(None);
+        };
+        // Pad over the rest of the last stolen instruction.
+        while patch.len() + 4 <= code.len() {
+            patch.extend(riscv64::OP_NOP.to_le_bytes());
+        }
+        if patch.len() < code.len() {
+            patch.extend(riscv64::OP_C_NOP.to_le_bytes());
+        }
+        Ok(Some(HookBuild {
+            trampoline: tramp.base,
+            trampoline_size: tramp.size,
+            original: code,
+            patch,
+            breakpoint: false,
+            trap_offset: 0,
+            style,
+            ibt: false,
+        }))
+    }
+}
+
+impl Backend for UnixRiscv64 {
+    unsafe fn code_patch(
+        &self,
+        address: *mut c_void,
+        buffer: *const u8,
+        size: usize,
+    ) -> Result<PatchAtomicity> {
+        platform::unix::code_patch(address, buffer, size)
+    }
+    unsafe fn restore_patch(&self, address: *mut c_void, original: &[u8]) -> Result<()> {
+        platform::unix::restore_patch(address, original, original.len())
+    }
+    unsafe fn hook_build(
+        &self,
+        address: *mut c_void,
+        fake_func: *mut c_void,
+        options: &HookOptions,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild> {
+        match options.mode {
+            HookMode::Breakpoint => Err(Error::UnsupportedPlatform),
+            HookMode::Inline | HookMode::Auto => Self::inline_build(
+                address,
+                fake_func,
+                options.patch_style,
+                options.near_first(),
+                allocator,
+            ),
+        }
+    }
+    unsafe fn symbol_resolver(
+        &self,
+        image_name: *const c_char,
+        symbol_name: *const c_char,
+        load: bool,
+    ) -> *mut c_void {
+        platform::unix::symbol_resolver(image_name, symbol_name, load)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branches_into_the_patch_are_refused() {
+        // beqz a0, entry+8; nop; nop; ret
+        let code: [u32; 4] = [0x0005_0463, riscv64::OP_NOP, riscv64::OP_NOP, 0x0000_8067];
+        let address = code.as_ptr() as *mut c_void;
+        assert!(matches!(
+            unsafe { UnixRiscv64::steal(address, 12) },
+            Err(Error::PatchTooSmall)
+        ));
+        // The branch target is past an 8-byte patch.
+        assert!(unsafe { UnixRiscv64::steal(address, 8) }.is_ok());
+    }
+}
@@ -0,0 +1,73 @@
+use super::{Backend, HookBuild};
+use crate::allocator::ExecutableAllocator;
+use crate::arch::x86::{TRAMP_SIZE, rel_jmp, relocate, rewrite_pc_thunks, steal};
+use crate::error::{Error, Result};
+use crate::options::{HookMode, HookOptions, PatchAtomicity, PatchStyle};
+use crate::platform;
+use core::ffi::{c_char, c_void};
+use core::ptr;
+
+pub(crate) static BACKEND: UnixX86 = UnixX86;
+pub(crate) struct UnixX86;
+
+impl Backend for UnixX86 {
+    unsafe fn code_patch(
+        &self,
+        address: *mut c_void,
+        buffer: *const u8,
+        size: usize,
+    ) -> Result<PatchAtomicity> {
+        platform::unix::code_patch(address, buffer, size)
+    }
+    unsafe fn restore_patch(&self, address: *mut c_void, original: &[u8]) -> Result<()> {
+        platform::unix::restore_patch(address, original, original.len())
+    }
+    unsafe fn hook_build(
+        &self,
+        address: *mut c_void,
+        fake_func: *mut c_void,
+        options: &HookOptions,
+        alloc: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild> {
+        if options.patch_style == PatchStyle::Page || options.mode == HookMode::Breakpoint {
+            return Err(Error::UnsupportedPlatform);
+        }
+        let target_ip = address as u32;
+        let bytes = core::slice::from_raw_parts(address as *const u8, 32);
+        let (mut insns, stolen_len) = steal(bytes, target_ip)?;
+        rewrite_pc_thunks(&mut insns, |a| *(a as *const [u8; 4]))?;
+
+        let tramp = alloc.alloc(TRAMP_SIZE)?;
+        let code = match relocate(&insns, target_ip + stolen_len as u32, tramp as u32) {
+            Ok(code) => code,
+            Err(e) => {
+                let _ = alloc.free(tramp, TRAMP_SIZE);
+                return Err(e);
+            }
+        };
+        ptr::copy_nonoverlapping(code.as_ptr(), tramp as *mut u8, code.len());
+        platform::unix::flush_icache(tramp, code.len());
+
+        // `Absolute` and `Auto` get the same `jmp rel32`: it reaches the detour from anywhere.
+        let mut patch = rel_jmp(target_ip, fake_func as u32).to_vec();
+        patch.resize(stolen_len, 0x90);
+        Ok(HookBuild {
+            trampoline: tramp,
+            trampoline_size: TRAMP_SIZE,
+            original: bytes[..stolen_len].to_vec(),
+            patch,
+            breakpoint: false,
+            trap_offset: 0,
+            style: PatchStyle::Relative,
+            ibt: false,
+        })
+    }
+    unsafe fn symbol_resolver(
+        &self,
+        image_name: *const c_char,
+        symbol_name: *const c_char,
+        load: bool,
+    ) -> *mut c_void {
+        platform::unix::symbol_resolver(image_name, symbol_name, load)
+    }
+}
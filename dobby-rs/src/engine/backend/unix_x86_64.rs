@@ -1,37 +1,31 @@
 use super::x86_64_common::{self, X64HookPlatform};
 use super::{Backend, HookBuild};
+use crate::allocator::ExecutableAllocator;
 use crate::error::Result;
+use crate::options::{HookOptions, PatchAtomicity};
 use crate::platform;
 use core::ffi::{c_char, c_void};
-use core::ptr;
 
 pub(crate) static BACKEND: UnixX86_64 = UnixX86_64;
 pub(crate) struct UnixX86_64;
 struct PlatformOps;
 
 impl X64HookPlatform for PlatformOps {
-    unsafe fn alloc_executable(size: usize) -> Result<*mut c_void> {
-        platform::unix::alloc_executable(size)
-    }
-    unsafe fn free_executable(ptr: *mut c_void, size: usize) -> Result<()> {
-        platform::unix::free_executable(ptr, size)
-    }
+    const BREAKPOINTS: bool = platform::unix::TRAP_HOOKS;
     unsafe fn flush_icache(address: *mut c_void, size: usize) -> Result<()> {
         platform::unix::flush_icache(address, size);
         Ok(())
     }
-    unsafe fn write_detour_with_nops(
-        address: *mut c_void,
-        stolen_len: usize,
-        detour: &[u8; 14],
-    ) -> Result<()> {
-        platform::unix::with_rwx(address, stolen_len, || {
-            ptr::copy_nonoverlapping(detour.as_ptr(), address as *mut u8, detour.len());
-            for i in detour.len()..stolen_len {
-                *(address as *mut u8).add(i) = 0x90;
-            }
-            Ok(())
-        })
+    unsafe fn ibt_marked(address: *mut c_void) -> bool {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            platform::unix::cet::ibt_marked(address)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let _ = address;
+            false
+        }
     }
 }
 
@@ -41,28 +35,27 @@ impl Backend for UnixX86_64 {
         address: *mut c_void,
         buffer: *const u8,
         size: usize,
-    ) -> Result<()> {
+    ) -> Result<PatchAtomicity> {
         platform::unix::code_patch(address, buffer, size)
     }
-    unsafe fn hook_build(&self, address: *mut c_void, fake_func: *mut c_void) -> Result<HookBuild> {
-        x86_64_common::hook_build::<PlatformOps>(address, fake_func)
+    unsafe fn restore_patch(&self, address: *mut c_void, original: &[u8]) -> Result<()> {
+        platform::unix::restore_patch(address, original, original.len())
     }
-    unsafe fn hook_destroy(
+    unsafe fn hook_build(
         &self,
         address: *mut c_void,
-        original: &[u8],
-        patch_len: usize,
-        trampoline: *mut c_void,
-        trampoline_size: usize,
-    ) -> Result<()> {
-        platform::unix::restore_patch(address, original, patch_len)?;
-        platform::unix::free_executable(trampoline, trampoline_size)
+        fake_func: *mut c_void,
+        options: &HookOptions,
+        allocator: &dyn ExecutableAllocator,
+    ) -> Result<HookBuild> {
+        x86_64_common::hook_build::<PlatformOps>(address, fake_func, options, allocator)
     }
     unsafe fn symbol_resolver(
         &self,
         image_name: *const c_char,
         symbol_name: *const c_char,
+        load: bool,
     ) -> *mut c_void {
-        platform::unix::symbol_resolver(image_name, symbol_name)
+        platform::unix::symbol_resolver(image_name, symbol_name, load)
     }
 }
@@ -1,48 +1,173 @@
 use super::HookBuild;
+use crate::allocator::ExecutableAllocator;
 use crate::error::{Error, Result};
-use crate::options;
+use crate::options::{HookMode, HookOptions, PatchStyle};
 use core::ffi::c_void;
 use core::ptr;
 use iced_x86::{
-    BlockEncoder, BlockEncoderOptions, Decoder, DecoderOptions, Instruction, InstructionBlock,
+    BlockEncoder, BlockEncoderOptions, Code, Decoder, DecoderOptions, FlowControl, Instruction,
+    InstructionBlock,
 };
 
 pub(crate) trait X64HookPlatform {
-    unsafe fn alloc_executable(size: usize) -> Result<*mut c_void>;
-    unsafe fn alloc_executable_near(
-        size: usize,
-        _pos: usize,
-        _range: usize,
-    ) -> Result<*mut c_void> {
-        Self::alloc_executable(size)
-    }
-    unsafe fn free_executable(ptr: *mut c_void, size: usize) -> Result<()>;
+    /// Whether the platform can redirect `int3` traps (see [`HookMode::Breakpoint`]).
+    const BREAKPOINTS: bool;
     unsafe fn flush_icache(address: *mut c_void, size: usize) -> Result<()>;
-    unsafe fn write_detour_with_nops(
-        address: *mut c_void,
-        stolen_len: usize,
-        detour: &[u8; 14],
-    ) -> Result<()>;
+    /// Whether the image containing `address` is marked for CET indirect branch tracking.
+    unsafe fn ibt_marked(address: *mut c_void) -> bool;
 }
 
-fn abs_jmp(dest: u64) -> [u8; 14] {
-    let mut b = [0u8; 14];
+const ABS_JMP_LEN: usize = 14;
+const ENDBR64: [u8; 4] = [0xF3, 0x0F, 0x1E, 0xFA];
+// `endbr64` and an absolute jump to the detour.
+const RELAY_LEN: usize = ENDBR64.len() + ABS_JMP_LEN;
+const REL_JMP_LEN: usize = 5;
+const TRAMP_SIZE: usize = 256;
+const REL32_RANGE: usize = 0x7fff_ffff;
+
+fn abs_jmp(dest: u64) -> [u8; ABS_JMP_LEN] {
+    let mut b = [0u8; ABS_JMP_LEN];
     b[0] = 0xFF;
     b[1] = 0x25;
     b[6..14].copy_from_slice(&dest.to_le_bytes());
     b
 }
 
+fn rel_jmp(from: u64, dest: u64) -> Option<[u8; REL_JMP_LEN]> {
+    let disp = dest.wrapping_sub(from + REL_JMP_LEN as u64) as i64;
+    let disp = i32::try_from(disp).ok()?;
+    let mut b = [0u8; REL_JMP_LEN];
+    b[0] = 0xE9;
+    b[1..5].copy_from_slice(&disp.to_le_bytes());
+    Some(b)
+}
+
+// The whole trampoline block has to be reachable, since the relay sits behind the relocated code.
+fn block_in_rel32(address: usize, block: usize) -> bool {
+    block.abs_diff(address) < REL32_RANGE - TRAMP_SIZE
+}
+
+/// How the target is redirected.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Patch {
+    Inline(PatchStyle),
+    Breakpoint,
+}
+
 pub(crate) unsafe fn hook_build<P: X64HookPlatform>(
     address: *mut c_void,
     fake_func: *mut c_void,
+    options: &HookOptions,
+    alloc: &dyn ExecutableAllocator,
 ) -> Result<HookBuild> {
-    let target_ip = address as u64;
+    if options.patch_style == PatchStyle::Page {
+        return Err(Error::UnsupportedPlatform);
+    }
+    let inline = Patch::Inline(options.patch_style);
+    match options.mode {
+        HookMode::Inline => build::<P>(address, fake_func, options, alloc, inline),
+        HookMode::Breakpoint if !P::BREAKPOINTS => Err(Error::UnsupportedPlatform),
+        HookMode::Breakpoint => build::<P>(address, fake_func, options, alloc, Patch::Breakpoint),
+        // Only a prologue that can't take an inline patch falls back; a style that can't be
+        // placed is reported as such.
+        HookMode::Auto => match build::<P>(address, fake_func, options, alloc, inline) {
+            Err(e @ (Error::PatchTooSmall | Error::RelocationFailed)) if P::BREAKPOINTS => {
+                build::<P>(address, fake_func, options, alloc, Patch::Breakpoint).map_err(|_| e)
+            }
+            r => r,
+        },
+    }
+}
+
+unsafe fn build<P: X64HookPlatform>(
+    address: *mut c_void,
+    fake_func: *mut c_void,
+    options: &HookOptions,
+    alloc: &dyn ExecutableAllocator,
+    kind: Patch,
+) -> Result<HookBuild> {
+    let pos = address as usize;
+    let style = match kind {
+        Patch::Inline(style) => style,
+        Patch::Breakpoint => PatchStyle::Absolute,
+    };
+
+    // A relative patch needs the relay within rel32 reach, so it always allocates near. So does a
+    // target under IBT: from a far trampoline the encoder turns the jump back (and relocated
+    // branches) into `jmp [rip+x]`, an indirect jump into the middle of the function that faults.
+    // Otherwise allocate anywhere first (or near, when requested), and if relocation/encoding fails
+    // (common with RIP-relative instructions when the trampoline is too far away) retry with a near
+    // block.
+    let ibt = P::ibt_marked(address);
+    let near_only = style == PatchStyle::Relative || ibt;
+    let tramp = if options.near_first() || near_only {
+        match alloc.alloc_near(TRAMP_SIZE, pos, REL32_RANGE)? {
+            Some(p) => p,
+            None if near_only => return Err(Error::EncodeFailed),
+            None => alloc.alloc(TRAMP_SIZE)?,
+        }
+    } else {
+        alloc.alloc(TRAMP_SIZE)?
+    };
+    match build_at::<P>(address, fake_func, tramp, kind, ibt) {
+        Ok(build) => return Ok(build),
+        Err(Error::EncodeFailed) if !block_in_rel32(pos, tramp as usize) => {
+            let _ = alloc.free(tramp, TRAMP_SIZE);
+        }
+        Err(e) => {
+            let _ = alloc.free(tramp, TRAMP_SIZE);
+            return Err(e);
+        }
+    }
+
+    let tramp = alloc
+        .alloc_near(TRAMP_SIZE, pos, REL32_RANGE)?
+        .ok_or(Error::EncodeFailed)?;
+    build_at::<P>(address, fake_func, tramp, kind, ibt).inspect_err(|_| {
+        let _ = alloc.free(tramp, TRAMP_SIZE);
+    })
+}
+
+unsafe fn build_at<P: X64HookPlatform>(
+    address: *mut c_void,
+    fake_func: *mut c_void,
+    tramp: *mut c_void,
+    kind: Patch,
+    ibt: bool,
+) -> Result<HookBuild> {
+    let near = block_in_rel32(address as usize, tramp as usize);
+    if ibt && !near {
+        return Err(Error::EncodeFailed);
+    }
     let bytes = core::slice::from_raw_parts(address as *const u8, 64);
-    let mut decoder = Decoder::with_ip(64, bytes, target_ip, DecoderOptions::NONE);
-    let mut insns: Vec<Instruction> = Vec::new();
+    // An `endbr64` at the entry stays in place and the patch goes right after it, so indirect
+    // calls still land on one under IBT.
+    let pad = if bytes.starts_with(&ENDBR64) {
+        ENDBR64.len()
+    } else {
+        0
+    };
+    let target_ip = address as u64 + pad as u64;
+    let relative = match kind {
+        Patch::Breakpoint | Patch::Inline(PatchStyle::Absolute) => false,
+        Patch::Inline(style) => {
+            if style == PatchStyle::Relative && !near {
+                return Err(Error::EncodeFailed);
+            }
+            near
+        }
+    };
+    let min_len = match kind {
+        Patch::Breakpoint => 1,
+        _ if relative => REL_JMP_LEN,
+        _ => ABS_JMP_LEN,
+    };
+
+    let mut decoder = Decoder::with_ip(64, &bytes[pad..], target_ip, DecoderOptions::NONE);
+    // The trampoline is reached by indirect calls through the original-function pointer.
+    let mut insns = vec![Instruction::with(Code::Endbr64)];
     let mut stolen_len = 0usize;
-    while stolen_len < 14 {
+    while stolen_len < min_len {
         let i = decoder.decode();
         if i.is_invalid() {
             return Err(Error::DecodeFailed);
@@ -50,62 +175,198 @@ pub(crate) unsafe fn hook_build<P: X64HookPlatform>(
         stolen_len += i.len();
         insns.push(i);
     }
-    let original = core::slice::from_raw_parts(address as *const u8, stolen_len).to_vec();
-    let tramp_size = 256usize;
-
-    // Try a normal trampoline allocation first. If relocation/encoding fails (common with RIP-relative
-    // instructions when the trampoline is too far away), retry with a near allocation.
-    let mut tramp = P::alloc_executable(tramp_size)?;
-    let code = match BlockEncoder::encode(
+    if kind != Patch::Breakpoint && !fits_inline(&insns[1..], target_ip, stolen_len) {
+        return Err(Error::PatchTooSmall);
+    }
+    // A trap only replaces the first byte; inline patches cover whole instructions.
+    let patch_len = if kind == Patch::Breakpoint {
+        1
+    } else {
+        stolen_len
+    };
+    let original = bytes[..pad + patch_len].to_vec();
+    // Jump back as part of the block: when branches get rewritten to `jmp/call [rip+x]`, the encoder
+    // appends their pointer slots after the last instruction, so nothing may follow the block.
+    insns.push(
+        Instruction::with_branch(Code::Jmp_rel32_64, target_ip + stolen_len as u64)
+            .map_err(|_| Error::EncodeFailed)?,
+    );
+    let code = BlockEncoder::encode(
         64,
         InstructionBlock::new(&insns, tramp as u64),
         BlockEncoderOptions::NONE,
-    ) {
-        Ok(encoded) => encoded.code_buffer,
-        Err(_) => {
-            let _ = P::free_executable(tramp, tramp_size);
-
-            let range = 0x7fff_ffffusize;
-            tramp = if let Some(cb) = options::alloc_near_code_callback() {
-                let p = cb(tramp_size as u32, address as usize, range);
-                if p == 0 {
-                    P::alloc_executable_near(tramp_size, address as usize, range)?
-                } else {
-                    p as *mut c_void
-                }
-            } else if options::near_trampoline_enabled() {
-                P::alloc_executable_near(tramp_size, address as usize, range)?
-            } else {
-                // Even if near trampoline wasn't explicitly enabled, it's worth retrying near since
-                // EncodeFailed usually means "trampoline too far".
-                P::alloc_executable_near(tramp_size, address as usize, range)?
-            };
-
-            BlockEncoder::encode(
-                64,
-                InstructionBlock::new(&insns, tramp as u64),
-                BlockEncoderOptions::NONE,
-            )
-            .map_err(|_| Error::EncodeFailed)?
-            .code_buffer
-        }
-    };
+    )
+    .map_err(|_| Error::EncodeFailed)?
+    .code_buffer;
 
-    if code.len() + 14 > tramp_size {
-        let _ = P::free_executable(tramp, tramp_size);
+    // Layout: [relocated code + jmp back][relay to the detour, for relative patches]
+    let relay_len = if relative { RELAY_LEN } else { 0 };
+    if code.len() + relay_len > TRAMP_SIZE {
         return Err(Error::EncodeFailed);
     }
     ptr::copy_nonoverlapping(code.as_ptr(), tramp as *mut u8, code.len());
-    let jmp_back = abs_jmp(target_ip + stolen_len as u64);
-    ptr::copy_nonoverlapping(jmp_back.as_ptr(), (tramp as *mut u8).add(code.len()), 14);
-    P::flush_icache(tramp, code.len() + 14)?;
-    let detour = abs_jmp(fake_func as u64);
-    P::write_detour_with_nops(address, stolen_len, &detour)?;
-    P::flush_icache(address, stolen_len)?;
+    let mut jump = if kind == Patch::Breakpoint {
+        vec![0xCC]
+    } else if relative {
+        let relay = (tramp as *mut u8).add(code.len());
+        let mut stub = ENDBR64.to_vec();
+        stub.extend(abs_jmp(fake_func as u64));
+        ptr::copy_nonoverlapping(stub.as_ptr(), relay, stub.len());
+        rel_jmp(target_ip, relay as u64)
+            .ok_or(Error::EncodeFailed)?
+            .to_vec()
+    } else {
+        abs_jmp(fake_func as u64).to_vec()
+    };
+    P::flush_icache(tramp, code.len() + relay_len)?;
+    jump.resize(patch_len, 0x90);
+    let mut patch = original[..pad].to_vec();
+    patch.extend(jump);
     Ok(HookBuild {
         trampoline: tramp,
-        trampoline_size: tramp_size,
+        trampoline_size: TRAMP_SIZE,
         original,
-        patch_len: stolen_len,
+        patch,
+        breakpoint: kind == Patch::Breakpoint,
+        trap_offset: pad,
+        style: if relative {
+            PatchStyle::Relative
+        } else {
+            PatchStyle::Absolute
+        },
+        ibt,
     })
 }
+
+// The patch must not run past the end of the function or cover a branch target: no stolen
+// instruction except the last may end control flow, and no stolen branch may land inside the
+// stolen range.
+fn fits_inline(insns: &[Instruction], start: u64, len: usize) -> bool {
+    let end = start + len as u64;
+    let body = &insns[..insns.len() - 1];
+    let terminal = body.iter().any(|i| {
+        matches!(
+            i.flow_control(),
+            FlowControl::Return
+                | FlowControl::UnconditionalBranch
+                | FlowControl::IndirectBranch
+                | FlowControl::Interrupt
+                | FlowControl::Exception
+        )
+    });
+    let internal = insns.iter().any(|i| {
+        matches!(
+            i.flow_control(),
+            FlowControl::UnconditionalBranch | FlowControl::ConditionalBranch
+        ) && (start + 1..end).contains(&i.near_branch_target())
+    });
+    !terminal && !internal
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+    use crate::allocator::SystemAllocator;
+    use crate::platform::unix;
+
+    struct Marked<const IBT: bool>;
+
+    impl<const IBT: bool> X64HookPlatform for Marked<IBT> {
+        const BREAKPOINTS: bool = false;
+        unsafe fn flush_icache(_address: *mut c_void, _size: usize) -> Result<()> {
+            Ok(())
+        }
+        unsafe fn ibt_marked(_address: *mut c_void) -> bool {
+            IBT
+        }
+    }
+
+    // Hands out a block far from everything for `alloc`, and the system's near block otherwise.
+    struct FarFirst(*mut c_void);
+
+    unsafe impl Send for FarFirst {}
+    unsafe impl Sync for FarFirst {}
+
+    impl ExecutableAllocator for FarFirst {
+        fn alloc_near(&self, size: usize, pos: usize, range: usize) -> Result<Option<*mut c_void>> {
+            SystemAllocator.alloc_near(size, pos, range)
+        }
+        fn alloc(&self, _size: usize) -> Result<*mut c_void> {
+            Ok(self.0)
+        }
+        unsafe fn free(&self, ptr: *mut c_void, size: usize) -> Result<()> {
+            if ptr == self.0 {
+                return Ok(());
+            }
+            SystemAllocator.free(ptr, size)
+        }
+    }
+
+    // endbr64; mov rax, rdi; add rax, 1; add rax, 2; add rax, 3; ret
+    const FUNCTION: [u8; 20] = [
+        0xF3, 0x0F, 0x1E, 0xFA, 0x48, 0x89, 0xF8, 0x48, 0x83, 0xC0, 0x01, 0x48, 0x83, 0xC0, 0x02,
+        0x48, 0x83, 0xC0, 0x03, 0xC3,
+    ];
+
+    // The relocated code is straight-line, so its first jump is the one back.
+    fn jump_back(build: &HookBuild) -> Instruction {
+        let code =
+            unsafe { core::slice::from_raw_parts(build.trampoline as *const u8, TRAMP_SIZE) };
+        Decoder::with_ip(64, code, build.trampoline as u64, DecoderOptions::NONE)
+            .into_iter()
+            .find(|i| {
+                matches!(
+                    i.flow_control(),
+                    FlowControl::UnconditionalBranch | FlowControl::IndirectBranch
+                )
+            })
+            .expect("jump back")
+    }
+
+    #[test]
+    fn marked_targets_jump_back_directly() {
+        unsafe {
+            let function = unix::alloc_executable(4096).expect("alloc");
+            ptr::copy_nonoverlapping(FUNCTION.as_ptr(), function as *mut u8, FUNCTION.len());
+            // Some free address beyond rel32 reach.
+            let far = (1..64)
+                .map(|i| (function as usize).wrapping_add(i << 33))
+                .find_map(|hint| {
+                    let p = libc::mmap(
+                        hint as *mut c_void,
+                        TRAMP_SIZE,
+                        libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                        libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_FIXED_NOREPLACE,
+                        -1,
+                        0,
+                    );
+                    (p != libc::MAP_FAILED).then_some(p)
+                })
+                .expect("far block");
+            assert!(!block_in_rel32(function as usize, far as usize));
+            let alloc = FarFirst(far);
+            let detour = 0x1000 as *mut c_void;
+            let options = HookOptions::default();
+
+            let build =
+                hook_build::<Marked<false>>(function, detour, &options, &alloc).expect("unmarked");
+            assert_eq!(build.trampoline, far);
+            assert_eq!(jump_back(&build).code(), Code::Jmp_rm64);
+
+            let build =
+                hook_build::<Marked<true>>(function, detour, &options, &alloc).expect("marked");
+            assert!(build.ibt);
+            assert!(block_in_rel32(function as usize, build.trampoline as usize));
+            let jmp = jump_back(&build);
+            assert_eq!(jmp.code(), Code::Jmp_rel32_64);
+            // Back to the `ret`.
+            assert_eq!(
+                jmp.near_branch_target(),
+                function as u64 + FUNCTION.len() as u64 - 1
+            );
+            SystemAllocator.free(build.trampoline, TRAMP_SIZE).unwrap();
+            libc::munmap(far, TRAMP_SIZE);
+            unix::free_executable(function, 4096).expect("free");
+        }
+    }
+}
@@ -1,12 +1,523 @@
+//! Entry and exit instrumentation (x86_64 Unix).
+//!
+//! Each instrumented function is hooked with a small per-site stub that loads the site record into
+//! `r11` and jumps to `entry_common`. That saves the registers into a [`RegisterContext`], runs
+//! the pre-handler and, when there is a post-handler, swaps the return address for `exit_thunk`
+//! and remembers the real one on a per-thread shadow stack. `exit_thunk` pops it again, runs the
+//! post-handler and returns to the caller.
+//!
+//! Frames skipped by `longjmp` never reach the thunk; their shadow entries are recognised by their
+//! stack slot and pruned on the next entry or exit of the same thread. An exception unwinding past
+//! an instrumented frame finds `exit_thunk` as its return address; the thunk's unwind info has a
+//! personality routine that pops the shadow entry and writes the real return address back into
+//! the slot, so the unwinder carries on into the caller. Backtraces, which don't run personality
+//! routines, stop at the thunk.
+
 use crate::error::{Error, Result};
 use core::ffi::c_void;
 
+/// Called with the instrumented address and a pointer to the thread's [`RegisterContext`].
+pub type InstrumentHandler = unsafe fn(address: *mut c_void, context: *mut c_void);
+
+/// Registers at an instrumentation point (x86_64).
+///
+/// Handlers get a pointer to this as `context`. Changes to anything but `rsp` are written back:
+/// a pre-handler can rewrite arguments, a post-handler the return value (`rax`, `rdx`, `xmm0`,
+/// `xmm1`). `rsp` points at the return address on entry and just above it on exit.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// Low 128 bits of `xmm0`..`xmm7`.
+    pub xmm: [u128; 8],
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+pub(super) fn instrument(
+    address: *mut c_void,
+    pre_handler: Option<InstrumentHandler>,
+    post_handler: Option<InstrumentHandler>,
+) -> Result<()> {
+    if address.is_null() {
+        return Err(Error::NullPointer);
+    }
+    unsafe { x86_64::instrument(address, pre_handler, post_handler) }
+}
+
+#[cfg(not(all(unix, target_arch = "x86_64")))]
 pub(super) fn instrument(
     address: *mut c_void,
-    _pre_handler: unsafe fn(address: *mut c_void, context: *mut c_void),
+    _pre_handler: Option<InstrumentHandler>,
+    _post_handler: Option<InstrumentHandler>,
 ) -> Result<()> {
     if address.is_null() {
         return Err(Error::NullPointer);
     }
     Err(Error::UnsupportedPlatform)
 }
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+mod x86_64 {
+    use super::{InstrumentHandler, RegisterContext};
+    use crate::allocator::{ExecutableAllocator, SystemAllocator};
+    use crate::engine::manager;
+    use crate::error::{Error, Result};
+    use crate::options::HookOptions;
+    use core::cell::{Cell, RefCell};
+    use core::ffi::c_void;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // mov r11, imm64; jmp [rip]; dq entry_common
+    const STUB_LEN: usize = 24;
+    // Placed just before the thunk's entry, so its unwind info can tell the thunk from a real
+    // return address.
+    const EXIT_MAGIC: u64 = 0x5d0b_b7e8_17c3_a95f;
+    const URC_CONTINUE_UNWIND: i32 = 8;
+
+    unsafe extern "C" {
+        fn _Unwind_GetCFA(context: *mut c_void) -> usize;
+    }
+
+    // Sites and their stubs are never freed: a thread may still be running through them after the
+    // hook is destroyed. Instrumenting the same address again reuses both.
+    struct Site {
+        address: usize,
+        pre: AtomicUsize,
+        post: AtomicUsize,
+        trampoline: AtomicUsize,
+        stub: usize,
+    }
+
+    struct Frame {
+        // Stack slot that held the return address; identifies the frame.
+        slot: usize,
+        ret: usize,
+        site: &'static Site,
+    }
+
+    static SITES: Mutex<Option<HashMap<usize, &'static Site>>> = Mutex::new(None);
+
+    thread_local! {
+        static SHADOW: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+        // Set while a handler runs, so functions it calls aren't instrumented recursively.
+        static BUSY: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub(super) unsafe fn instrument(
+        address: *mut c_void,
+        pre: Option<InstrumentHandler>,
+        post: Option<InstrumentHandler>,
+    ) -> Result<()> {
+        let mut sites = SITES.lock().unwrap_or_else(|e| e.into_inner());
+        let sites = sites.get_or_insert_with(HashMap::new);
+        if manager::trampoline(address).is_some() {
+            return Err(Error::AlreadyHooked);
+        }
+        let site = match sites.get(&(address as usize)) {
+            Some(site) => *site,
+            None => {
+                let site = new_site(address as usize)?;
+                sites.insert(address as usize, site);
+                site
+            }
+        };
+        site.trampoline.store(0, Ordering::Release);
+        site.pre
+            .store(pre.map_or(0, |f| f as usize), Ordering::Release);
+        site.post
+            .store(post.map_or(0, |f| f as usize), Ordering::Release);
+        let trampoline = manager::hook(address, site.stub as *mut c_void, &HookOptions::default())?;
+        site.trampoline
+            .store(trampoline as usize, Ordering::Release);
+        Ok(())
+    }
+
+    fn new_site(address: usize) -> Result<&'static Site> {
+        let stub = SystemAllocator.alloc(STUB_LEN)? as usize;
+        let site: &'static Site = Box::leak(Box::new(Site {
+            address,
+            pre: AtomicUsize::new(0),
+            post: AtomicUsize::new(0),
+            trampoline: AtomicUsize::new(0),
+            stub,
+        }));
+        let mut code = [0u8; STUB_LEN];
+        code[..2].copy_from_slice(&[0x49, 0xBB]);
+        code[2..10].copy_from_slice(&(site as *const Site as u64).to_le_bytes());
+        code[10..12].copy_from_slice(&[0xFF, 0x25]);
+        code[16..24].copy_from_slice(&(entry_common as *const () as u64).to_le_bytes());
+        unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), stub as *mut u8, STUB_LEN) };
+        Ok(site)
+    }
+
+    fn exit_entry() -> usize {
+        exit_thunk as *const () as usize + 8
+    }
+
+    // The frame whose return address was at `slot`; deeper frames left on the way are dropped.
+    fn pop_frame(slot: usize) -> Option<Frame> {
+        SHADOW
+            .try_with(|shadow| {
+                let mut shadow = shadow.try_borrow_mut().ok()?;
+                while let Some(frame) = shadow.pop() {
+                    if frame.slot == slot {
+                        return Some(frame);
+                    }
+                }
+                None
+            })
+            .ok()
+            .flatten()
+    }
+
+    unsafe fn call(handler: usize, address: usize, ctx: *mut RegisterContext) {
+        let handler: InstrumentHandler = core::mem::transmute(handler);
+        let busy = BUSY.replace(true);
+        handler(address as *mut c_void, ctx as *mut c_void);
+        BUSY.set(busy);
+    }
+
+    unsafe extern "C" fn on_enter(site: &'static Site, ctx: *mut RegisterContext) -> usize {
+        // The stub can be reached before `instrument` has stored the trampoline.
+        let trampoline = loop {
+            match site.trampoline.load(Ordering::Acquire) {
+                0 => core::hint::spin_loop(),
+                t => break t,
+            }
+        };
+        if BUSY.try_with(Cell::get).unwrap_or(true) {
+            return trampoline;
+        }
+        let pre = site.pre.load(Ordering::Acquire);
+        if pre != 0 {
+            call(pre, site.address, ctx);
+        }
+        if site.post.load(Ordering::Acquire) != 0 {
+            let slot = (*ctx).rsp as usize;
+            BUSY.set(true);
+            SHADOW.with_borrow_mut(|shadow| {
+                // Anything at or below our slot belongs to a frame that is already gone.
+                while shadow.last().is_some_and(|f| f.slot <= slot) {
+                    shadow.pop();
+                }
+                shadow.push(Frame {
+                    slot,
+                    ret: *(slot as *const usize),
+                    site,
+                });
+            });
+            BUSY.set(false);
+            *(slot as *mut usize) = exit_entry();
+        }
+        trampoline
+    }
+
+    unsafe extern "C" fn on_exit(ctx: *mut RegisterContext) -> usize {
+        let slot = (*ctx).rsp as usize - 8;
+        let Some(frame) = pop_frame(slot) else {
+            // No way back to the caller.
+            std::process::abort();
+        };
+        // From here on the thunk unwinds like the function had returned normally.
+        *(slot as *mut usize) = frame.ret;
+        let post = frame.site.post.load(Ordering::Acquire);
+        if post != 0 {
+            call(post, frame.site.address, ctx);
+        }
+        frame.ret
+    }
+
+    // Entered from a site stub with `r11` = site and `rsp` at the return address (8 mod 16).
+    #[unsafe(naked)]
+    unsafe extern "C" fn entry_common() {
+        core::arch::naked_asm!(
+            "sub rsp, 264",
+            "mov [rsp], rax",
+            "mov [rsp + 8], rbx",
+            "mov [rsp + 16], rcx",
+            "mov [rsp + 24], rdx",
+            "mov [rsp + 32], rsi",
+            "mov [rsp + 40], rdi",
+            "mov [rsp + 48], rbp",
+            "lea rax, [rsp + 264]",
+            "mov [rsp + 56], rax",
+            "mov [rsp + 64], r8",
+            "mov [rsp + 72], r9",
+            "mov [rsp + 80], r10",
+            "mov [rsp + 88], r11",
+            "mov [rsp + 96], r12",
+            "mov [rsp + 104], r13",
+            "mov [rsp + 112], r14",
+            "mov [rsp + 120], r15",
+            "movdqu [rsp + 128], xmm0",
+            "movdqu [rsp + 144], xmm1",
+            "movdqu [rsp + 160], xmm2",
+            "movdqu [rsp + 176], xmm3",
+            "movdqu [rsp + 192], xmm4",
+            "movdqu [rsp + 208], xmm5",
+            "movdqu [rsp + 224], xmm6",
+            "movdqu [rsp + 240], xmm7",
+            "mov rdi, r11",
+            "mov rsi, rsp",
+            "call {on_enter}",
+            "mov r11, rax",
+            "movdqu xmm0, [rsp + 128]",
+            "movdqu xmm1, [rsp + 144]",
+            "movdqu xmm2, [rsp + 160]",
+            "movdqu xmm3, [rsp + 176]",
+            "movdqu xmm4, [rsp + 192]",
+            "movdqu xmm5, [rsp + 208]",
+            "movdqu xmm6, [rsp + 224]",
+            "movdqu xmm7, [rsp + 240]",
+            "mov rax, [rsp]",
+            "mov rbx, [rsp + 8]",
+            "mov rcx, [rsp + 16]",
+            "mov rdx, [rsp + 24]",
+            "mov rsi, [rsp + 32]",
+            "mov rdi, [rsp + 40]",
+            "mov rbp, [rsp + 48]",
+            "mov r8, [rsp + 64]",
+            "mov r9, [rsp + 72]",
+            "mov r10, [rsp + 80]",
+            "mov r12, [rsp + 96]",
+            "mov r13, [rsp + 104]",
+            "mov r14, [rsp + 112]",
+            "mov r15, [rsp + 120]",
+            "add rsp, 264",
+            "jmp r11",
+            on_enter = sym on_enter,
+        )
+    }
+
+    // Personality of `exit_thunk`: an exception leaving an instrumented frame puts the real
+    // return address back before the unwinder reads it.
+    unsafe extern "C" fn exit_personality(
+        _version: i32,
+        _actions: i32,
+        _class: u64,
+        _exception: *mut c_void,
+        context: *mut c_void,
+    ) -> i32 {
+        let slot = (_Unwind_GetCFA(context) - 8) as *mut usize;
+        // Already done by `on_exit` if the exception came from a post-handler.
+        if *slot == exit_entry()
+            && let Some(frame) = pop_frame(slot as usize)
+        {
+            *slot = frame.ret;
+        }
+        URC_CONTINUE_UNWIND
+    }
+
+    // "Returned to" by an instrumented function, at `exit_entry()` with `rsp` just above the
+    // swapped slot.
+    //
+    // The unwind info describes the thunk as a frame whose return address is in that slot, or 0
+    // (end of stack) while the slot still holds the thunk: DW_CFA_val_expression for `rip` of
+    // `ret * (*(ret - 8) != EXIT_MAGIC)`, with `ret` = `*(CFA - 8)`. The save area stops short
+    // of the slot, so `on_exit` can put the real return address back.
+    #[unsafe(naked)]
+    unsafe extern "C" fn exit_thunk() {
+        core::arch::naked_asm!(
+            ".cfi_startproc",
+            ".cfi_personality 0x1b, {personality}",
+            ".cfi_def_cfa rsp, 0",
+            ".cfi_escape 0x16, 0x10, 18, 0x38, 0x1c, 0x06, 0x12, 0x38, 0x1c, 0x06, 0x0e",
+            ".cfi_escape {m} & 0xff, ({m} >> 8) & 0xff, ({m} >> 16) & 0xff, ({m} >> 24) & 0xff",
+            ".cfi_escape ({m} >> 32) & 0xff, ({m} >> 40) & 0xff, ({m} >> 48) & 0xff, ({m} >> 56) & 0xff",
+            ".cfi_escape 0x2e, 0x1e",
+            // The unwinder looks up `pc - 1`, which lands here.
+            ".quad {m}",
+            "sub rsp, 272",
+            ".cfi_adjust_cfa_offset 272",
+            "mov [rsp], rax",
+            "mov [rsp + 8], rbx",
+            "mov [rsp + 16], rcx",
+            "mov [rsp + 24], rdx",
+            "mov [rsp + 32], rsi",
+            "mov [rsp + 40], rdi",
+            "mov [rsp + 48], rbp",
+            "lea rax, [rsp + 272]",
+            "mov [rsp + 56], rax",
+            "mov [rsp + 64], r8",
+            "mov [rsp + 72], r9",
+            "mov [rsp + 80], r10",
+            "mov [rsp + 88], r11",
+            "mov [rsp + 96], r12",
+            "mov [rsp + 104], r13",
+            "mov [rsp + 112], r14",
+            "mov [rsp + 120], r15",
+            "movdqu [rsp + 128], xmm0",
+            "movdqu [rsp + 144], xmm1",
+            "movdqu [rsp + 160], xmm2",
+            "movdqu [rsp + 176], xmm3",
+            "movdqu [rsp + 192], xmm4",
+            "movdqu [rsp + 208], xmm5",
+            "movdqu [rsp + 224], xmm6",
+            "movdqu [rsp + 240], xmm7",
+            "mov rdi, rsp",
+            "call {on_exit}",
+            "mov r11, rax",
+            "movdqu xmm0, [rsp + 128]",
+            "movdqu xmm1, [rsp + 144]",
+            "movdqu xmm2, [rsp + 160]",
+            "movdqu xmm3, [rsp + 176]",
+            "movdqu xmm4, [rsp + 192]",
+            "movdqu xmm5, [rsp + 208]",
+            "movdqu xmm6, [rsp + 224]",
+            "movdqu xmm7, [rsp + 240]",
+            "mov rax, [rsp]",
+            "mov rbx, [rsp + 8]",
+            "mov rcx, [rsp + 16]",
+            "mov rdx, [rsp + 24]",
+            "mov rsi, [rsp + 32]",
+            "mov rdi, [rsp + 40]",
+            "mov rbp, [rsp + 48]",
+            "mov r8, [rsp + 64]",
+            "mov r9, [rsp + 72]",
+            "mov r10, [rsp + 80]",
+            "mov r12, [rsp + 96]",
+            "mov r13, [rsp + 104]",
+            "mov r14, [rsp + 112]",
+            "mov r15, [rsp + 120]",
+            "add rsp, 272",
+            ".cfi_adjust_cfa_offset -272",
+            "jmp r11",
+            ".cfi_endproc",
+            on_exit = sym on_exit,
+            personality = sym exit_personality,
+            m = const EXIT_MAGIC,
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::engine;
+
+        static ENTERED: AtomicUsize = AtomicUsize::new(0);
+        static EXITED: AtomicUsize = AtomicUsize::new(0);
+
+        #[inline(never)]
+        extern "C" fn depth(n: u64) -> u64 {
+            if core::hint::black_box(n) == 0 {
+                0
+            } else {
+                core::hint::black_box(depth as extern "C" fn(u64) -> u64)(n - 1) + 1
+            }
+        }
+
+        unsafe fn on_depth_enter(address: *mut c_void, _context: *mut c_void) {
+            assert_eq!(address, depth as *const () as *mut c_void);
+            ENTERED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        unsafe fn on_depth_exit(_address: *mut c_void, context: *mut c_void) {
+            EXITED.fetch_add(1, Ordering::SeqCst);
+            // Each level adds 10 instead of 1.
+            (*(context as *mut RegisterContext)).rax += 9;
+        }
+
+        #[test]
+        fn post_handler_rewrites_nested_returns() {
+            let address = depth as *const () as *mut c_void;
+            engine::instrument_with_exit(address, Some(on_depth_enter), on_depth_exit)
+                .expect("instrument");
+            let call = core::hint::black_box(depth as extern "C" fn(u64) -> u64);
+            // Innermost call returns 0 + 9, each of the 3 callers adds 1 + 9.
+            assert_eq!(call(3), 39);
+            assert_eq!(ENTERED.load(Ordering::SeqCst), 4);
+            assert_eq!(EXITED.load(Ordering::SeqCst), 4);
+            assert!(SHADOW.with_borrow(Vec::is_empty));
+            unsafe { engine::destroy(address).expect("destroy") };
+            assert_eq!(call(3), 3);
+        }
+
+        static UNWIND_EXITS: AtomicUsize = AtomicUsize::new(0);
+
+        #[inline(never)]
+        extern "C-unwind" fn unwinds(n: u64, panic: bool) -> u64 {
+            if core::hint::black_box(n) > 0 {
+                let unwinds =
+                    core::hint::black_box(unwinds as extern "C-unwind" fn(u64, bool) -> u64);
+                return unwinds(n - 1, panic) + 1;
+            }
+            if panic {
+                std::panic::resume_unwind(Box::new(()));
+            }
+            // Stops at the innermost thunk instead of looping on it.
+            drop(std::backtrace::Backtrace::force_capture());
+            0
+        }
+
+        unsafe fn on_unwinds_exit(_address: *mut c_void, _context: *mut c_void) {
+            UNWIND_EXITS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        #[test]
+        fn panics_unwind_through_instrumented_frames() {
+            let address = unwinds as *const () as *mut c_void;
+            engine::instrument_with_exit(address, None, on_unwinds_exit).expect("instrument");
+            let call = core::hint::black_box(unwinds as extern "C-unwind" fn(u64, bool) -> u64);
+            assert!(std::panic::catch_unwind(|| call(3, true)).is_err());
+            // Unwound frames skip their post-handler and leave nothing behind.
+            assert_eq!(UNWIND_EXITS.load(Ordering::SeqCst), 0);
+            assert!(SHADOW.with_borrow(Vec::is_empty));
+            assert_eq!(call(3, false), 3);
+            assert_eq!(UNWIND_EXITS.load(Ordering::SeqCst), 4);
+            unsafe { engine::destroy(address).expect("destroy") };
+        }
+
+        #[test]
+        fn stale_frames_are_pruned() {
+            static SITE: Site = Site {
+                address: 0,
+                pre: AtomicUsize::new(0),
+                post: AtomicUsize::new(0),
+                trampoline: AtomicUsize::new(0),
+                stub: 0,
+            };
+            // Frames left behind by a longjmp from deeper in the stack.
+            SHADOW.with_borrow_mut(|shadow| {
+                for slot in [0x1000, 0x0f00] {
+                    shadow.push(Frame {
+                        slot,
+                        ret: 0,
+                        site: &SITE,
+                    });
+                }
+            });
+            let mut ret = [0usize, 0xdead];
+            let mut ctx: RegisterContext = unsafe { core::mem::zeroed() };
+            ctx.rsp = &mut ret[1] as *mut usize as u64;
+            unsafe fn ignore(_address: *mut c_void, _context: *mut c_void) {}
+            SITE.post
+                .store(ignore as *const () as usize, Ordering::SeqCst);
+            SITE.trampoline.store(1, Ordering::SeqCst);
+            unsafe {
+                on_enter(&SITE, &mut ctx);
+                assert_eq!(SHADOW.with_borrow(Vec::len), 1);
+                ctx.rsp += 8;
+                assert_eq!(on_exit(&mut ctx), 0xdead);
+            }
+            assert!(SHADOW.with_borrow(Vec::is_empty));
+        }
+    }
+}
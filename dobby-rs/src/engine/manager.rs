@@ -1,53 +1,437 @@
+use crate::allocator::ExecutableAllocator;
 use crate::engine::backend;
 use crate::error::{Error, Result};
+use crate::options::{HookOptions, PatchAtomicity, PatchStyle, ThreadSuspension};
 use once_cell::sync::OnceCell;
 use std::collections::HashMap;
 use std::ffi::c_void;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-#[derive(Debug)]
 struct HookInfo {
     original: Vec<u8>,
-    patch_len: usize,
+    patch: Vec<u8>,
     trampoline: usize,
     trampoline_size: usize,
+    enabled: bool,
+    breakpoint: bool,
+    trap_offset: usize,
+    style: PatchStyle,
+    ibt: bool,
+    /// How the patch was last written (installed or re-enabled).
+    atomicity: PatchAtomicity,
+    verify_before_destroy: bool,
+    thread_suspension: ThreadSuspension,
+    allocator: Arc<dyn ExecutableAllocator>,
 }
 
-static HOOKS: OnceCell<Mutex<HashMap<usize, HookInfo>>> = OnceCell::new();
-fn hooks() -> &'static Mutex<HashMap<usize, HookInfo>> {
+// One slot per target address. The map lock is only held to look up or retire a slot; the slot
+// lock is held for the whole hook/destroy/enable/disable so operations on one address are
+// serialized while unrelated targets proceed in parallel.
+type Slot = Arc<Mutex<Option<HookInfo>>>;
+
+static HOOKS: OnceCell<Mutex<HashMap<usize, Slot>>> = OnceCell::new();
+fn hooks() -> &'static Mutex<HashMap<usize, Slot>> {
     HOOKS.get_or_init(|| Mutex::new(HashMap::new()))
 }
 
-pub(crate) unsafe fn hook(address: *mut c_void, fake_func: *mut c_void) -> Result<*mut c_void> {
+fn slot(key: usize, create: bool) -> Option<Slot> {
+    let mut hooks = hooks().lock().unwrap();
+    if create {
+        Some(hooks.entry(key).or_default().clone())
+    } else {
+        hooks.get(&key).cloned()
+    }
+}
+
+// Drop an empty slot from the map once nobody else is waiting on it.
+fn retire(key: usize, slot: Slot) {
+    let mut hooks = hooks().lock().unwrap();
+    // Map + ours; nobody can clone it from the map while we hold the map lock.
+    if Arc::strong_count(&slot) == 2 && slot.lock().unwrap().is_none() {
+        hooks.remove(&key);
+    }
+}
+
+#[cfg(unix)]
+use crate::platform::unix::{SuspendedThreads, suspend_other_threads};
+#[cfg(windows)]
+use crate::platform::windows::{SuspendedThreads, suspend_other_threads};
+
+// Suspensions tried before giving up on a thread that keeps stopping inside the patched bytes.
+#[cfg(any(unix, windows))]
+const SUSPEND_ATTEMPTS: usize = 16;
+
+/// Suspend other threads for a write of `len` bytes at `site`, retrying while one of them is
+/// stopped inside it.
+#[cfg(any(unix, windows))]
+unsafe fn suspend(
+    mode: ThreadSuspension,
+    site: *mut c_void,
+    len: usize,
+) -> Result<Option<SuspendedThreads>> {
+    if mode == ThreadSuspension::None {
+        return Ok(None);
+    }
+    for _ in 0..SUSPEND_ATTEMPTS {
+        let suspended = suspend_other_threads()?;
+        if !suspended.stopped_inside(site as usize, len) {
+            return Ok(Some(suspended));
+        }
+        drop(suspended);
+        std::thread::yield_now();
+    }
+    Err(Error::ThreadInPatch)
+}
+
+#[cfg(not(any(unix, windows)))]
+unsafe fn suspend(mode: ThreadSuspension, _site: *mut c_void, _len: usize) -> Result<Option<()>> {
+    match mode {
+        ThreadSuspension::None => Ok(None),
+        ThreadSuspension::Others => Err(Error::UnsupportedPlatform),
+    }
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use crate::platform::unix::trap::{register as register_trap, unregister as unregister_trap};
+
+#[cfg(not(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+unsafe fn register_trap(_address: usize, _target: usize) -> Result<()> {
+    Err(Error::UnsupportedPlatform)
+}
+
+#[cfg(not(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn unregister_trap(_address: usize) {}
+
+pub(crate) unsafe fn hook(
+    address: *mut c_void,
+    fake_func: *mut c_void,
+    options: &HookOptions,
+) -> Result<*mut c_void> {
     let key = address as usize;
-    if hooks().lock().unwrap().contains_key(&key) {
+    let slot = slot(key, true).expect("slot created");
+    let result = hook_locked(&mut slot.lock().unwrap(), address, fake_func, options);
+    retire(key, slot);
+    result
+}
+
+unsafe fn hook_locked(
+    entry: &mut Option<HookInfo>,
+    address: *mut c_void,
+    fake_func: *mut c_void,
+    options: &HookOptions,
+) -> Result<*mut c_void> {
+    if entry.is_some() {
         return Err(Error::AlreadyHooked);
     }
-    let build = backend::get().hook_build(address, fake_func)?;
-    hooks().lock().unwrap().insert(
-        key,
-        HookInfo {
-            original: build.original,
-            patch_len: build.patch_len,
-            trampoline: build.trampoline as usize,
-            trampoline_size: build.trampoline_size,
-        },
-    );
+    let allocator = options.resolved_allocator();
+    let build = backend::get().hook_build(address, fake_func, options, allocator.as_ref())?;
+    let site = backend::get().code_address(address);
+    // A trap must be routable before the first thread can hit it.
+    let trap = site as usize + build.trap_offset;
+    let registered = if build.breakpoint {
+        register_trap(trap, fake_func as usize)
+    } else {
+        Ok(())
+    };
+    let written = registered.and_then(|()| {
+        suspend(options.thread_suspension, site, build.patch.len()).and_then(|_suspended| {
+            backend::get().code_patch(site, build.patch.as_ptr(), build.patch.len())
+        })
+    });
+    let atomicity = match written {
+        Ok(atomicity) => atomicity,
+        Err(e) => {
+            if build.breakpoint {
+                unregister_trap(trap);
+            }
+            let _ = allocator.free(build.trampoline, build.trampoline_size);
+            return Err(e);
+        }
+    };
+    *entry = Some(HookInfo {
+        original: build.original,
+        patch: build.patch,
+        trampoline: build.trampoline as usize,
+        trampoline_size: build.trampoline_size,
+        enabled: true,
+        breakpoint: build.breakpoint,
+        trap_offset: build.trap_offset,
+        style: build.style,
+        ibt: build.ibt,
+        atomicity,
+        verify_before_destroy: options.verify_before_destroy,
+        thread_suspension: options.thread_suspension,
+        allocator,
+    });
     Ok(build.trampoline)
 }
 
 pub(crate) unsafe fn destroy(address: *mut c_void) -> Result<()> {
     let key = address as usize;
-    let info = hooks()
-        .lock()
-        .unwrap()
-        .remove(&key)
-        .ok_or(Error::HookNotFound)?;
-    backend::get().hook_destroy(
-        address,
-        &info.original,
-        info.patch_len,
-        info.trampoline as *mut c_void,
-        info.trampoline_size,
-    )
+    let slot = slot(key, false).ok_or(Error::HookNotFound)?;
+    let result = destroy_locked(&mut slot.lock().unwrap(), address);
+    retire(key, slot);
+    result
+}
+
+unsafe fn destroy_locked(entry: &mut Option<HookInfo>, address: *mut c_void) -> Result<()> {
+    let info = entry.as_ref().ok_or(Error::HookNotFound)?;
+    let site = backend::get().code_address(address);
+    if info.enabled {
+        if info.verify_before_destroy {
+            let current = core::slice::from_raw_parts(site as *const u8, info.patch.len());
+            if current != info.patch.as_slice() {
+                return Err(Error::PatchVerifyFailed);
+            }
+        }
+        let _suspended = suspend(info.thread_suspension, site, info.original.len())?;
+        backend::get().restore_patch(site, &info.original)?;
+    }
+    let info = entry.take().expect("hook present");
+    if info.breakpoint {
+        unregister_trap(site as usize + info.trap_offset);
+    }
+    info.allocator
+        .free(info.trampoline as *mut c_void, info.trampoline_size)
+}
+
+/// Trampoline (callable original) of the hook at `address`, if there is one.
+pub(crate) fn trampoline(address: *mut c_void) -> Option<*mut c_void> {
+    let slot = slot(address as usize, false)?;
+    let entry = slot.lock().unwrap();
+    entry.as_ref().map(|info| info.trampoline as *mut c_void)
+}
+
+/// Jump style written at `address`, or `None` if it isn't hooked or uses a breakpoint.
+pub(crate) fn patch_style(address: *mut c_void) -> Option<PatchStyle> {
+    let slot = slot(address as usize, false)?;
+    let entry = slot.lock().unwrap();
+    entry
+        .as_ref()
+        .filter(|info| !info.breakpoint)
+        .map(|info| info.style)
+}
+
+/// Whether the target of the hook at `address` is in an image marked for CET indirect branch
+/// tracking, or `None` if it isn't hooked.
+pub(crate) fn ibt_marked(address: *mut c_void) -> Option<bool> {
+    let slot = slot(address as usize, false)?;
+    let entry = slot.lock().unwrap();
+    entry.as_ref().map(|info| info.ibt)
+}
+
+/// How the patch of the hook at `address` was last written, or `None` if it isn't hooked.
+pub(crate) fn patch_atomicity(address: *mut c_void) -> Option<PatchAtomicity> {
+    let slot = slot(address as usize, false)?;
+    let entry = slot.lock().unwrap();
+    entry.as_ref().map(|info| info.atomicity)
+}
+
+/// Re-apply (`true`) or temporarily remove (`false`) the patch of an existing hook. The
+/// trampoline stays allocated, so pointers to the original function remain valid.
+pub(crate) unsafe fn set_enabled(address: *mut c_void, enabled: bool) -> Result<()> {
+    let key = address as usize;
+    let slot = slot(key, false).ok_or(Error::HookNotFound)?;
+    let result = {
+        let mut entry = slot.lock().unwrap();
+        match entry.as_mut() {
+            None => Err(Error::HookNotFound),
+            Some(info) if info.enabled == enabled => Ok(()),
+            Some(info) => {
+                let bytes = if enabled { &info.patch } else { &info.original };
+                let site = backend::get().code_address(address);
+                suspend(info.thread_suspension, site, bytes.len())
+                    .and_then(|_suspended| {
+                        backend::get().code_patch(site, bytes.as_ptr(), bytes.len())
+                    })
+                    .map(|atomicity| {
+                        if enabled {
+                            info.atomicity = atomicity;
+                        }
+                        info.enabled = enabled;
+                    })
+            }
+        }
+    };
+    retire(key, slot);
+    result
+}
+
+#[cfg(all(test, unix, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn target(x: i32) -> i32 {
+        core::hint::black_box(x).wrapping_mul(5) - 2
+    }
+
+    #[inline(never)]
+    fn detour(x: i32) -> i32 {
+        x + 2000
+    }
+
+    #[test]
+    fn relative_patch_and_verified_destroy() {
+        let address = target as *const () as *mut c_void;
+        let options = HookOptions::new()
+            .patch_style(PatchStyle::Relative)
+            .verify_before_destroy(true)
+            .thread_suspension(ThreadSuspension::Others);
+        let stop = Arc::new(core::sync::atomic::AtomicBool::new(false));
+        let spinner = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(core::sync::atomic::Ordering::Relaxed) {
+                    core::hint::spin_loop();
+                }
+            })
+        };
+        unsafe {
+            let tramp = hook(address, detour as *const () as *mut c_void, &options).expect("hook");
+            assert_eq!(*(address as *const u8), 0xE9);
+            assert_eq!(patch_style(address), Some(PatchStyle::Relative));
+            assert_eq!(core::hint::black_box(target as fn(i32) -> i32)(1), 2001);
+            let original: fn(i32) -> i32 = core::mem::transmute(tramp);
+            assert_eq!(original(1), 3);
+
+            // Someone else rewrites the patch: destroy must refuse and keep the hook.
+            let saved = *(address as *const [u8; 5]);
+            let clobbered = [0x90u8; 5];
+            backend::get()
+                .code_patch(address, clobbered.as_ptr(), clobbered.len())
+                .unwrap();
+            assert!(matches!(destroy(address), Err(Error::PatchVerifyFailed)));
+            backend::get()
+                .code_patch(address, saved.as_ptr(), saved.len())
+                .unwrap();
+            destroy(address).expect("destroy");
+        }
+        stop.store(true, core::sync::atomic::Ordering::Relaxed);
+        spinner.join().unwrap();
+        assert_eq!(core::hint::black_box(target as fn(i32) -> i32)(1), 3);
+    }
+
+    #[inline(never)]
+    fn contended(x: i32) -> i32 {
+        core::hint::black_box(x).wrapping_mul(7) + 3
+    }
+
+    #[test]
+    fn concurrent_hooks_on_one_target_and_toggle() {
+        let address = contended as *const () as *mut c_void;
+        let call = || core::hint::black_box(contended as fn(i32) -> i32)(1);
+        let results: Vec<_> = (0..8)
+            .map(|_| {
+                let key = address as usize;
+                std::thread::spawn(move || unsafe {
+                    hook(
+                        key as *mut c_void,
+                        detour as *const () as *mut c_void,
+                        &HookOptions::default(),
+                    )
+                    .map(|t| t as usize)
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect();
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(
+            results
+                .iter()
+                .all(|r| r.is_ok() || matches!(r, Err(Error::AlreadyHooked)))
+        );
+        assert_eq!(call(), 2001);
+        unsafe {
+            set_enabled(address, false).unwrap();
+            assert_eq!(call(), 10);
+            set_enabled(address, true).unwrap();
+            assert_eq!(call(), 2001);
+            set_enabled(address, false).unwrap();
+            destroy(address).expect("destroy");
+        }
+        assert_eq!(call(), 10);
+        assert!(!hooks().lock().unwrap().contains_key(&(address as usize)));
+    }
+
+    extern "C" fn plus_100(x: i32) -> i32 {
+        x + 100
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tiny_function_falls_back_to_breakpoint() {
+        use crate::options::HookMode;
+        // lea eax, [rdi + 1]; ret
+        let code = [0x8D, 0x47, 0x01, 0xC3];
+        unsafe {
+            let page = crate::platform::unix::alloc_executable(4096).unwrap();
+            core::ptr::copy_nonoverlapping(code.as_ptr(), page as *mut u8, code.len());
+            let tiny: extern "C" fn(i32) -> i32 = core::mem::transmute(page);
+            let detour = plus_100 as *const () as *mut c_void;
+
+            let inline = HookOptions::new().mode(HookMode::Inline);
+            assert!(matches!(
+                hook(page, detour, &inline),
+                Err(Error::PatchTooSmall)
+            ));
+
+            let tramp = hook(page, detour, &HookOptions::default()).expect("breakpoint hook");
+            assert_eq!(*(page as *const u8), 0xCC);
+            assert_eq!(core::hint::black_box(tiny)(1), 101);
+            let original: extern "C" fn(i32) -> i32 = core::mem::transmute(tramp);
+            assert_eq!(original(1), 2);
+            destroy(page).expect("destroy");
+            assert_eq!(core::hint::black_box(tiny)(1), 2);
+            crate::platform::unix::free_executable(page, 4096).unwrap();
+        }
+    }
+
+    #[test]
+    fn endbr64_stays_at_the_entry() {
+        // endbr64; lea eax, [rdi + rdi*4]; sub eax, 2; ret
+        let code = [
+            0xF3, 0x0F, 0x1E, 0xFA, 0x8D, 0x04, 0xBF, 0x83, 0xE8, 0x02, 0xC3,
+        ];
+        let endbr64 = &code[..4];
+        unsafe {
+            let page = crate::platform::unix::alloc_executable(4096).unwrap();
+            core::ptr::copy_nonoverlapping(code.as_ptr(), page as *mut u8, code.len());
+            let func: extern "C" fn(i32) -> i32 = core::mem::transmute(page);
+            let detour = plus_100 as *const () as *mut c_void;
+            let options = HookOptions::new().patch_style(PatchStyle::Relative);
+
+            let tramp = hook(page, detour, &options).expect("hook");
+            let entry = core::slice::from_raw_parts(page as *const u8, 10);
+            assert_eq!(&entry[..4], endbr64);
+            assert_eq!(entry[4], 0xE9);
+            // Trampoline and relay are both landing pads.
+            assert_eq!(core::slice::from_raw_parts(tramp as *const u8, 4), endbr64);
+            let disp = i32::from_le_bytes([entry[5], entry[6], entry[7], entry[8]]);
+            let relay = (page as *const u8).offset(9 + disp as isize);
+            assert_eq!(core::slice::from_raw_parts(relay, 4), endbr64);
+            assert_eq!(ibt_marked(page), Some(false));
+            // The 10-byte patch sits in the page's first 16-byte block.
+            assert_eq!(patch_atomicity(page), Some(PatchAtomicity::Atomic));
+
+            assert_eq!(core::hint::black_box(func)(1), 101);
+            let original: extern "C" fn(i32) -> i32 = core::mem::transmute(tramp);
+            assert_eq!(original(1), 3);
+            destroy(page).expect("destroy");
+            assert_eq!(core::slice::from_raw_parts(page as *const u8, 11), &code);
+            assert_eq!(ibt_marked(page), None);
+            crate::platform::unix::free_executable(page, 4096).unwrap();
+        }
+    }
 }
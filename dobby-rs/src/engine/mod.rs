@@ -1,24 +1,157 @@
 use core::ffi::{CStr, c_char, c_void};
 
+mod assemble;
 mod backend;
 mod imports;
 mod instrument;
 mod manager;
+mod patch;
+mod watch;
 
+use crate::allocator::ExecutableAllocator;
 use crate::error::{Error, Result};
+use crate::options::{HookOptions, PatchAtomicity, PatchStyle};
+use std::sync::Arc;
+
+#[cfg(target_arch = "aarch64")]
+pub use assemble::Arm64Assembler;
+pub use instrument::InstrumentHandler;
+#[cfg(target_arch = "x86_64")]
+pub use instrument::RegisterContext;
+pub use patch::{PatchGuard, PatchSet};
+pub use watch::{WatchAccess, WatchCallback, WatchHit, WatchId};
 
 pub unsafe fn code_patch(address: *mut c_void, buffer: *const u8, buffer_size: u32) -> Result<()> {
     if address.is_null() || buffer.is_null() {
         return Err(Error::NullPointer);
     }
-    backend::get().code_patch(address, buffer, buffer_size as usize)
+    backend::get()
+        .code_patch(address, buffer, buffer_size as usize)
+        .map(|_| ())
+}
+
+pub unsafe fn code_patch_guarded(address: *mut c_void, bytes: &[u8]) -> Result<PatchGuard> {
+    if address.is_null() {
+        return Err(Error::NullPointer);
+    }
+    patch::code_patch_guarded(address, bytes)
+}
+
+/// Assemble instructions at `address` and patch them over it, padded with NOPs to the end of the
+/// last instruction they overlap.
+///
+/// ```no_run
+/// use dobby_hook_core::code_asm::*;
+/// # let address = core::ptr::null_mut();
+/// let guard = unsafe {
+///     dobby_hook_core::patch_instructions(address, |asm| {
+///         asm.mov(eax, 1)?;
+///         asm.ret()
+///     })
+/// }?;
+/// # Ok::<(), dobby_hook_core::Error>(())
+/// ```
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub unsafe fn patch_instructions(
+    address: *mut c_void,
+    build: impl FnOnce(
+        &mut iced_x86::code_asm::CodeAssembler,
+    ) -> core::result::Result<(), iced_x86::IcedError>,
+) -> Result<PatchGuard> {
+    if address.is_null() {
+        return Err(Error::NullPointer);
+    }
+    assemble::patch_instructions(address, build)
+}
+
+/// Assemble instructions at `address` and patch them over it (see [`Arm64Assembler`]).
+#[cfg(target_arch = "aarch64")]
+pub unsafe fn patch_instructions(
+    address: *mut c_void,
+    build: impl FnOnce(&mut Arm64Assembler) -> Result<()>,
+) -> Result<PatchGuard> {
+    if address.is_null() {
+        return Err(Error::NullPointer);
+    }
+    assemble::patch_instructions(address, build)
+}
+
+/// Make `function` return `value` right away. An `endbr64` or BTI landing pad at its entry is kept.
+pub unsafe fn patch_return_value(function: *mut c_void, value: u64) -> Result<PatchGuard> {
+    if function.is_null() {
+        return Err(Error::NullPointer);
+    }
+    assemble::patch_return_value(function, value)
+}
+
+/// Patch a jump to `target` over `address`: a relative one if it reaches, otherwise an absolute
+/// one.
+pub unsafe fn patch_branch(address: *mut c_void, target: *const c_void) -> Result<PatchGuard> {
+    if address.is_null() || target.is_null() {
+        return Err(Error::NullPointer);
+    }
+    assemble::patch_branch(address, target)
+}
+
+/// Replace `len` bytes at `address` with as few NOPs as possible. `len` has to end on an
+/// instruction boundary.
+pub unsafe fn nop_out(address: *mut c_void, len: usize) -> Result<PatchGuard> {
+    if address.is_null() {
+        return Err(Error::NullPointer);
+    }
+    assemble::nop_out(address, len)
+}
+
+pub fn register_patch_set(set: PatchSet) -> Result<()> {
+    patch::register_patch_set(set)
+}
+
+pub unsafe fn unregister_patch_set(name: &str) -> Result<PatchSet> {
+    patch::unregister_patch_set(name)
+}
+
+pub unsafe fn enable_patch_set(name: &str) -> Result<()> {
+    patch::with_patch_set(name, |set| set.enable())
+}
+
+pub unsafe fn disable_patch_set(name: &str) -> Result<()> {
+    patch::with_patch_set(name, |set| set.disable())
+}
+
+pub unsafe fn toggle_patch_set(name: &str) -> Result<bool> {
+    patch::with_patch_set(name, |set| set.toggle())
+}
+
+pub fn patch_set_enabled(name: &str) -> Option<bool> {
+    patch::patch_set_enabled(name)
 }
 
 pub unsafe fn hook(address: *mut c_void, fake_func: *mut c_void) -> Result<*mut c_void> {
     if address.is_null() || fake_func.is_null() {
         return Err(Error::NullPointer);
     }
-    manager::hook(address, fake_func)
+    manager::hook(address, fake_func, &HookOptions::default())
+}
+
+/// Like [`hook`], with per-hook settings instead of the process-wide defaults.
+pub unsafe fn hook_with_options(
+    address: *mut c_void,
+    fake_func: *mut c_void,
+    options: &HookOptions,
+) -> Result<*mut c_void> {
+    if address.is_null() || fake_func.is_null() {
+        return Err(Error::NullPointer);
+    }
+    manager::hook(address, fake_func, options)
+}
+
+/// Like [`hook`], but the trampoline is allocated from (and later freed to) `allocator`.
+pub unsafe fn hook_with_allocator(
+    address: *mut c_void,
+    fake_func: *mut c_void,
+    allocator: Arc<dyn ExecutableAllocator>,
+) -> Result<*mut c_void> {
+    hook_with_options(address, fake_func, &HookOptions::new().allocator(allocator))
 }
 
 pub unsafe fn destroy(address: *mut c_void) -> Result<()> {
@@ -28,11 +161,55 @@ pub unsafe fn destroy(address: *mut c_void) -> Result<()> {
     manager::destroy(address)
 }
 
+/// Re-apply the patch of a hook previously turned off with [`disable_hook`].
+pub unsafe fn enable_hook(address: *mut c_void) -> Result<()> {
+    if address.is_null() {
+        return Err(Error::NullPointer);
+    }
+    manager::set_enabled(address, true)
+}
+
+/// Restore the original bytes but keep the hook (and its trampoline) registered.
+pub unsafe fn disable_hook(address: *mut c_void) -> Result<()> {
+    if address.is_null() {
+        return Err(Error::NullPointer);
+    }
+    manager::set_enabled(address, false)
+}
+
+/// Jump style the hook at `address` was written with (see [`PatchStyle`]); `None` if there is no
+/// hook or it is a breakpoint hook.
+pub fn hook_patch_style(address: *mut c_void) -> Option<PatchStyle> {
+    manager::patch_style(address)
+}
+
+/// How the patch of the hook at `address` was written while other threads could be running it
+/// (see [`PatchAtomicity`]); `None` if there is no hook.
+pub fn hook_patch_atomicity(address: *mut c_void) -> Option<PatchAtomicity> {
+    manager::patch_atomicity(address)
+}
+
+/// Whether the hooked function at `address` lives in an image built for CET indirect branch
+/// tracking (`GNU_PROPERTY_X86_FEATURE_1_IBT`); `None` if there is no hook. Always `false` off
+/// x86_64 ELF.
+pub fn hook_ibt_marked(address: *mut c_void) -> Option<bool> {
+    manager::ibt_marked(address)
+}
+
+/// Callable original of `address` if the engine hooked it; used by the platform layer to call
+/// libc functions without going through a user's detour.
+#[cfg(unix)]
+pub(crate) fn hooked_original(address: *mut c_void) -> Option<*mut c_void> {
+    manager::trampoline(address)
+}
+
+/// Resolve `symbol_name` in an already loaded image (or the global scope when `image_name` is
+/// null). Never loads a library; see [`resolve_symbol_or_load`].
 pub unsafe fn symbol_resolver(
     image_name: *const c_char,
     symbol_name: *const c_char,
 ) -> *mut c_void {
-    backend::get().symbol_resolver(image_name, symbol_name)
+    backend::get().symbol_resolver(image_name, symbol_name, false)
 }
 
 pub fn resolve_symbol(image_name: Option<&CStr>, symbol_name: &CStr) -> *mut c_void {
@@ -44,6 +221,19 @@ pub fn resolve_symbol(image_name: Option<&CStr>, symbol_name: &CStr) -> *mut c_v
     }
 }
 
+/// Like [`resolve_symbol`], but loads `image_name` first if it isn't loaded. The library then stays
+/// loaded for the rest of the process.
+pub fn resolve_symbol_or_load(image_name: &CStr, symbol_name: &CStr) -> *mut c_void {
+    unsafe { backend::get().symbol_resolver(image_name.as_ptr(), symbol_name.as_ptr(), true) }
+}
+
+/// Forget cached symbol addresses. The cache already invalidates itself when a module is
+/// unloaded; this is for callers that patch symbol tables themselves.
+pub fn clear_symbol_cache() {
+    #[cfg(unix)]
+    crate::platform::unix::clear_symbol_cache();
+}
+
 pub fn import_table_replace(
     image_name: Option<&CStr>,
     symbol_name: &CStr,
@@ -55,9 +245,58 @@ pub fn import_table_replace(
     imports::import_table_replace(image_name, symbol_name, fake_func)
 }
 
-pub fn instrument(
+/// Call `pre_handler` on every entry to `address`, then run the original function. Remove with
+/// [`destroy`]. x86_64 Unix only.
+pub fn instrument(address: *mut c_void, pre_handler: InstrumentHandler) -> Result<()> {
+    instrument::instrument(address, Some(pre_handler), None)
+}
+
+/// Like [`instrument`], and also call `post_handler` when the function returns.
+///
+/// The return address is swapped for an exit thunk on entry and kept on a per-thread shadow
+/// stack; frames abandoned by `longjmp` are pruned. An exception or panic unwinding through the
+/// instrumented frame continues into the real caller without running `post_handler`; backtraces
+/// taken inside the frame end at the thunk.
+pub fn instrument_with_exit(
     address: *mut c_void,
-    pre_handler: unsafe fn(address: *mut c_void, context: *mut c_void),
+    pre_handler: Option<InstrumentHandler>,
+    post_handler: InstrumentHandler,
 ) -> Result<()> {
-    instrument::instrument(address, pre_handler)
+    instrument::instrument(address, pre_handler, Some(post_handler))
+}
+
+/// Call `callback` whenever any thread reads, writes or executes `len` bytes at `address`.
+///
+/// The containing pages are made inaccessible, so every access to them (watched or not) takes a
+/// fault and a single-step; expect a large slowdown on those pages. Accesses made by the kernel
+/// (e.g. `read(2)` into the buffer) fail with `EFAULT` instead of being reported, and accesses
+/// the original protection forbids fault as before without a report. While a page is unguarded
+/// to single-step one thread's access, accesses to it by other threads are not reported. Linux
+/// x86_64 only.
+pub unsafe fn watch(address: *mut c_void, len: usize, callback: WatchCallback) -> Result<WatchId> {
+    if address.is_null() {
+        return Err(Error::NullPointer);
+    }
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    {
+        crate::platform::unix::watch::watch(address as usize, len, callback).map(WatchId)
+    }
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    {
+        let _ = (len, callback);
+        Err(Error::UnsupportedPlatform)
+    }
+}
+
+/// Remove a watch and restore the original protection of pages no other watch covers.
+pub unsafe fn unwatch(id: WatchId) -> Result<()> {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    {
+        crate::platform::unix::watch::unwatch(id.0)
+    }
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    {
+        let _ = id;
+        Err(Error::UnsupportedPlatform)
+    }
 }
@@ -0,0 +1,256 @@
+use crate::engine::backend;
+use crate::error::{Error, Result};
+use core::ffi::c_void;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A byte patch that remembers what it overwrote.
+///
+/// The original bytes are restored when the guard is dropped or when [`PatchGuard::revert`] is
+/// called. Use [`PatchGuard::leak`] to keep the patch in place permanently.
+#[derive(Debug)]
+pub struct PatchGuard {
+    address: usize,
+    original: Vec<u8>,
+    active: bool,
+}
+
+impl PatchGuard {
+    pub fn address(&self) -> *mut c_void {
+        self.address as *mut c_void
+    }
+    pub fn len(&self) -> usize {
+        self.original.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.original.is_empty()
+    }
+    pub fn original_bytes(&self) -> &[u8] {
+        &self.original
+    }
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+    /// Restore the original bytes now, reporting failures instead of ignoring them like `Drop`.
+    pub unsafe fn revert(mut self) -> Result<()> {
+        self.restore()
+    }
+    /// Keep the patch applied and forget the original bytes.
+    pub fn leak(mut self) {
+        self.active = false;
+    }
+    unsafe fn restore(&mut self) -> Result<()> {
+        if !self.active {
+            return Ok(());
+        }
+        backend::get().restore_patch(self.address as *mut c_void, &self.original)?;
+        self.active = false;
+        Ok(())
+    }
+}
+
+impl Drop for PatchGuard {
+    fn drop(&mut self) {
+        let _ = unsafe { self.restore() };
+    }
+}
+
+pub(super) unsafe fn code_patch_guarded(address: *mut c_void, bytes: &[u8]) -> Result<PatchGuard> {
+    if bytes.is_empty() {
+        return Err(Error::InvalidInput);
+    }
+    let original = core::slice::from_raw_parts(address as *const u8, bytes.len()).to_vec();
+    backend::get().code_patch(address, bytes.as_ptr(), bytes.len())?;
+    Ok(PatchGuard {
+        address: address as usize,
+        original,
+        active: true,
+    })
+}
+
+/// A named group of byte patches that are applied and reverted together.
+///
+/// Original bytes are captured each time the set is enabled, so a set can be toggled any number of
+/// times. Patches are applied in insertion order and reverted in reverse order, which keeps
+/// overlapping entries consistent.
+#[derive(Debug)]
+pub struct PatchSet {
+    name: String,
+    patches: Vec<(usize, Vec<u8>)>,
+    applied: Vec<PatchGuard>,
+}
+
+impl PatchSet {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            patches: Vec::new(),
+            applied: Vec::new(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn len(&self) -> usize {
+        self.patches.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+    pub fn is_enabled(&self) -> bool {
+        !self.applied.is_empty()
+    }
+    pub fn add(&mut self, address: *mut c_void, bytes: &[u8]) -> Result<&mut Self> {
+        if address.is_null() {
+            return Err(Error::NullPointer);
+        }
+        if bytes.is_empty() || self.is_enabled() {
+            return Err(Error::InvalidInput);
+        }
+        self.patches.push((address as usize, bytes.to_vec()));
+        Ok(self)
+    }
+    pub fn with_patch(mut self, address: *mut c_void, bytes: &[u8]) -> Result<Self> {
+        self.add(address, bytes)?;
+        Ok(self)
+    }
+    /// Apply every patch in the set. If one of them fails, the ones already applied are reverted.
+    pub unsafe fn enable(&mut self) -> Result<()> {
+        if self.is_enabled() {
+            return Ok(());
+        }
+        for (address, bytes) in &self.patches {
+            match code_patch_guarded(*address as *mut c_void, bytes) {
+                Ok(guard) => self.applied.push(guard),
+                Err(e) => {
+                    let _ = self.disable();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+    pub unsafe fn disable(&mut self) -> Result<()> {
+        while let Some(guard) = self.applied.pop() {
+            guard.revert()?;
+        }
+        Ok(())
+    }
+    /// Flip the set and return whether it is now enabled.
+    pub unsafe fn toggle(&mut self) -> Result<bool> {
+        if self.is_enabled() {
+            self.disable()?;
+        } else {
+            self.enable()?;
+        }
+        Ok(self.is_enabled())
+    }
+}
+
+static PATCH_SETS: OnceCell<Mutex<HashMap<String, PatchSet>>> = OnceCell::new();
+fn patch_sets() -> &'static Mutex<HashMap<String, PatchSet>> {
+    PATCH_SETS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub(super) fn register_patch_set(set: PatchSet) -> Result<()> {
+    let mut sets = patch_sets().lock().unwrap();
+    if sets.contains_key(set.name()) {
+        return Err(Error::InvalidInput);
+    }
+    sets.insert(set.name.clone(), set);
+    Ok(())
+}
+
+pub(super) unsafe fn unregister_patch_set(name: &str) -> Result<PatchSet> {
+    let mut sets = patch_sets().lock().unwrap();
+    let set = sets.get_mut(name).ok_or(Error::PatchSetNotFound)?;
+    set.disable()?;
+    Ok(sets.remove(name).expect("patch set present"))
+}
+
+pub(super) unsafe fn with_patch_set<R>(
+    name: &str,
+    f: impl FnOnce(&mut PatchSet) -> Result<R>,
+) -> Result<R> {
+    let mut sets = patch_sets().lock().unwrap();
+    f(sets.get_mut(name).ok_or(Error::PatchSetNotFound)?)
+}
+
+pub(super) fn patch_set_enabled(name: &str) -> Option<bool> {
+    patch_sets()
+        .lock()
+        .unwrap()
+        .get(name)
+        .map(PatchSet::is_enabled)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::platform::unix;
+
+    unsafe fn scratch() -> *mut u8 {
+        let p = unix::alloc_executable(4096).expect("alloc") as *mut u8;
+        for i in 0..64 {
+            *p.add(i) = i as u8;
+        }
+        p
+    }
+
+    #[test]
+    fn guard_restores_on_drop_and_revert() {
+        unsafe {
+            let p = scratch();
+            {
+                let g = code_patch_guarded(p.add(4) as *mut c_void, &[0xAA, 0xBB]).expect("ok");
+                assert_eq!(g.original_bytes(), &[4, 5]);
+                assert_eq!(core::slice::from_raw_parts(p.add(4), 2), &[0xAA, 0xBB]);
+            }
+            assert_eq!(core::slice::from_raw_parts(p.add(4), 2), &[4, 5]);
+
+            let g = code_patch_guarded(p as *mut c_void, &[0xCC]).expect("ok");
+            g.revert().expect("revert");
+            assert_eq!(*p, 0);
+
+            code_patch_guarded(p as *mut c_void, &[0xCC])
+                .expect("ok")
+                .leak();
+            assert_eq!(*p, 0xCC);
+            unix::free_executable(p as *mut c_void, 4096).expect("free");
+        }
+    }
+
+    #[test]
+    fn patch_set_toggles_overlapping_patches() {
+        unsafe {
+            let p = scratch();
+            let set = PatchSet::new("test.overlap")
+                .with_patch(p.add(8) as *mut c_void, &[0x11, 0x22, 0x33])
+                .and_then(|s| s.with_patch(p.add(9) as *mut c_void, &[0x99]))
+                .expect("build");
+            register_patch_set(set).expect("register");
+            assert!(register_patch_set(PatchSet::new("test.overlap")).is_err());
+
+            assert!(with_patch_set("test.overlap", |s| s.toggle()).expect("on"));
+            assert_eq!(
+                core::slice::from_raw_parts(p.add(8), 3),
+                &[0x11, 0x99, 0x33]
+            );
+            assert_eq!(patch_set_enabled("test.overlap"), Some(true));
+
+            assert!(!with_patch_set("test.overlap", |s| s.toggle()).expect("off"));
+            assert_eq!(core::slice::from_raw_parts(p.add(8), 3), &[8, 9, 10]);
+
+            with_patch_set("test.overlap", |s| s.enable()).expect("on");
+            let set = unregister_patch_set("test.overlap").expect("unregister");
+            assert!(!set.is_enabled());
+            assert_eq!(core::slice::from_raw_parts(p.add(8), 3), &[8, 9, 10]);
+            assert!(matches!(
+                unregister_patch_set("test.overlap"),
+                Err(Error::PatchSetNotFound)
+            ));
+            unix::free_executable(p as *mut c_void, 4096).expect("free");
+        }
+    }
+}
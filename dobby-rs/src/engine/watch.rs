@@ -0,0 +1,29 @@
+use core::ffi::c_void;
+
+/// Kind of access that hit a watched range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// One access to a watched range, as seen from the faulting thread.
+#[derive(Debug)]
+pub struct WatchHit {
+    /// Address that was accessed.
+    pub address: *mut c_void,
+    pub access: WatchAccess,
+    /// Instruction that made the access.
+    pub pc: *mut c_void,
+    /// Platform signal context (`ucontext_t`); register changes are applied when the handler
+    /// returns.
+    pub context: *mut c_void,
+}
+
+/// Runs in signal context, on the thread that made the access: keep it async-signal-safe.
+pub type WatchCallback = unsafe fn(hit: &WatchHit);
+
+/// Handle returned by [`watch`](crate::watch).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchId(pub(crate) usize);
@@ -14,6 +14,11 @@ pub enum Error {
     RelocationFailed,
     EncodeFailed,
     PatchTooSmall,
+    PatchSetNotFound,
+    PatchVerifyFailed,
+    /// A suspended thread kept stopping inside the bytes about to be rewritten.
+    ThreadInPatch,
+    WatchNotFound,
     Unix(i32),
     Win32(u32),
 }
@@ -31,6 +36,10 @@ impl fmt::Display for Error {
             Error::RelocationFailed => write!(f, "instruction relocation failed"),
             Error::EncodeFailed => write!(f, "instruction encode failed"),
             Error::PatchTooSmall => write!(f, "patch region too small"),
+            Error::PatchSetNotFound => write!(f, "patch set not found"),
+            Error::PatchVerifyFailed => write!(f, "patched bytes were modified externally"),
+            Error::ThreadInPatch => write!(f, "a thread is stopped inside the patched bytes"),
+            Error::WatchNotFound => write!(f, "watch not found"),
             Error::Unix(code) => write!(f, "unix error: {code}"),
             Error::Win32(code) => write!(f, "win32 error: {code}"),
         }
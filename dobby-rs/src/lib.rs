@@ -2,16 +2,37 @@
 #![allow(clippy::missing_safety_doc)]
 #![doc = include_str!("../README.md")]
 
+mod allocator;
 mod arch;
 mod engine;
 mod error;
 mod options;
 mod platform;
 
+pub use crate::allocator::{ExecutableAllocator, SystemAllocator};
+#[cfg(target_arch = "aarch64")]
+pub use crate::engine::Arm64Assembler;
+#[cfg(target_arch = "x86_64")]
+pub use crate::engine::RegisterContext;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+pub use crate::engine::patch_instructions;
 pub use crate::engine::{
-    code_patch, destroy, hook, import_table_replace, instrument, resolve_symbol, symbol_resolver,
+    InstrumentHandler, PatchGuard, PatchSet, WatchAccess, WatchCallback, WatchHit, WatchId,
+    clear_symbol_cache, code_patch, code_patch_guarded, destroy, disable_hook, disable_patch_set,
+    enable_hook, enable_patch_set, hook, hook_ibt_marked, hook_patch_atomicity, hook_patch_style,
+    hook_with_allocator, hook_with_options, import_table_replace, instrument, instrument_with_exit,
+    nop_out, patch_branch, patch_return_value, patch_set_enabled, register_patch_set,
+    resolve_symbol, resolve_symbol_or_load, symbol_resolver, toggle_patch_set,
+    unregister_patch_set, unwatch, watch,
 };
 pub use crate::error::{Error, Result};
-pub use crate::options::{register_alloc_near_code_callback, set_near_trampoline, set_options};
+pub use crate::options::{
+    HookMode, HookOptions, PatchAtomicity, PatchStyle, ThreadSuspension, TrampolinePlacement,
+    register_alloc_near_code_callback, register_executable_allocator, set_near_trampoline,
+    set_options,
+};
+/// The iced-x86 assembler and registers used by [`patch_instructions`].
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use iced_x86::code_asm;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
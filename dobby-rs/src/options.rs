@@ -1,18 +1,24 @@
+use crate::allocator::{DefaultAllocator, ExecutableAllocator};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use once_cell::sync::OnceCell;
+use std::sync::{Arc, RwLock};
 
 static NEAR_TRAMPOLINE: AtomicBool = AtomicBool::new(false);
 static ALLOC_NEAR_CODE_CB: AtomicUsize = AtomicUsize::new(0);
+static EXECUTABLE_ALLOCATOR: RwLock<Option<Arc<dyn ExecutableAllocator>>> = RwLock::new(None);
+static DEFAULT_ALLOCATOR: OnceCell<Arc<DefaultAllocator>> = OnceCell::new();
 
 pub type AllocNearCodeCallback = unsafe fn(size: u32, pos: usize, range: usize) -> usize;
 
-// These helpers are currently only used by the x86_64 trampoline relocation path.
-// Keep them cfg-gated so non-x86_64 builds don't trip -D dead-code.
-#[cfg(target_arch = "x86_64")]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) fn near_trampoline_enabled() -> bool {
     NEAR_TRAMPOLINE.load(Ordering::Relaxed)
 }
 
-#[cfg(target_arch = "x86_64")]
 pub(crate) fn alloc_near_code_callback() -> Option<AllocNearCodeCallback> {
     let p = ALLOC_NEAR_CODE_CB.load(Ordering::Relaxed);
     if p == 0 {
@@ -26,6 +32,28 @@ pub fn set_near_trampoline(enable: bool) {
     NEAR_TRAMPOLINE.store(enable, Ordering::Relaxed);
 }
 
+/// Allocator used for hooks that don't bring their own.
+///
+/// Falls back to the platform allocator (plus the legacy near-code callback, if registered).
+pub(crate) fn executable_allocator() -> Arc<dyn ExecutableAllocator> {
+    if let Some(a) = EXECUTABLE_ALLOCATOR.read().unwrap().as_ref() {
+        return a.clone();
+    }
+    DEFAULT_ALLOCATOR
+        .get_or_init(|| Arc::new(DefaultAllocator::default()))
+        .clone()
+}
+
+/// Install a process-wide trampoline allocator, or `None` to go back to the default.
+///
+/// Hooks keep the allocator they were created with, so changing it never frees memory through
+/// the wrong owner.
+pub fn register_executable_allocator(allocator: Option<Arc<dyn ExecutableAllocator>>) {
+    *EXECUTABLE_ALLOCATOR.write().unwrap() = allocator;
+}
+
+/// Legacy near-code allocation hook. Prefer [`register_executable_allocator`], which also owns
+/// freeing; blocks returned by this callback are never released by the engine.
 pub fn register_alloc_near_code_callback(handler: Option<AllocNearCodeCallback>) {
     ALLOC_NEAR_CODE_CB.store(handler.map_or(0, |f| f as usize), Ordering::Relaxed);
 }
@@ -37,3 +65,161 @@ pub fn set_options(
     set_near_trampoline(enable_near_trampoline);
     register_alloc_near_code_callback(alloc_near_code_callback);
 }
+
+/// Where the trampoline for a hook is allocated.
+///
+/// Ignored on i686 and 32-bit ARM, where the patch jumps straight to the detour and stolen code
+/// relocates the same from anywhere.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TrampolinePlacement {
+    /// Follow [`set_near_trampoline`].
+    #[default]
+    Default,
+    /// Allocate anywhere; only retry near the target if relocation needs it.
+    Anywhere,
+    /// Allocate near the target first, falling back to anywhere if no slot is free.
+    Near,
+}
+
+/// How the jump to the detour is written at the target.
+///
+/// The default is `Auto` on aarch64 and riscv64, where the absolute jump is long enough to run
+/// past small functions, and `Absolute` elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchStyle {
+    /// The shortest style whose relay could be placed within reach (`Relative`, then `Page` on
+    /// aarch64), `Absolute` otherwise.
+    Auto,
+    /// Absolute indirect jump (14 bytes on x86_64, 16 bytes on aarch64, 20 to 26 bytes on
+    /// riscv64). On 32-bit ARM, an `ldr pc` from a literal (8 bytes, 10 for Thumb code that isn't
+    /// word-aligned); it is the only style there, and what `Auto` picks. On i686 a `jmp rel32`
+    /// already reaches the whole address space, so it is written as one and reported as
+    /// `Relative`.
+    Absolute,
+    /// `jmp rel32` (5 bytes) on x86_64, `b` (4 bytes, ±128 MiB) on aarch64 or `auipc t1; jalr`
+    /// (8 bytes, ±2 GiB) on riscv64, to a relay stub next to the trampoline. Requires a near
+    /// trampoline. On i686 every style but `Page` is a `jmp rel32` straight to the detour.
+    Relative,
+    /// `adrp x17; add x17; br x17` (12 bytes) to a relay within ±4 GiB. aarch64 only.
+    Page,
+}
+
+impl Default for PatchStyle {
+    fn default() -> Self {
+        if cfg!(any(target_arch = "aarch64", target_arch = "riscv64")) {
+            PatchStyle::Auto
+        } else {
+            PatchStyle::Absolute
+        }
+    }
+}
+
+/// How a patch was written while other threads may be running the code under it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchAtomicity {
+    /// One atomic store of the aligned 8- or 16-byte block holding the whole patch: other threads
+    /// see either the old or the new code.
+    Atomic,
+    /// Threads reaching the patch spin on a `jmp $` over its first two bytes while the rest is
+    /// written, then the real first two bytes are stored atomically. A thread already past the
+    /// first instruction can still see a mix.
+    TwoPhase,
+    /// A plain copy: off x86_64, through `/proc/self/mem`, or when the first two bytes straddle a
+    /// 16-byte boundary. Use [`ThreadSuspension::Others`] if the code may be running.
+    NonAtomic,
+}
+
+/// How execution is redirected from the target to the detour.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HookMode {
+    /// Inline patch; fall back to `Breakpoint` where supported if the target can't be patched
+    /// inline (too short or unrelocatable prologue).
+    #[default]
+    Auto,
+    Inline,
+    /// A single `int3`/`brk` redirected by a process-wide SIGTRAP handler (Linux/Android on x86_64
+    /// and aarch64). Works on tiny functions but costs a signal per call.
+    Breakpoint,
+}
+
+/// Whether other threads are stopped while the patch bytes are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadSuspension {
+    #[default]
+    None,
+    /// Suspend every other thread of the process around the patch write and restore.
+    Others,
+}
+
+/// Per-hook settings for [`hook_with_options`](crate::hook_with_options).
+///
+/// The process-wide setters in this module only provide the defaults used by [`hook`](crate::hook).
+#[derive(Clone, Default)]
+pub struct HookOptions {
+    pub mode: HookMode,
+    pub trampoline: TrampolinePlacement,
+    pub patch_style: PatchStyle,
+    /// Refuse to destroy the hook if the patched bytes were changed by someone else.
+    pub verify_before_destroy: bool,
+    pub thread_suspension: ThreadSuspension,
+    /// Trampoline allocator; `None` uses the one from [`register_executable_allocator`].
+    pub allocator: Option<Arc<dyn ExecutableAllocator>>,
+}
+
+impl HookOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn mode(mut self, mode: HookMode) -> Self {
+        self.mode = mode;
+        self
+    }
+    pub fn trampoline(mut self, placement: TrampolinePlacement) -> Self {
+        self.trampoline = placement;
+        self
+    }
+    pub fn patch_style(mut self, style: PatchStyle) -> Self {
+        self.patch_style = style;
+        self
+    }
+    pub fn verify_before_destroy(mut self, verify: bool) -> Self {
+        self.verify_before_destroy = verify;
+        self
+    }
+    pub fn thread_suspension(mut self, mode: ThreadSuspension) -> Self {
+        self.thread_suspension = mode;
+        self
+    }
+    pub fn allocator(mut self, allocator: Arc<dyn ExecutableAllocator>) -> Self {
+        self.allocator = Some(allocator);
+        self
+    }
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn near_first(&self) -> bool {
+        match self.trampoline {
+            TrampolinePlacement::Default => near_trampoline_enabled(),
+            TrampolinePlacement::Anywhere => false,
+            TrampolinePlacement::Near => true,
+        }
+    }
+    pub(crate) fn resolved_allocator(&self) -> Arc<dyn ExecutableAllocator> {
+        self.allocator.clone().unwrap_or_else(executable_allocator)
+    }
+}
+
+impl core::fmt::Debug for HookOptions {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HookOptions")
+            .field("mode", &self.mode)
+            .field("trampoline", &self.trampoline)
+            .field("patch_style", &self.patch_style)
+            .field("verify_before_destroy", &self.verify_before_destroy)
+            .field("thread_suspension", &self.thread_suspension)
+            .field("allocator", &self.allocator.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
@@ -0,0 +1,161 @@
+//! CET (Control-flow Enforcement Technology) markings of loaded ELF images.
+
+use core::ffi::c_void;
+
+const PT_GNU_PROPERTY: u32 = 0x6474_E553;
+const NT_GNU_PROPERTY_TYPE_0: u32 = 5;
+const GNU_PROPERTY_X86_FEATURE_1_AND: u32 = 0xC000_0002;
+const GNU_PROPERTY_X86_FEATURE_1_IBT: u32 = 1;
+
+/// Whether the image containing `address` was built for indirect branch tracking (its
+/// `.note.gnu.property` has `GNU_PROPERTY_X86_FEATURE_1_IBT`). `false` outside any loaded image.
+pub(crate) unsafe fn ibt_marked(address: *const c_void) -> bool {
+    struct Search {
+        address: usize,
+        marked: bool,
+    }
+    unsafe extern "C" fn visit(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut c_void,
+    ) -> i32 {
+        let search = &mut *(data as *mut Search);
+        let info = &*info;
+        let base = info.dlpi_addr as usize;
+        let phdrs = core::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+        let contains = phdrs.iter().any(|p| {
+            let start = base + p.p_vaddr as usize;
+            p.p_type == libc::PT_LOAD
+                && (start..start + p.p_memsz as usize).contains(&search.address)
+        });
+        if !contains {
+            return 0;
+        }
+        // The property note is in its own PT_GNU_PROPERTY segment, and in a PT_NOTE one too.
+        search.marked = phdrs
+            .iter()
+            .filter(|p| p.p_type == PT_GNU_PROPERTY || p.p_type == libc::PT_NOTE)
+            .any(|p| {
+                let notes = core::slice::from_raw_parts(
+                    (base + p.p_vaddr as usize) as *const u8,
+                    p.p_memsz as usize,
+                );
+                notes_mark_ibt(notes, p.p_align as usize)
+            });
+        1
+    }
+    let mut search = Search {
+        address: address as usize,
+        marked: false,
+    };
+    libc::dl_iterate_phdr(Some(visit), &mut search as *mut Search as *mut c_void);
+    search.marked
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Whether the notes in a segment aligned to `align` include a GNU property note with the IBT
+/// feature bit.
+fn notes_mark_ibt(notes: &[u8], align: usize) -> bool {
+    let align = align.max(4);
+    let up = |n: usize| n.div_ceil(align) * align;
+    let mut at = 0;
+    while let (Some(namesz), Some(descsz), Some(kind)) = (
+        read_u32(notes, at),
+        read_u32(notes, at + 4),
+        read_u32(notes, at + 8),
+    ) {
+        let desc = up(at + 12 + namesz as usize);
+        let end = desc + descsz as usize;
+        let name = notes.get(at + 12..at + 12 + namesz as usize);
+        if kind == NT_GNU_PROPERTY_TYPE_0
+            && name == Some(b"GNU\0")
+            && notes.get(desc..end).is_some_and(properties_mark_ibt)
+        {
+            return true;
+        }
+        at = up(end);
+    }
+    false
+}
+
+// Properties are (type, size, data) with the data padded to 8 bytes.
+fn properties_mark_ibt(mut desc: &[u8]) -> bool {
+    while let (Some(kind), Some(size)) = (read_u32(desc, 0), read_u32(desc, 4)) {
+        if kind == GNU_PROPERTY_X86_FEATURE_1_AND {
+            return read_u32(desc, 8).is_some_and(|f| f & GNU_PROPERTY_X86_FEATURE_1_IBT != 0);
+        }
+        match desc.get(8 + (size as usize).div_ceil(8) * 8..) {
+            Some(rest) => desc = rest,
+            None => break,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(kind: u32, name: &[u8], desc: &[u8], align: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend((name.len() as u32).to_le_bytes());
+        out.extend((desc.len() as u32).to_le_bytes());
+        out.extend(kind.to_le_bytes());
+        out.extend(name);
+        out.resize(out.len().div_ceil(align) * align, 0);
+        out.extend(desc);
+        out.resize(out.len().div_ceil(align) * align, 0);
+        out
+    }
+
+    fn property(kind: u32, value: u32) -> Vec<u8> {
+        let mut out = kind.to_le_bytes().to_vec();
+        out.extend(4u32.to_le_bytes());
+        out.extend(value.to_le_bytes());
+        out.extend([0; 4]);
+        out
+    }
+
+    #[test]
+    fn finds_ibt_property() {
+        // ISA level, then feature_1_and with IBT | SHSTK
+        let mut desc = property(0xC000_8002, 1);
+        desc.extend(property(GNU_PROPERTY_X86_FEATURE_1_AND, 3));
+        let notes = note(NT_GNU_PROPERTY_TYPE_0, b"GNU\0", &desc, 8);
+        assert!(notes_mark_ibt(&notes, 8));
+
+        // Behind a 4-aligned build-id note whose 20-byte descriptor isn't a multiple of 8.
+        let mut notes = note(3, b"GNU\0", &[0xAB; 20], 4);
+        notes.extend(note(
+            NT_GNU_PROPERTY_TYPE_0,
+            b"GNU\0",
+            &property(GNU_PROPERTY_X86_FEATURE_1_AND, 1),
+            4,
+        ));
+        assert!(notes_mark_ibt(&notes, 4));
+    }
+
+    #[test]
+    fn other_notes_are_unmarked() {
+        // SHSTK only
+        let shstk = property(GNU_PROPERTY_X86_FEATURE_1_AND, 2);
+        assert!(!notes_mark_ibt(
+            &note(NT_GNU_PROPERTY_TYPE_0, b"GNU\0", &shstk, 8),
+            8
+        ));
+        let ibt = property(GNU_PROPERTY_X86_FEATURE_1_AND, 1);
+        assert!(!notes_mark_ibt(
+            &note(NT_GNU_PROPERTY_TYPE_0, b"XYZ\0", &ibt, 8),
+            8
+        ));
+        assert!(!notes_mark_ibt(&note(1, b"GNU\0", &ibt, 8), 8));
+        // Truncated
+        let notes = note(NT_GNU_PROPERTY_TYPE_0, b"GNU\0", &ibt, 8);
+        assert!(!notes_mark_ibt(&notes[..20], 8));
+        assert!(!unsafe { ibt_marked(core::ptr::null()) });
+    }
+}
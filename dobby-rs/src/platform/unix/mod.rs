@@ -0,0 +1,514 @@
+use crate::error::{Error, Result};
+use crate::options::PatchAtomicity;
+use core::ffi::c_void;
+use core::ptr;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use once_cell::sync::OnceCell;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    target_arch = "x86_64"
+))]
+pub(crate) mod cet;
+mod resolver;
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) mod signals;
+mod sys;
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) mod trap;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub(crate) mod watch;
+
+/// Whether breakpoint hooks ([`trap`]) are available on this target.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) const TRAP_HOOKS: bool = cfg!(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+));
+
+pub(crate) use resolver::{clear_symbol_cache, symbol_resolver};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno() -> i32 {
+    *libc::__errno_location()
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+unsafe fn errno() -> i32 {
+    *libc::__error()
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+unsafe fn errno() -> i32 {
+    0
+}
+
+pub(crate) unsafe fn page_size() -> usize {
+    sys::page_size()
+}
+
+pub(crate) unsafe fn page_align_down(addr: usize) -> usize {
+    addr & !(page_size() - 1)
+}
+pub(crate) unsafe fn page_align_up(addr: usize) -> usize {
+    (addr + page_size() - 1) & !(page_size() - 1)
+}
+
+pub(crate) unsafe fn alloc_executable(size: usize) -> Result<*mut c_void> {
+    sys::mmap(
+        ptr::null_mut(),
+        size,
+        libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+        libc::MAP_PRIVATE | libc::MAP_ANON,
+        -1,
+        0,
+    )
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAP_FIXED_NOREPLACE: i32 = 0x10_0000;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const MAP_FIXED_NOREPLACE: i32 = 0;
+
+/// Allocate within `range` bytes of `pos`, or `Ok(None)` if no free slot was found.
+///
+/// Probes outward from `pos` in 64KB steps. `MAP_FIXED_NOREPLACE` makes occupied slots fail fast on
+/// Linux; elsewhere (and on kernels that ignore the flag) the address is only a hint, so anything
+/// that lands out of range is unmapped again.
+pub(crate) unsafe fn alloc_executable_near(
+    size: usize,
+    pos: usize,
+    range: usize,
+) -> Result<Option<*mut c_void>> {
+    const STEP: usize = 0x10000;
+    let size = page_align_up(size);
+    let base = pos & !(STEP - 1);
+    let max_steps = (range / STEP).min(0x1_0000);
+    let in_range = |p: usize| p.abs_diff(pos) <= range && (p + size).abs_diff(pos) <= range;
+
+    for i in 1..=max_steps {
+        let off = i * STEP;
+        for hint in [base.checked_add(off), base.checked_sub(off)]
+            .into_iter()
+            .flatten()
+        {
+            let Ok(p) = sys::mmap(
+                hint as *mut c_void,
+                size,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANON | MAP_FIXED_NOREPLACE,
+                -1,
+                0,
+            ) else {
+                continue;
+            };
+            if in_range(p as usize) {
+                return Ok(Some(p));
+            }
+            let _ = sys::munmap(p, size);
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) unsafe fn free_executable(ptr: *mut c_void, size: usize) -> Result<()> {
+    if ptr.is_null() {
+        return Ok(());
+    }
+    sys::munmap(ptr, size)
+}
+
+pub(crate) unsafe fn with_rwx(
+    address: *mut c_void,
+    size: usize,
+    f: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let start = page_align_down(address as usize) as *mut c_void;
+    let end = page_align_up(address as usize + size);
+    let len = end - start as usize;
+    sys::mprotect(
+        start,
+        len,
+        libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+    )?;
+    let r = f();
+    let _ = sys::mprotect(start, len, libc::PROT_READ | libc::PROT_EXEC);
+    r
+}
+
+#[allow(dead_code)]
+pub(crate) unsafe fn with_writeable(
+    address: *mut c_void,
+    size: usize,
+    f: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let start = page_align_down(address as usize) as *mut c_void;
+    let end = page_align_up(address as usize + size);
+    let len = end - start as usize;
+    sys::mprotect(
+        start,
+        len,
+        libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+    )?;
+    let r = f();
+    let _ = sys::mprotect(start, len, libc::PROT_READ | libc::PROT_EXEC);
+    r
+}
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+unsafe extern "C" {
+    fn __clear_cache(begin: *mut u8, end: *mut u8);
+}
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+pub(crate) unsafe fn flush_icache(address: *mut c_void, size: usize) {
+    __clear_cache(address as *mut u8, (address as *mut u8).add(size));
+}
+
+#[cfg(target_arch = "riscv64")]
+unsafe extern "C" {
+    fn __riscv_flush_icache(
+        start: *mut c_void,
+        end: *mut c_void,
+        flags: libc::c_ulong,
+    ) -> libc::c_int;
+}
+
+// Flags 0: make the stores visible to every hart, not just the calling thread's.
+#[cfg(target_arch = "riscv64")]
+pub(crate) unsafe fn flush_icache(address: *mut c_void, size: usize) {
+    __riscv_flush_icache(address, (address as *mut u8).add(size) as *mut c_void, 0);
+}
+
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64")))]
+pub(crate) unsafe fn flush_icache(_address: *mut c_void, _size: usize) {}
+
+/// Copy `bytes` over code at `address`, making the pages writable for the duration.
+///
+/// On Linux, when `mprotect` is refused (SELinux `execmod`, seccomp), fall back to writing through
+/// `/proc/self/mem`.
+unsafe fn write_bytes(address: *mut c_void, bytes: &[u8]) -> PatchAtomicity {
+    #[cfg(target_arch = "x86_64")]
+    {
+        crate::arch::x86_64::write_code(address as *mut u8, bytes)
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), address as *mut u8, bytes.len());
+        PatchAtomicity::NonAtomic
+    }
+}
+
+unsafe fn write_code(address: *mut c_void, bytes: &[u8]) -> Result<PatchAtomicity> {
+    let mut atomicity = PatchAtomicity::NonAtomic;
+    let r = with_rwx(address, bytes.len(), || {
+        atomicity = write_bytes(address, bytes);
+        Ok(())
+    });
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Err(Error::Unix(libc::EACCES | libc::EPERM)) = r {
+        return sys::write_proc_mem(address, bytes).map(|()| PatchAtomicity::NonAtomic);
+    }
+    r.map(|()| atomicity)
+}
+
+pub(crate) unsafe fn code_patch(
+    address: *mut c_void,
+    buffer: *const u8,
+    size: usize,
+) -> Result<PatchAtomicity> {
+    let atomicity = write_code(address, core::slice::from_raw_parts(buffer, size))?;
+    flush_icache(address, size);
+    Ok(atomicity)
+}
+
+pub(crate) unsafe fn restore_patch(
+    address: *mut c_void,
+    original: &[u8],
+    patch_len: usize,
+) -> Result<()> {
+    if patch_len != original.len() {
+        return Err(Error::PatchTooSmall);
+    }
+    write_code(address, original)?;
+    flush_icache(address, patch_len);
+    Ok(())
+}
+
+/// Other threads of the process, parked in a signal handler until this value is dropped.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) struct SuspendedThreads {
+    _lock: MutexGuard<'static, ()>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+static SUSPEND_LOCK: Mutex<()> = Mutex::new(());
+#[cfg(any(target_os = "linux", target_os = "android"))]
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+#[cfg(any(target_os = "linux", target_os = "android"))]
+static RELEASED: AtomicBool = AtomicBool::new(true);
+// Where each parked thread was interrupted, recorded before it counts as parked. More threads
+// than slots make every range look occupied.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAX_PARKED_PCS: usize = 1024;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+static PARKED_PCS: [AtomicUsize; MAX_PARKED_PCS] = [const { AtomicUsize::new(0) }; MAX_PARKED_PCS];
+#[cfg(any(target_os = "linux", target_os = "android"))]
+static PARKED_PC_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// A real-time signal from the top of the range, away from the ones libc and runtimes reserve.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn park_signal() -> i32 {
+    libc::SIGRTMAX() - 2
+}
+
+/// Program counter saved in a signal context.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn context_pc(uc: *const libc::ucontext_t) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    return (*uc).uc_mcontext.gregs[libc::REG_RIP as usize] as usize;
+    #[cfg(target_arch = "x86")]
+    return (*uc).uc_mcontext.gregs[libc::REG_EIP as usize] as usize;
+    #[cfg(target_arch = "aarch64")]
+    return (*uc).uc_mcontext.pc as usize;
+    #[cfg(target_arch = "arm")]
+    return (*uc).uc_mcontext.arm_pc as usize;
+    #[cfg(target_arch = "riscv64")]
+    return (*uc).uc_mcontext.__gregs[0] as usize;
+    #[allow(unreachable_code)]
+    0
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+extern "C" fn park_handler(_sig: i32, _info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    let slot = PARKED_PC_COUNT.fetch_add(1, Ordering::SeqCst);
+    if let Some(pc) = PARKED_PCS.get(slot) {
+        pc.store(
+            unsafe { context_pc(ctx as *const libc::ucontext_t) },
+            Ordering::SeqCst,
+        );
+    }
+    PARKED.fetch_add(1, Ordering::SeqCst);
+    while !RELEASED.load(Ordering::Acquire) {
+        unsafe { libc::sched_yield() };
+    }
+    PARKED.fetch_sub(1, Ordering::SeqCst);
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl SuspendedThreads {
+    /// Whether a parked thread was interrupted strictly inside `start..start + len`, where it would
+    /// resume in the middle of whatever is written there.
+    pub(crate) fn stopped_inside(&self, start: usize, len: usize) -> bool {
+        let count = PARKED_PC_COUNT.load(Ordering::SeqCst);
+        count > MAX_PARKED_PCS
+            || PARKED_PCS[..count]
+                .iter()
+                .map(|pc| pc.load(Ordering::SeqCst))
+                .any(|pc| pc > start && pc - start < len)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for SuspendedThreads {
+    fn drop(&mut self) {
+        RELEASED.store(true, Ordering::Release);
+        while PARKED.load(Ordering::SeqCst) != 0 {
+            unsafe { libc::sched_yield() };
+        }
+    }
+}
+
+/// Stop every other thread by signalling it into a spin-wait handler.
+///
+/// Best effort: threads that block the signal make this time out, and threads created after the
+/// task list was read keep running. The handler stays installed so late signals are harmless.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) unsafe fn suspend_other_threads() -> Result<SuspendedThreads> {
+    static INSTALLED: OnceCell<i32> = OnceCell::new();
+    let install_err = *INSTALLED.get_or_init(|| {
+        let mut sa: libc::sigaction = core::mem::zeroed();
+        sa.sa_sigaction = park_handler as *const () as usize;
+        sa.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+        libc::sigfillset(&mut sa.sa_mask);
+        if libc::sigaction(park_signal(), &sa, ptr::null_mut()) != 0 {
+            errno()
+        } else {
+            0
+        }
+    });
+    if install_err != 0 {
+        return Err(Error::Unix(install_err));
+    }
+
+    let lock = SUSPEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let me = libc::syscall(libc::SYS_gettid) as i32;
+    let tids: Vec<i32> = std::fs::read_dir("/proc/self/task")
+        .map_err(|e| Error::Unix(e.raw_os_error().unwrap_or(0)))?
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|tid| *tid != me)
+        .collect();
+
+    PARKED_PC_COUNT.store(0, Ordering::SeqCst);
+    RELEASED.store(false, Ordering::Release);
+    let guard = SuspendedThreads { _lock: lock };
+    let pid = libc::getpid();
+    let mut sent = 0usize;
+    for tid in tids {
+        if libc::syscall(libc::SYS_tgkill, pid, tid, park_signal()) == 0 {
+            sent += 1;
+        }
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+    while PARKED.load(Ordering::SeqCst) < sent {
+        if std::time::Instant::now() > deadline {
+            return Err(Error::Unix(libc::ETIMEDOUT));
+        }
+        libc::sched_yield();
+    }
+    Ok(guard)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) struct SuspendedThreads;
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+impl SuspendedThreads {
+    pub(crate) fn stopped_inside(&self, _start: usize, _len: usize) -> bool {
+        false
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) unsafe fn suspend_other_threads() -> Result<SuspendedThreads> {
+    Err(Error::UnsupportedPlatform)
+}
+
+#[cfg(all(
+    test,
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod tests {
+    use super::*;
+    use crate::engine;
+    use crate::options::{HookOptions, ThreadSuspension};
+    use core::cell::Cell;
+    use core::sync::atomic::AtomicUsize;
+
+    type MprotectFn = unsafe extern "C" fn(*mut c_void, usize, i32) -> i32;
+    static ORIGINAL: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static CALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe extern "C" fn mprotect_detour(addr: *mut c_void, len: usize, prot: i32) -> i32 {
+        CALLS.with(|c| c.set(c.get() + 1));
+        // Other test threads can get here before the trampoline is published.
+        match ORIGINAL.load(Ordering::SeqCst) {
+            0 => libc::syscall(libc::SYS_mprotect, addr, len, prot) as i32,
+            original => core::mem::transmute::<usize, MprotectFn>(original)(addr, len, prot),
+        }
+    }
+
+    #[inline(never)]
+    fn victim(x: i32) -> i32 {
+        core::hint::black_box(x) ^ 0x55
+    }
+
+    #[inline(never)]
+    fn victim_detour(x: i32) -> i32 {
+        x + 1
+    }
+
+    #[test]
+    fn hooking_mprotect_does_not_reenter_engine() {
+        let target = libc::mprotect as *mut c_void;
+        // Other test threads may call mprotect (thread stacks); keep them out of the half-written
+        // patch.
+        let options = HookOptions::new().thread_suspension(ThreadSuspension::Others);
+        unsafe {
+            let tramp = engine::hook_with_options(
+                target,
+                mprotect_detour as *const () as *mut c_void,
+                &options,
+            )
+            .expect("hook mprotect");
+            ORIGINAL.store(tramp as usize, Ordering::SeqCst);
+
+            // The detour works and forwards to the original.
+            let page = alloc_executable(page_size()).unwrap();
+            assert_eq!(libc::mprotect(page, page_size(), libc::PROT_READ), 0);
+            assert_eq!(CALLS.with(Cell::get), 1);
+            free_executable(page, page_size()).unwrap();
+
+            // Installing and removing another hook must not go through the detour.
+            let address = victim as *const () as *mut c_void;
+            engine::hook(address, victim_detour as *const () as *mut c_void).expect("hook");
+            assert_eq!(core::hint::black_box(victim as fn(i32) -> i32)(1), 2);
+            engine::destroy(address).expect("destroy");
+            assert_eq!(CALLS.with(Cell::get), 1);
+
+            engine::destroy(target).expect("destroy mprotect hook");
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn parked_threads_report_where_they_stopped() {
+        unsafe {
+            let size = page_size();
+            let p = alloc_executable(size).unwrap() as *mut u8;
+            // loop: 7 x nop; jmp loop
+            let code = [0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0xEB, 0xF7];
+            ptr::copy_nonoverlapping(code.as_ptr(), p, code.len());
+            let spin: extern "C" fn() = core::mem::transmute(p);
+            let spinner = std::thread::spawn(move || spin());
+            std::thread::sleep(std::time::Duration::from_millis(50));
+
+            let start = p as usize;
+            let suspended = suspend_other_threads().expect("suspend");
+            assert!(suspended.stopped_inside(start - 1, code.len() + 1));
+            assert!(!suspended.stopped_inside(start + code.len(), 16));
+            drop(suspended);
+
+            ptr::write_volatile(p, 0xC3);
+            spinner.join().unwrap();
+            free_executable(p as *mut c_void, size).unwrap();
+        }
+    }
+
+    #[test]
+    fn proc_mem_writes_read_only_pages() {
+        unsafe {
+            let size = page_size();
+            let page = sys::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            )
+            .unwrap();
+            sys::write_proc_mem(page, &[0xAA, 0xBB, 0xCC]).expect("write through /proc/self/mem");
+            assert_eq!(*(page as *const [u8; 3]), [0xAA, 0xBB, 0xCC]);
+            sys::munmap(page, size).unwrap();
+        }
+    }
+}
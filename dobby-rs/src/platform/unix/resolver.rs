@@ -0,0 +1,164 @@
+use core::ffi::{CStr, c_char, c_void};
+use core::ptr;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::sync::Mutex;
+
+/// Resolved addresses keyed by (image, symbol). Only hits are cached, and the whole cache is
+/// dropped as soon as the loader reports that any module was unloaded.
+#[derive(Default)]
+struct Cache {
+    unloads: Option<u64>,
+    entries: HashMap<(Option<CString>, CString), usize>,
+}
+
+static CACHE: OnceCell<Mutex<Cache>> = OnceCell::new();
+fn cache() -> &'static Mutex<Cache> {
+    CACHE.get_or_init(|| Mutex::new(Cache::default()))
+}
+
+/// Loader unload counter (`dlpi_subs`), or `None` when the loader doesn't provide one.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn unload_count() -> Option<u64> {
+    unsafe extern "C" fn first(
+        info: *mut libc::dl_phdr_info,
+        size: usize,
+        data: *mut c_void,
+    ) -> i32 {
+        // Older loaders pass a shorter struct without the counters.
+        let end = core::mem::offset_of!(libc::dl_phdr_info, dlpi_subs)
+            + core::mem::size_of::<libc::c_ulonglong>();
+        if size >= end {
+            *(data as *mut Option<u64>) = Some((*info).dlpi_subs);
+        }
+        // The counters are process-wide; the first module is enough.
+        1
+    }
+    let mut subs: Option<u64> = None;
+    unsafe { libc::dl_iterate_phdr(Some(first), &mut subs as *mut Option<u64> as *mut c_void) };
+    subs
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn unload_count() -> Option<u64> {
+    None
+}
+
+/// Drop every cached symbol address.
+pub(crate) fn clear_symbol_cache() {
+    let mut cache = cache().lock().unwrap();
+    cache.entries.clear();
+    cache.unloads = None;
+}
+
+/// Resolve `symbol_name` in `image_name` (or the global scope when null).
+///
+/// Images are looked up with `RTLD_NOLOAD` and the reference is dropped again, so resolving never
+/// loads a library or pins it. With `load`, a missing image is loaded and stays loaded.
+pub(crate) unsafe fn symbol_resolver(
+    image_name: *const c_char,
+    symbol_name: *const c_char,
+    load: bool,
+) -> *mut c_void {
+    if symbol_name.is_null() {
+        return ptr::null_mut();
+    }
+    let key = (
+        (!image_name.is_null()).then(|| CStr::from_ptr(image_name).to_owned()),
+        CStr::from_ptr(symbol_name).to_owned(),
+    );
+    let unloads = unload_count();
+    if unloads.is_some() {
+        let mut cache = cache().lock().unwrap();
+        if cache.unloads != unloads {
+            cache.entries.clear();
+            cache.unloads = unloads;
+        }
+        if let Some(&p) = cache.entries.get(&key) {
+            return p as *mut c_void;
+        }
+    }
+
+    let p = lookup(image_name, symbol_name, load);
+    if !p.is_null() && unloads.is_some() {
+        let mut cache = cache().lock().unwrap();
+        // Skip the insert if a module went away while we were resolving.
+        if cache.unloads == unloads {
+            cache.entries.insert(key, p as usize);
+        }
+    }
+    p
+}
+
+unsafe fn lookup(image_name: *const c_char, symbol_name: *const c_char, load: bool) -> *mut c_void {
+    if image_name.is_null() {
+        return dlsym(libc::RTLD_DEFAULT, symbol_name);
+    }
+    let handle = dlopen(image_name, libc::RTLD_NOW | libc::RTLD_NOLOAD);
+    if !handle.is_null() {
+        let p = dlsym(handle, symbol_name);
+        dlclose(handle);
+        return p;
+    }
+    if !load {
+        return ptr::null_mut();
+    }
+    // Explicit load: keep this reference so the returned address stays valid.
+    let handle = dlopen(image_name, libc::RTLD_NOW);
+    if handle.is_null() {
+        return ptr::null_mut();
+    }
+    dlsym(handle, symbol_name)
+}
+
+// The dl* functions can't be replaced by raw syscalls. If they are hooked through this engine,
+// call the original code via the trampoline so symbol resolution never re-enters the user's detour.
+unsafe fn dlopen(filename: *const c_char, flags: i32) -> *mut c_void {
+    type DlopenFn = unsafe extern "C" fn(*const c_char, i32) -> *mut c_void;
+    let f: DlopenFn = match crate::engine::hooked_original(libc::dlopen as *mut c_void) {
+        Some(t) => core::mem::transmute::<*mut c_void, DlopenFn>(t),
+        None => libc::dlopen,
+    };
+    f(filename, flags)
+}
+
+unsafe fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void {
+    type DlsymFn = unsafe extern "C" fn(*mut c_void, *const c_char) -> *mut c_void;
+    let f: DlsymFn = match crate::engine::hooked_original(libc::dlsym as *mut c_void) {
+        Some(t) => core::mem::transmute::<*mut c_void, DlsymFn>(t),
+        None => libc::dlsym,
+    };
+    f(handle, symbol)
+}
+
+unsafe fn dlclose(handle: *mut c_void) {
+    type DlcloseFn = unsafe extern "C" fn(*mut c_void) -> i32;
+    let f: DlcloseFn = match crate::engine::hooked_original(libc::dlclose as *mut c_void) {
+        Some(t) => core::mem::transmute::<*mut c_void, DlcloseFn>(t),
+        None => libc::dlclose,
+    };
+    f(handle);
+}
+
+#[cfg(all(test, target_os = "linux", target_env = "gnu"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_lookup_without_loading() {
+        unsafe {
+            let a = symbol_resolver(c"libc.so.6".as_ptr(), c"getpid".as_ptr(), false);
+            assert_eq!(a, libc::getpid as *mut c_void);
+            let key = (Some(c"libc.so.6".to_owned()), c"getpid".to_owned());
+            assert!(cache().lock().unwrap().entries.contains_key(&key));
+            assert_eq!(
+                symbol_resolver(c"libc.so.6".as_ptr(), c"getpid".as_ptr(), false),
+                a
+            );
+            // Never loaded, so not found without an explicit load.
+            let missing = c"libdobby-rs-not-loaded.so";
+            assert!(symbol_resolver(missing.as_ptr(), c"f".as_ptr(), false).is_null());
+        }
+    }
+}
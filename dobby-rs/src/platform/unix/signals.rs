@@ -0,0 +1,322 @@
+//! Signal multiplexer for the engine's fault-based features.
+//!
+//! One dispatcher is installed per signal. It offers the signal to the engine's consumers in
+//! registration order and forwards anything they don't claim to the handler that was installed
+//! before (the application's, a runtime's or a crash reporter's), honouring its `SA_SIGINFO`,
+//! `sa_mask`, `SA_NODEFER` and `SA_RESETHAND` flags.
+//!
+//! `sigaction` itself is hooked, so handlers installed later for a multiplexed signal don't
+//! replace the dispatcher: they become the new "previous" action and still receive every signal
+//! the engine doesn't consume.
+
+use crate::error::{Error, Result};
+use core::cell::UnsafeCell;
+use core::ffi::c_void;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use once_cell::sync::OnceCell;
+use std::sync::Mutex;
+
+/// Returns `true` if the signal was handled and execution may resume.
+pub(crate) type Consumer =
+    unsafe fn(sig: i32, info: *mut libc::siginfo_t, ctx: *mut c_void) -> bool;
+
+const SIGNALS: [i32; 4] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGTRAP, libc::SIGILL];
+const MAX_CONSUMERS: usize = 8;
+// Buffers a slot's previous action rotates through.
+const ACTIONS: usize = 8;
+
+struct Slot {
+    installed: AtomicBool,
+    consumers: [AtomicUsize; MAX_CONSUMERS],
+    // One of `actions` or `DEFAULT_ACTION`. A handler may still be copying the old action when it
+    // is replaced, so a buffer is only rewritten `ACTIONS` replacements later.
+    previous: AtomicPtr<libc::sigaction>,
+    actions: [UnsafeCell<libc::sigaction>; ACTIONS],
+    next: AtomicUsize,
+}
+
+// `actions` are only written through `set_previous`, into a buffer `previous` no longer points
+// at.
+unsafe impl Sync for Slot {}
+
+static SLOTS: [Slot; SIGNALS.len()] = [const {
+    Slot {
+        installed: AtomicBool::new(false),
+        consumers: [const { AtomicUsize::new(0) }; MAX_CONSUMERS],
+        previous: AtomicPtr::new(ptr::null_mut()),
+        actions: [const { UnsafeCell::new(unsafe { core::mem::zeroed() }) }; ACTIONS],
+        next: AtomicUsize::new(0),
+    }
+}; SIGNALS.len()];
+
+impl Slot {
+    /// Make `action` the previous action, in the next buffer, and return the one it replaced.
+    /// Async-signal-safe: nothing is allocated.
+    fn set_previous(&self, action: &libc::sigaction) -> *mut libc::sigaction {
+        let buf = self.actions[self.next.fetch_add(1, Ordering::Relaxed) % ACTIONS].get();
+        unsafe { buf.write(*action) };
+        self.previous.swap(buf, Ordering::AcqRel)
+    }
+}
+
+static INSTALL_LOCK: Mutex<()> = Mutex::new(());
+
+// `SIG_DFL`, for a forwarded `SA_RESETHAND` action; allocating in the handler isn't safe.
+static DEFAULT_ACTION: libc::sigaction = unsafe { core::mem::zeroed() };
+
+fn slot(sig: i32) -> Option<&'static Slot> {
+    SIGNALS.iter().position(|&s| s == sig).map(|i| &SLOTS[i])
+}
+
+type SigactionFn = unsafe extern "C" fn(i32, *const libc::sigaction, *mut libc::sigaction) -> i32;
+
+// `sigaction` bypassing our own interposition.
+unsafe fn real_sigaction(sig: i32, act: *const libc::sigaction, old: *mut libc::sigaction) -> i32 {
+    let f: SigactionFn = match crate::engine::hooked_original(libc::sigaction as *mut c_void) {
+        Some(t) => core::mem::transmute::<*mut c_void, SigactionFn>(t),
+        None => libc::sigaction,
+    };
+    f(sig, act, old)
+}
+
+/// Route `sig` through the dispatcher and offer it to `consumer` (after earlier consumers).
+pub(crate) unsafe fn add_consumer(sig: i32, consumer: Consumer) -> Result<()> {
+    let slot = slot(sig).ok_or(Error::InvalidInput)?;
+    let _lock = INSTALL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if !slot
+        .consumers
+        .iter()
+        .any(|c| c.load(Ordering::Acquire) == consumer as usize)
+    {
+        slot.consumers
+            .iter()
+            .find(|c| c.load(Ordering::Acquire) == 0)
+            .ok_or(Error::Unix(libc::ENOSPC))?
+            .store(consumer as usize, Ordering::Release);
+    }
+
+    // Also re-installs after a forwarded signal reset the action to `SIG_DFL`.
+    if !slot.installed.load(Ordering::Acquire) {
+        let mut sa: libc::sigaction = core::mem::zeroed();
+        sa.sa_sigaction = dispatch as *const () as usize;
+        sa.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+        let mut old: libc::sigaction = core::mem::zeroed();
+        if real_sigaction(sig, &sa, &mut old) != 0 {
+            return Err(Error::Unix(super::errno()));
+        }
+        slot.set_previous(&old);
+        slot.installed.store(true, Ordering::Release);
+    }
+    drop(_lock);
+    interpose_sigaction();
+    Ok(())
+}
+
+unsafe extern "C" fn dispatch(sig: i32, info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    let Some(slot) = slot(sig) else {
+        return;
+    };
+    for c in slot.consumers.iter() {
+        match c.load(Ordering::Acquire) {
+            0 => break,
+            f => {
+                let consumer: Consumer = core::mem::transmute(f);
+                if consumer(sig, info, ctx) {
+                    return;
+                }
+            }
+        }
+    }
+    let previous = slot.previous.load(Ordering::Acquire);
+    if !previous.is_null() {
+        // Copied right away: the buffer may be reused once the action is replaced again.
+        let previous = *previous;
+        forward(&previous, slot, sig, info, ctx);
+    }
+}
+
+// Run the previous action the way the kernel would have.
+unsafe fn forward(
+    previous: &libc::sigaction,
+    slot: &Slot,
+    sig: i32,
+    info: *mut libc::siginfo_t,
+    ctx: *mut c_void,
+) {
+    match previous.sa_sigaction {
+        libc::SIG_IGN => {}
+        libc::SIG_DFL => {
+            let mut dfl: libc::sigaction = core::mem::zeroed();
+            dfl.sa_sigaction = libc::SIG_DFL;
+            real_sigaction(sig, &dfl, ptr::null_mut());
+            slot.installed.store(false, Ordering::Release);
+            // A synchronous fault re-triggers when we return; anything else has to be sent again.
+            let fault = matches!(
+                sig,
+                libc::SIGSEGV | libc::SIGBUS | libc::SIGILL | libc::SIGFPE
+            );
+            if !fault || (*info).si_code <= 0 {
+                libc::raise(sig);
+            }
+        }
+        f => {
+            let uc = ctx as *mut libc::ucontext_t;
+            let mut mask = (*uc).uc_sigmask;
+            for s in 1..libc::SIGRTMAX() + 1 {
+                if libc::sigismember(&previous.sa_mask, s) == 1 {
+                    libc::sigaddset(&mut mask, s);
+                }
+            }
+            if previous.sa_flags & libc::SA_NODEFER == 0 {
+                libc::sigaddset(&mut mask, sig);
+            } else {
+                libc::sigdelset(&mut mask, sig);
+            }
+            let mut saved: libc::sigset_t = core::mem::zeroed();
+            libc::pthread_sigmask(libc::SIG_SETMASK, &mask, &mut saved);
+            if previous.sa_flags & libc::SA_RESETHAND != 0 {
+                slot.previous.store(
+                    &DEFAULT_ACTION as *const libc::sigaction as *mut libc::sigaction,
+                    Ordering::Release,
+                );
+            }
+            if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                let f: extern "C" fn(i32, *mut libc::siginfo_t, *mut c_void) =
+                    core::mem::transmute(f);
+                f(sig, info, ctx);
+            } else {
+                let f: extern "C" fn(i32) = core::mem::transmute(f);
+                f(sig);
+            }
+            libc::pthread_sigmask(libc::SIG_SETMASK, &saved, ptr::null_mut());
+        }
+    }
+}
+
+// Hook `sigaction` so later installs for multiplexed signals land behind the dispatcher. Best
+// effort: if the hook can't be installed, a later handler simply takes over as before.
+fn interpose_sigaction() {
+    static INTERPOSED: OnceCell<bool> = OnceCell::new();
+    INTERPOSED.get_or_init(|| unsafe {
+        crate::engine::hook(
+            libc::sigaction as *mut c_void,
+            sigaction_detour as *const () as *mut c_void,
+        )
+        .is_ok()
+    });
+}
+
+unsafe extern "C" fn sigaction_detour(
+    sig: i32,
+    act: *const libc::sigaction,
+    old: *mut libc::sigaction,
+) -> i32 {
+    let Some(slot) = slot(sig).filter(|s| s.installed.load(Ordering::Acquire)) else {
+        return real_sigaction(sig, act, old);
+    };
+    // No lock or allocation: this may run in a signal handler, possibly one that interrupted
+    // `add_consumer`. Swapping the pointer reports exactly the action it replaced.
+    let previous = if act.is_null() {
+        slot.previous.load(Ordering::Acquire)
+    } else {
+        slot.set_previous(&*act)
+    };
+    if !old.is_null() {
+        *old = if previous.is_null() {
+            core::mem::zeroed()
+        } else {
+            *previous
+        };
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicI32;
+
+    static SEEN_BY_CONSUMER: AtomicI32 = AtomicI32::new(0);
+    static SEEN_BY_APP: AtomicI32 = AtomicI32::new(0);
+
+    unsafe fn consumer(_sig: i32, info: *mut libc::siginfo_t, _ctx: *mut c_void) -> bool {
+        SEEN_BY_CONSUMER.fetch_add(1, Ordering::SeqCst);
+        // Claim only signals carrying our marker value.
+        (*info).si_value().sival_ptr as usize == 0x5eed
+    }
+
+    extern "C" fn app_handler(_sig: i32) {
+        SEEN_BY_APP.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn app_handler_installed_later_is_chained() {
+        unsafe {
+            add_consumer(libc::SIGILL, consumer).unwrap();
+            // The application replaces the handler afterwards, through the hooked `sigaction`.
+            let mut sa: libc::sigaction = core::mem::zeroed();
+            sa.sa_sigaction = app_handler as *const () as usize;
+            assert_eq!(libc::sigaction(libc::SIGILL, &sa, ptr::null_mut()), 0);
+            let mut current: libc::sigaction = core::mem::zeroed();
+            real_sigaction(libc::SIGILL, ptr::null(), &mut current);
+            assert_eq!(current.sa_sigaction, dispatch as *const () as usize);
+
+            let tid = libc::syscall(libc::SYS_gettid);
+            let send = |value: usize| {
+                let mut si: libc::siginfo_t = core::mem::zeroed();
+                let raw = &mut si as *mut libc::siginfo_t as *mut i32;
+                // si_signo, si_errno, si_code, padding, then si_pid, si_uid and si_value.
+                *raw = libc::SIGILL;
+                *raw.add(2) = -1; // SI_QUEUE
+                *raw.add(4) = libc::getpid();
+                *raw.add(5) = libc::getuid() as i32;
+                *(raw.add(6) as *mut usize) = value;
+                libc::syscall(
+                    libc::SYS_rt_tgsigqueueinfo,
+                    libc::getpid(),
+                    tid,
+                    libc::SIGILL,
+                    &si,
+                );
+            };
+            send(0x5eed);
+            assert_eq!(SEEN_BY_CONSUMER.load(Ordering::SeqCst), 1);
+            assert_eq!(SEEN_BY_APP.load(Ordering::SeqCst), 0);
+            send(1);
+            assert_eq!(SEEN_BY_CONSUMER.load(Ordering::SeqCst), 2);
+            assert_eq!(SEEN_BY_APP.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn replaced_actions_are_reported_back() {
+        unsafe {
+            add_consumer(libc::SIGBUS, consumer).unwrap();
+            let mut before: libc::sigaction = core::mem::zeroed();
+            assert_eq!(libc::sigaction(libc::SIGBUS, ptr::null(), &mut before), 0);
+            // Save and restore around each "probe", more often than there are buffers.
+            let mut current = before;
+            for i in 0..3 * ACTIONS as i32 {
+                let mut sa: libc::sigaction = core::mem::zeroed();
+                sa.sa_sigaction = app_handler as *const () as usize;
+                libc::sigaddset(&mut sa.sa_mask, 1 + i % 30);
+                let mut old: libc::sigaction = core::mem::zeroed();
+                assert_eq!(libc::sigaction(libc::SIGBUS, &sa, &mut old), 0);
+                assert_eq!(old.sa_sigaction, current.sa_sigaction);
+                for s in 1..32 {
+                    assert_eq!(
+                        libc::sigismember(&old.sa_mask, s),
+                        libc::sigismember(&current.sa_mask, s)
+                    );
+                }
+                current = sa;
+            }
+            assert_eq!(libc::sigaction(libc::SIGBUS, &before, ptr::null_mut()), 0);
+            let mut installed: libc::sigaction = core::mem::zeroed();
+            real_sigaction(libc::SIGBUS, ptr::null(), &mut installed);
+            assert_eq!(installed.sa_sigaction, dispatch as *const () as usize);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Memory-management system calls that bypass libc.
+//!
+//! Memory tracers routinely hook `mmap`/`mprotect`/`munmap`. If the engine went through the libc
+//! wrappers, installing the next hook would run through those detours (or through a half-written
+//! patch). On Linux/Android x86_64 and aarch64 the calls are issued with `syscall`/`svc` directly,
+//! and on riscv64, i686 and 32-bit ARM (`mmap2` on both) through libc's generic `syscall`
+//! function; other targets use the libc wrappers.
+
+use crate::error::Result;
+use core::ffi::c_void;
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod raw {
+    use crate::error::{Error, Result};
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn syscall6(n: usize, a: [usize; 6]) -> isize {
+        let ret: isize;
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") n as isize => ret,
+            in("rdi") a[0],
+            in("rsi") a[1],
+            in("rdx") a[2],
+            in("r10") a[3],
+            in("r8") a[4],
+            in("r9") a[5],
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+        ret
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe fn syscall6(n: usize, a: [usize; 6]) -> isize {
+        let ret: isize;
+        core::arch::asm!(
+            "svc 0",
+            in("x8") n,
+            inlateout("x0") a[0] as isize => ret,
+            in("x1") a[1],
+            in("x2") a[2],
+            in("x3") a[3],
+            in("x4") a[4],
+            in("x5") a[5],
+            options(nostack),
+        );
+        ret
+    }
+
+    /// Issue syscall `n`; the kernel reports failures as `-errno` in the last page of the range.
+    pub(super) unsafe fn call(n: libc::c_long, a: [usize; 6]) -> Result<usize> {
+        let ret = syscall6(n as usize, a);
+        if (-4095..0).contains(&ret) {
+            return Err(Error::Unix(-ret as i32));
+        }
+        Ok(ret as usize)
+    }
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+pub(crate) unsafe fn mmap(
+    addr: *mut c_void,
+    len: usize,
+    prot: i32,
+    flags: i32,
+    fd: i32,
+    offset: i64,
+) -> Result<*mut c_void> {
+    let args = [
+        addr as usize,
+        len,
+        prot as usize,
+        flags as usize,
+        fd as isize as usize,
+        offset as usize,
+    ];
+    syscall(libc::SYS_mmap, args).map(|p| p as *mut c_void)
+}
+
+/// `mmap2` takes the offset in 4096-byte units, whatever the page size.
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86", target_arch = "arm")
+))]
+pub(crate) unsafe fn mmap(
+    addr: *mut c_void,
+    len: usize,
+    prot: i32,
+    flags: i32,
+    fd: i32,
+    offset: i64,
+) -> Result<*mut c_void> {
+    if offset < 0 || offset % 4096 != 0 {
+        return Err(crate::error::Error::Unix(libc::EINVAL));
+    }
+    let args = [
+        addr as usize,
+        len,
+        prot as usize,
+        flags as usize,
+        fd as isize as usize,
+        (offset / 4096) as usize,
+    ];
+    syscall(libc::SYS_mmap2, args).map(|p| p as *mut c_void)
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "x86",
+        target_arch = "arm"
+    )
+))]
+pub(crate) unsafe fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> Result<()> {
+    syscall(
+        libc::SYS_mprotect,
+        [addr as usize, len, prot as usize, 0, 0, 0],
+    )
+    .map(|_| ())
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "x86",
+        target_arch = "arm"
+    )
+))]
+pub(crate) unsafe fn munmap(addr: *mut c_void, len: usize) -> Result<()> {
+    syscall(libc::SYS_munmap, [addr as usize, len, 0, 0, 0, 0]).map(|_| ())
+}
+
+#[cfg(not(all(
+    any(target_os = "linux", target_os = "android"),
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "x86",
+        target_arch = "arm"
+    )
+)))]
+pub(crate) unsafe fn mmap(
+    addr: *mut c_void,
+    len: usize,
+    prot: i32,
+    flags: i32,
+    fd: i32,
+    offset: i64,
+) -> Result<*mut c_void> {
+    let p = libc::mmap(addr, len, prot, flags, fd, offset as libc::off_t);
+    if p == libc::MAP_FAILED {
+        return Err(crate::error::Error::Unix(super::errno()));
+    }
+    Ok(p)
+}
+
+#[cfg(not(all(
+    any(target_os = "linux", target_os = "android"),
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "x86",
+        target_arch = "arm"
+    )
+)))]
+pub(crate) unsafe fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> Result<()> {
+    if libc::mprotect(addr, len, prot) != 0 {
+        return Err(crate::error::Error::Unix(super::errno()));
+    }
+    Ok(())
+}
+
+#[cfg(not(all(
+    any(target_os = "linux", target_os = "android"),
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "x86",
+        target_arch = "arm"
+    )
+)))]
+pub(crate) unsafe fn munmap(addr: *mut c_void, len: usize) -> Result<()> {
+    if libc::munmap(addr, len) != 0 {
+        return Err(crate::error::Error::Unix(super::errno()));
+    }
+    Ok(())
+}
+
+/// System page size. On Linux/Android it comes from the auxiliary vector and is cached on first
+/// use, so the patch path never calls `sysconf`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn page_size() -> usize {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    match PAGE_SIZE.load(Ordering::Relaxed) {
+        0 => {
+            let ps = match unsafe { libc::getauxval(libc::AT_PAGESZ) } as usize {
+                0 => 4096,
+                ps => ps,
+            };
+            PAGE_SIZE.store(ps, Ordering::Relaxed);
+            ps
+        }
+        ps => ps,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn page_size() -> usize {
+    let ps = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if ps <= 0 { 4096 } else { ps as usize }
+}
+
+/// Write `bytes` at `address` through `/proc/self/mem`.
+///
+/// The kernel services these writes with `FOLL_FORCE`, so they succeed on read-only text even when
+/// policy forbids making it writable with `mprotect`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) unsafe fn write_proc_mem(address: *mut c_void, bytes: &[u8]) -> Result<()> {
+    let fd = syscall(
+        libc::SYS_openat,
+        [
+            libc::AT_FDCWD as isize as usize,
+            c"/proc/self/mem".as_ptr() as usize,
+            (libc::O_RDWR | libc::O_CLOEXEC) as usize,
+            0,
+            0,
+            0,
+        ],
+    )?;
+    let mut done = 0;
+    let written = loop {
+        if done == bytes.len() {
+            break Ok(());
+        }
+        let offset = address as usize + done;
+        // The 64-bit offset is split over two registers on 32-bit targets, low word first; ARM
+        // EABI starts it at an even register, leaving the fourth argument unused.
+        #[cfg(not(target_arch = "arm"))]
+        let args = [
+            fd,
+            bytes.as_ptr().add(done) as usize,
+            bytes.len() - done,
+            offset,
+            0,
+            0,
+        ];
+        #[cfg(target_arch = "arm")]
+        let args = [
+            fd,
+            bytes.as_ptr().add(done) as usize,
+            bytes.len() - done,
+            0,
+            offset,
+            0,
+        ];
+        match syscall(libc::SYS_pwrite64, args) {
+            Ok(0) => break Err(crate::error::Error::Unix(libc::EIO)),
+            Ok(n) => done += n,
+            Err(crate::error::Error::Unix(libc::EINTR)) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = syscall(libc::SYS_close, [fd, 0, 0, 0, 0, 0]);
+    written
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+unsafe fn syscall(n: libc::c_long, a: [usize; 6]) -> Result<usize> {
+    raw::call(n, a)
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+unsafe fn syscall(n: libc::c_long, a: [usize; 6]) -> Result<usize> {
+    let ret = libc::syscall(n, a[0], a[1], a[2], a[3], a[4], a[5]);
+    // Not `< 0`: on 32-bit targets, mappings above 2 GiB come back negative.
+    if ret == -1 {
+        return Err(crate::error::Error::Unix(super::errno()));
+    }
+    Ok(ret as usize)
+}
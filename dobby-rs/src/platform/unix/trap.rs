@@ -0,0 +1,106 @@
+//! SIGTRAP redirection for breakpoint hooks.
+//!
+//! A breakpoint hook replaces the first instruction with `int3`/`brk`. The handler looks the trap
+//! address up in a fixed, lock-free table (it runs in signal context, so no locks or allocation)
+//! and resumes the thread at the detour with all registers untouched.
+
+use crate::error::{Error, Result};
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const MAX_TRAPS: usize = 256;
+
+// Slot states: free (`address == 0`), live, or retired (`target == address`). A retired slot
+// keeps resuming threads that trapped just before the hook was removed at the restored
+// instruction, and is reused by the next registration that needs it. Writers are serialized by
+// `REGISTER`; the handler only reads.
+struct Trap {
+    address: AtomicUsize,
+    target: AtomicUsize,
+}
+
+static TRAPS: [Trap; MAX_TRAPS] = [const {
+    Trap {
+        address: AtomicUsize::new(0),
+        target: AtomicUsize::new(0),
+    }
+}; MAX_TRAPS];
+
+static REGISTER: Mutex<()> = Mutex::new(());
+
+/// Route traps at `address` to `target`. Must happen before the trap instruction is written.
+pub(crate) unsafe fn register(address: usize, target: usize) -> Result<()> {
+    super::signals::add_consumer(libc::SIGTRAP, on_trap)?;
+    let _lock = REGISTER.lock().unwrap_or_else(|e| e.into_inner());
+    let slot = |f: &dyn Fn(usize, usize) -> bool| {
+        TRAPS.iter().find(|t| {
+            f(
+                t.address.load(Ordering::Acquire),
+                t.target.load(Ordering::Acquire),
+            )
+        })
+    };
+    let trap = slot(&|a, _| a == address)
+        .or_else(|| slot(&|a, _| a == 0))
+        .or_else(|| slot(&|a, t| a == t))
+        .ok_or(Error::Unix(libc::ENOSPC))?;
+    // A retired slot keeps routing its old address until it is rewritten here; nothing traps at
+    // the new one before it is complete.
+    trap.address.store(address, Ordering::Release);
+    trap.target.store(target, Ordering::Release);
+    Ok(())
+}
+
+/// Retire `address`; threads that still trap on it resume at the restored instruction.
+pub(crate) fn unregister(address: usize) {
+    let _lock = REGISTER.lock().unwrap_or_else(|e| e.into_inner());
+    for trap in TRAPS.iter() {
+        if trap.address.load(Ordering::Acquire) == address {
+            trap.target.store(address, Ordering::Release);
+        }
+    }
+}
+
+fn lookup(address: usize) -> Option<usize> {
+    TRAPS
+        .iter()
+        .find(|t| t.address.load(Ordering::Acquire) == address)
+        .map(|t| t.target.load(Ordering::Acquire))
+        .filter(|&t| t != 0)
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn pc(uc: *mut libc::ucontext_t) -> *mut usize {
+    &mut (*uc).uc_mcontext.gregs[libc::REG_RIP as usize] as *mut i64 as *mut usize
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn pc(uc: *mut libc::ucontext_t) -> *mut usize {
+    &mut (*uc).uc_mcontext.pc as *mut u64 as *mut usize
+}
+
+// `int3` reports the address after itself with `SI_KERNEL`; `brk` reports itself with
+// `TRAP_BRKPT`. Other SIGTRAPs (single-step, `raise`) are never ours.
+#[cfg(target_arch = "x86_64")]
+unsafe fn trap_site(info: *mut libc::siginfo_t, pc: usize) -> Option<usize> {
+    const SI_KERNEL: i32 = 0x80;
+    ((*info).si_code == SI_KERNEL).then(|| pc - 1)
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn trap_site(info: *mut libc::siginfo_t, pc: usize) -> Option<usize> {
+    const TRAP_BRKPT: i32 = 1;
+    ((*info).si_code == TRAP_BRKPT).then_some(pc)
+}
+
+unsafe fn on_trap(_sig: i32, info: *mut libc::siginfo_t, ctx: *mut c_void) -> bool {
+    let pc = pc(ctx as *mut libc::ucontext_t);
+    match trap_site(info, *pc).and_then(lookup) {
+        Some(target) => {
+            *pc = target;
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,399 @@
+//! Page-guard watchpoints (Linux x86_64).
+//!
+//! Watched pages are mapped `PROT_NONE`. The SIGSEGV handler reports accesses that fall inside a
+//! watched range, puts the page's protection back, and single-steps the faulting instruction with
+//! the trap flag; the SIGTRAP that follows re-guards the page. Accesses to unwatched bytes that
+//! share a page with a watch take the same path without reporting anything. Accesses the page's
+//! original protection would not have allowed are forwarded as ordinary faults, as are faults on a
+//! fifth guarded page within one instruction.
+//!
+//! Both handlers run in signal context, so they only read the lock-free tables below; `watch` and
+//! `unwatch` serialize updates with `LOCK`.
+
+use super::sys;
+use crate::engine::{WatchAccess, WatchCallback, WatchHit};
+use crate::error::{Error, Result};
+use core::cell::Cell;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const MAX_WATCHES: usize = 64;
+const MAX_PAGES: usize = 256;
+const TF: i64 = 0x100;
+const TRAP_TRACE: i32 = 2;
+// Page-fault error code bits.
+const PF_WRITE: i64 = 1 << 1;
+const PF_INSTR: i64 = 1 << 4;
+
+struct Watch {
+    // 0 while free; published last, so a non-zero id means the other fields are valid.
+    id: AtomicUsize,
+    start: AtomicUsize,
+    len: AtomicUsize,
+    callback: AtomicUsize,
+}
+
+struct Page {
+    base: AtomicUsize,
+    prot: AtomicUsize,
+}
+
+static WATCHES: [Watch; MAX_WATCHES] = [const {
+    Watch {
+        id: AtomicUsize::new(0),
+        start: AtomicUsize::new(0),
+        len: AtomicUsize::new(0),
+        callback: AtomicUsize::new(0),
+    }
+}; MAX_WATCHES];
+
+static PAGES: [Page; MAX_PAGES] = [const {
+    Page {
+        base: AtomicUsize::new(0),
+        prot: AtomicUsize::new(0),
+    }
+}; MAX_PAGES];
+
+static LOCK: Mutex<()> = Mutex::new(());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    // Pages this thread unguarded for the instruction it is single-stepping. An instruction can
+    // touch more than one guarded page, each fault adds one.
+    static STEPPING: [Cell<usize>; 4] = const { [const { Cell::new(0) }; 4] };
+}
+
+fn pages(start: usize, len: usize) -> Result<impl Iterator<Item = usize>> {
+    let ps = sys::page_size();
+    let last = len
+        .checked_sub(1)
+        .and_then(|n| start.checked_add(n))
+        .ok_or(Error::InvalidInput)?;
+    Ok(((start & !(ps - 1))..=(last & !(ps - 1))).step_by(ps))
+}
+
+fn page_prot(base: usize) -> Option<i32> {
+    PAGES
+        .iter()
+        .find(|p| p.base.load(Ordering::Acquire) == base)
+        .map(|p| p.prot.load(Ordering::Acquire) as i32)
+}
+
+// Current protection of the mapping containing `addr`, from /proc/self/maps.
+fn query_prot(addr: usize) -> Result<i32> {
+    let maps = std::fs::read_to_string("/proc/self/maps")
+        .map_err(|e| Error::Unix(e.raw_os_error().unwrap_or(0)))?;
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(range), Some(perms)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let Some((lo, hi)) = range.split_once('-') else {
+            continue;
+        };
+        let (Ok(lo), Ok(hi)) = (usize::from_str_radix(lo, 16), usize::from_str_radix(hi, 16))
+        else {
+            continue;
+        };
+        if (lo..hi).contains(&addr) {
+            let p = perms.as_bytes();
+            let mut prot = libc::PROT_NONE;
+            if p.first() == Some(&b'r') {
+                prot |= libc::PROT_READ;
+            }
+            if p.get(1) == Some(&b'w') {
+                prot |= libc::PROT_WRITE;
+            }
+            if p.get(2) == Some(&b'x') {
+                prot |= libc::PROT_EXEC;
+            }
+            return Ok(prot);
+        }
+    }
+    Err(Error::Unix(libc::EFAULT))
+}
+
+unsafe fn install() -> Result<()> {
+    super::signals::add_consumer(libc::SIGSEGV, on_segv)?;
+    super::signals::add_consumer(libc::SIGTRAP, on_trap)
+}
+
+pub(crate) unsafe fn watch(start: usize, len: usize, callback: WatchCallback) -> Result<usize> {
+    let range = pages(start, len)?;
+    install()?;
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let slot = WATCHES
+        .iter()
+        .find(|w| w.id.load(Ordering::Acquire) == 0)
+        .ok_or(Error::Unix(libc::ENOSPC))?;
+    let mut new_pages = Vec::new();
+    for base in range {
+        if page_prot(base).is_none() {
+            new_pages.push((base, query_prot(base)?));
+        }
+    }
+    let free_pages = PAGES
+        .iter()
+        .filter(|p| p.base.load(Ordering::Acquire) == 0)
+        .count();
+    if new_pages.len() > free_pages {
+        return Err(Error::Unix(libc::ENOSPC));
+    }
+
+    // Publish the watch before guarding, so the first fault already finds it.
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    slot.start.store(start, Ordering::Relaxed);
+    slot.len.store(len, Ordering::Relaxed);
+    slot.callback.store(callback as usize, Ordering::Relaxed);
+    slot.id.store(id, Ordering::Release);
+
+    for (base, prot) in new_pages {
+        let page = PAGES
+            .iter()
+            .find(|p| p.base.load(Ordering::Acquire) == 0)
+            .expect("counted above");
+        page.prot.store(prot as usize, Ordering::Release);
+        page.base.store(base, Ordering::Release);
+        if let Err(e) = sys::mprotect(base as *mut c_void, sys::page_size(), libc::PROT_NONE) {
+            page.base.store(0, Ordering::Release);
+            let _ = unwatch_locked(id);
+            return Err(e);
+        }
+    }
+    Ok(id)
+}
+
+pub(crate) unsafe fn unwatch(id: usize) -> Result<()> {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    unwatch_locked(id)
+}
+
+unsafe fn unwatch_locked(id: usize) -> Result<()> {
+    let slot = WATCHES
+        .iter()
+        .find(|w| w.id.load(Ordering::Acquire) == id)
+        .ok_or(Error::WatchNotFound)?;
+    let (start, len) = (
+        slot.start.load(Ordering::Relaxed),
+        slot.len.load(Ordering::Relaxed),
+    );
+    slot.id.store(0, Ordering::Release);
+
+    for base in pages(start, len)? {
+        let still_watched = WATCHES.iter().any(|w| {
+            w.id.load(Ordering::Acquire) != 0
+                && pages(
+                    w.start.load(Ordering::Relaxed),
+                    w.len.load(Ordering::Relaxed),
+                )
+                .is_ok_and(|mut p| p.any(|p| p == base))
+        });
+        if still_watched {
+            continue;
+        }
+        if let Some(page) = PAGES
+            .iter()
+            .find(|p| p.base.load(Ordering::Acquire) == base)
+        {
+            let prot = page.prot.load(Ordering::Acquire) as i32;
+            page.base.store(0, Ordering::Release);
+            sys::mprotect(base as *mut c_void, sys::page_size(), prot)?;
+        }
+    }
+    Ok(())
+}
+
+unsafe fn on_segv(_sig: i32, info: *mut libc::siginfo_t, ctx: *mut c_void) -> bool {
+    let addr = (*info).si_addr() as usize;
+    let base = addr & !(sys::page_size() - 1);
+    let Some(prot) = page_prot(base) else {
+        return false;
+    };
+    let uc = ctx as *mut libc::ucontext_t;
+    let gregs = &mut (*uc).uc_mcontext.gregs;
+    let err = gregs[libc::REG_ERR as usize];
+    let (access, needs) = if err & PF_INSTR != 0 {
+        (WatchAccess::Execute, libc::PROT_EXEC)
+    } else if err & PF_WRITE != 0 {
+        (WatchAccess::Write, libc::PROT_WRITE)
+    } else {
+        (WatchAccess::Read, libc::PROT_READ)
+    };
+    // Not an access the guard stole: stepping it would only fault again.
+    if prot & needs == 0 {
+        return false;
+    }
+    // Without a slot the trap could not re-guard the page; fail the access instead of losing it.
+    let Some(slot) = STEPPING.with(|pending| pending.iter().position(|c| c.get() == 0)) else {
+        return false;
+    };
+    let hit = WatchHit {
+        address: addr as *mut c_void,
+        access,
+        pc: gregs[libc::REG_RIP as usize] as usize as *mut c_void,
+        context: ctx,
+    };
+    for w in WATCHES.iter() {
+        if w.id.load(Ordering::Acquire) == 0 {
+            continue;
+        }
+        let start = w.start.load(Ordering::Relaxed);
+        if addr >= start && addr - start < w.len.load(Ordering::Relaxed) {
+            let callback: WatchCallback = core::mem::transmute(w.callback.load(Ordering::Relaxed));
+            callback(&hit);
+        }
+    }
+
+    let _ = sys::mprotect(base as *mut c_void, sys::page_size(), prot);
+    STEPPING.with(|pending| pending[slot].set(base));
+    (*uc).uc_mcontext.gregs[libc::REG_EFL as usize] |= TF;
+    true
+}
+
+unsafe fn on_trap(_sig: i32, info: *mut libc::siginfo_t, ctx: *mut c_void) -> bool {
+    let stepping = STEPPING.with(|pending| pending.iter().any(|c| c.get() != 0));
+    if !stepping || (*info).si_code != TRAP_TRACE {
+        return false;
+    }
+    STEPPING.with(|pending| {
+        for c in pending.iter() {
+            let base = c.replace(0);
+            // Skip pages that were unwatched while this thread was stepping.
+            if base != 0 && page_prot(base).is_some() {
+                let _ = sys::mprotect(base as *mut c_void, sys::page_size(), libc::PROT_NONE);
+            }
+        }
+    });
+    let uc = ctx as *mut libc::ucontext_t;
+    (*uc).uc_mcontext.gregs[libc::REG_EFL as usize] &= !TF;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ptr;
+
+    // Filled from the signal handler, so no locks. One log per test, as they run in parallel.
+    struct Log {
+        count: AtomicUsize,
+        hits: [(AtomicUsize, AtomicUsize); 8],
+    }
+
+    static LOGS: [Log; 2] = [const {
+        Log {
+            count: AtomicUsize::new(0),
+            hits: [const { (AtomicUsize::new(0), AtomicUsize::new(0)) }; 8],
+        }
+    }; 2];
+    static FORWARDED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe fn record<const LOG: usize>(hit: &WatchHit) {
+        let log = &LOGS[LOG];
+        if let Some((address, access)) = log.hits.get(log.count.fetch_add(1, Ordering::SeqCst)) {
+            address.store(hit.address as usize, Ordering::SeqCst);
+            access.store(hit.access as usize, Ordering::SeqCst);
+        }
+    }
+
+    fn hits(log: usize) -> Vec<(usize, usize)> {
+        let log = &LOGS[log];
+        log.hits
+            .iter()
+            .take(log.count.load(Ordering::SeqCst))
+            .map(|(a, k)| (a.load(Ordering::SeqCst), k.load(Ordering::SeqCst)))
+            .collect()
+    }
+
+    unsafe fn map_page() -> *mut u64 {
+        sys::mmap(
+            ptr::null_mut(),
+            sys::page_size(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANON,
+            -1,
+            0,
+        )
+        .unwrap() as *mut u64
+    }
+
+    // The application's own SIGSEGV handler: makes the faulting page writable.
+    extern "C" fn unprotect(_sig: i32, info: *mut libc::siginfo_t, _ctx: *mut c_void) {
+        unsafe {
+            let base = (*info).si_addr() as usize & !(sys::page_size() - 1);
+            FORWARDED.fetch_add(1, Ordering::SeqCst);
+            libc::mprotect(
+                base as *mut c_void,
+                sys::page_size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+            );
+        }
+    }
+
+    #[test]
+    fn reports_watched_bytes_only() {
+        unsafe {
+            let page = map_page();
+            let watched = page.add(2);
+            let neighbour = page.add(20);
+
+            let id = watch(watched as usize, 8, record::<0>).expect("watch");
+            ptr::write_volatile(watched, 7);
+            assert_eq!(ptr::read_volatile(watched), 7);
+            // Same page, outside the range: handled without a report.
+            ptr::write_volatile(neighbour, 9);
+            assert_eq!(ptr::read_volatile(neighbour), 9);
+            unwatch(id).expect("unwatch");
+            ptr::write_volatile(watched, 8);
+
+            assert_eq!(
+                hits(0),
+                [
+                    (watched as usize, WatchAccess::Write as usize),
+                    (watched as usize, WatchAccess::Read as usize),
+                ]
+            );
+            assert!(matches!(unwatch(id), Err(Error::WatchNotFound)));
+            sys::munmap(page as *mut c_void, sys::page_size()).unwrap();
+        }
+    }
+
+    #[test]
+    fn faults_the_original_protection_forbids_are_forwarded() {
+        unsafe {
+            let page = map_page();
+            sys::mprotect(page as *mut c_void, sys::page_size(), libc::PROT_READ).unwrap();
+            let id = watch(page as usize, 8, record::<1>).expect("watch");
+            assert_eq!(ptr::read_volatile(page), 0);
+
+            let mut sa: libc::sigaction = core::mem::zeroed();
+            sa.sa_sigaction = unprotect as *const () as usize;
+            sa.sa_flags = libc::SA_SIGINFO;
+            let mut old: libc::sigaction = core::mem::zeroed();
+            assert_eq!(libc::sigaction(libc::SIGSEGV, &sa, &mut old), 0);
+            ptr::write_volatile(page, 5);
+            assert_eq!(FORWARDED.load(Ordering::SeqCst), 1);
+
+            // No single-step slot left: the fault is forwarded rather than unguarding the page.
+            let other = map_page();
+            let other_id = watch(other as usize, 8, record::<1>).expect("watch");
+            STEPPING.with(|pending| pending.iter().for_each(|c| c.set(1)));
+            ptr::write_volatile(other, 6);
+            STEPPING.with(|pending| pending.iter().for_each(|c| c.set(0)));
+            assert_eq!(libc::sigaction(libc::SIGSEGV, &old, ptr::null_mut()), 0);
+
+            assert_eq!(FORWARDED.load(Ordering::SeqCst), 2);
+            assert_eq!(hits(1), [(page as usize, WatchAccess::Read as usize)]);
+            unwatch(other_id).expect("unwatch");
+            unwatch(id).expect("unwatch");
+            assert!(matches!(
+                watch(usize::MAX - 3, 8, record::<1>),
+                Err(Error::InvalidInput)
+            ));
+            sys::munmap(page as *mut c_void, sys::page_size()).unwrap();
+            sys::munmap(other as *mut c_void, sys::page_size()).unwrap();
+        }
+    }
+}
@@ -1,13 +1,24 @@
 use crate::error::{Error, Result};
+use crate::options::PatchAtomicity;
 use core::ffi::c_void;
 use core::ptr;
-use windows_sys::Win32::Foundation::{GetLastError, HANDLE};
+use windows_sys::Win32::Foundation::{CloseHandle, GetLastError, HANDLE, INVALID_HANDLE_VALUE};
 use windows_sys::Win32::System::Diagnostics::Debug::FlushInstructionCache;
+#[cfg(target_arch = "x86_64")]
+use windows_sys::Win32::System::Diagnostics::Debug::{
+    CONTEXT, CONTEXT_CONTROL_AMD64, GetThreadContext,
+};
+use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, TH32CS_SNAPTHREAD, THREADENTRY32, Thread32First, Thread32Next,
+};
 use windows_sys::Win32::System::Memory::{
     MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READWRITE, VirtualAlloc, VirtualFree,
     VirtualProtect,
 };
-use windows_sys::Win32::System::Threading::GetCurrentProcess;
+use windows_sys::Win32::System::Threading::{
+    GetCurrentProcess, GetCurrentProcessId, GetCurrentThreadId, OpenThread, ResumeThread,
+    SuspendThread, THREAD_GET_CONTEXT, THREAD_SUSPEND_RESUME,
+};
 
 unsafe fn last_error() -> Error {
     Error::Win32(GetLastError())
@@ -30,8 +41,8 @@ pub(crate) unsafe fn alloc_executable_near(
     size: usize,
     pos: usize,
     range: usize,
-) -> Result<*mut c_void> {
-    // Best-effort: allocate within +/- 2GB of `pos` so RIP-relative instructions can be relocated.
+) -> Result<Option<*mut c_void>> {
+    // Best-effort: allocate within +/- `range` of `pos` so RIP-relative instructions can be relocated.
     // Windows allocation base addresses must be 64KB aligned.
     const GRANULARITY: usize = 0x10000;
     let step = GRANULARITY;
@@ -50,7 +61,7 @@ pub(crate) unsafe fn alloc_executable_near(
                 PAGE_EXECUTE_READWRITE,
             );
             if !p.is_null() {
-                return Ok(p);
+                return Ok(Some(p));
             }
         }
 
@@ -64,13 +75,12 @@ pub(crate) unsafe fn alloc_executable_near(
                 PAGE_EXECUTE_READWRITE,
             );
             if !p.is_null() {
-                return Ok(p);
+                return Ok(Some(p));
             }
         }
     }
 
-    // Fallback to anywhere.
-    alloc_executable(size)
+    Ok(None)
 }
 
 pub(crate) unsafe fn free_executable(ptr: *mut c_void) -> Result<()> {
@@ -106,16 +116,30 @@ pub(crate) unsafe fn flush_icache(address: *mut c_void, size: usize) -> Result<(
     Ok(())
 }
 
+unsafe fn write_code(address: *mut c_void, bytes: &[u8]) -> PatchAtomicity {
+    #[cfg(target_arch = "x86_64")]
+    {
+        crate::arch::x86_64::write_code(address as *mut u8, bytes)
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), address as *mut u8, bytes.len());
+        PatchAtomicity::NonAtomic
+    }
+}
+
 pub(crate) unsafe fn code_patch(
     address: *mut c_void,
     buffer: *const u8,
     size: usize,
-) -> Result<()> {
+) -> Result<PatchAtomicity> {
+    let mut atomicity = PatchAtomicity::NonAtomic;
     with_rwx(address, size, || {
-        ptr::copy_nonoverlapping(buffer, address as *mut u8, size);
+        atomicity = write_code(address, core::slice::from_raw_parts(buffer, size));
         Ok(())
     })?;
-    flush_icache(address, size)
+    flush_icache(address, size)?;
+    Ok(atomicity)
 }
 
 pub(crate) unsafe fn restore_patch(
@@ -127,8 +151,87 @@ pub(crate) unsafe fn restore_patch(
         return Err(Error::PatchTooSmall);
     }
     with_rwx(address, patch_len, || {
-        ptr::copy_nonoverlapping(original.as_ptr(), address as *mut u8, patch_len);
+        write_code(address, original);
         Ok(())
     })?;
     flush_icache(address, patch_len)
 }
+
+/// Other threads of the process, suspended until this value is dropped.
+pub(crate) struct SuspendedThreads {
+    handles: Vec<HANDLE>,
+    pcs: Vec<usize>,
+}
+
+impl SuspendedThreads {
+    /// Whether a suspended thread stopped strictly inside `start..start + len`, where it would
+    /// resume in the middle of whatever is written there.
+    pub(crate) fn stopped_inside(&self, start: usize, len: usize) -> bool {
+        self.pcs.iter().any(|&pc| pc > start && pc - start < len)
+    }
+}
+
+// `GetThreadContext` also waits for the suspension to take effect.
+#[cfg(target_arch = "x86_64")]
+unsafe fn thread_pc(h: HANDLE) -> Option<usize> {
+    let mut ctx: CONTEXT = core::mem::zeroed();
+    ctx.ContextFlags = CONTEXT_CONTROL_AMD64;
+    (GetThreadContext(h, &mut ctx) != 0).then_some(ctx.Rip as usize)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn thread_pc(_h: HANDLE) -> Option<usize> {
+    None
+}
+
+impl Drop for SuspendedThreads {
+    fn drop(&mut self) {
+        for h in self.handles.drain(..) {
+            unsafe {
+                ResumeThread(h);
+                CloseHandle(h);
+            }
+        }
+    }
+}
+
+/// Suspend every other thread of the process. The thread list is taken and both vectors are
+/// sized up front: a suspended thread may hold the heap lock, so nothing allocates from the first
+/// `SuspendThread` until the threads are resumed.
+pub(crate) unsafe fn suspend_other_threads() -> Result<SuspendedThreads> {
+    let snap = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+    if snap == INVALID_HANDLE_VALUE {
+        return Err(last_error());
+    }
+    let pid = GetCurrentProcessId();
+    let me = GetCurrentThreadId();
+    let mut ids = Vec::new();
+    let mut te: THREADENTRY32 = core::mem::zeroed();
+    te.dwSize = core::mem::size_of::<THREADENTRY32>() as u32;
+    let mut more = Thread32First(snap, &mut te) != 0;
+    while more {
+        if te.th32OwnerProcessID == pid && te.th32ThreadID != me {
+            ids.push(te.th32ThreadID);
+        }
+        more = Thread32Next(snap, &mut te) != 0;
+    }
+    CloseHandle(snap);
+
+    let mut out = SuspendedThreads {
+        handles: Vec::with_capacity(ids.len()),
+        pcs: Vec::with_capacity(ids.len()),
+    };
+    for id in ids {
+        let h = OpenThread(THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT, 0, id);
+        if h.is_null() {
+            continue;
+        }
+        if SuspendThread(h) == u32::MAX {
+            CloseHandle(h);
+        } else {
+            out.pcs.extend(thread_pc(h));
+            out.handles.push(h);
+        }
+    }
+    Ok(out)
+}
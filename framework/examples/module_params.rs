@@ -4,6 +4,7 @@
 mod common;
 
 use core::ffi::c_void;
+use core::mem::size_of;
 use dobby_rs_framework::framework::{ModuleHandle, params};
 use std::ffi::CString;
 
@@ -29,6 +30,23 @@ fn main() -> dobby_rs_framework::Result<()> {
         println!("read_ptr_value = 0x{v:08x}");
     }
 
+    // EN: 2b) `try_*` / `read_*` variants return `Err` instead of crashing on bad pointers.
+    // CN: 2b) `try_*` / `read_*` 系列遇到非法指针返回 `Err`，而不是直接崩溃。
+    let bad = 0x10usize as *const c_void;
+    let r: dobby_rs_framework::Result<u32> = unsafe { params::try_read_ptr_value(bad) };
+    println!("try_read_ptr_value(0x10) = {r:?}");
+
+    let text = c"hello";
+    let s = params::read_c_string(text.as_ptr(), 64)?;
+    println!("read_c_string = {s:?}");
+
+    // EN: Pointer chain `root->next->value`: offset 0 loads `next`, offset 8 loads `value`.
+    // CN: 指针链 `root->next->value`：偏移 0 读取 `next`，偏移 8 读取 `value`。
+    let leaf: [usize; 2] = [0, 0xfeed];
+    let root: [usize; 1] = [leaf.as_ptr() as usize];
+    let v = params::read_ptr_chain(root.as_ptr() as *const c_void, &[0, size_of::<usize>()])?;
+    println!("read_ptr_chain = {v:p}");
+
     // EN: 3) `ModuleHandle` - open a module and resolve a symbol.
     // CN: 3) `ModuleHandle` - 打开动态库并解析符号。
     let m = ModuleHandle::open(common::DEMO_LIB)?;
@@ -1,4 +1,7 @@
-use core::ffi::c_void;
+use crate::{Error, Result};
+use core::ffi::{c_char, c_void};
+use core::mem::{MaybeUninit, size_of};
+use std::ffi::CString;
 
 /// Cast a raw pointer (address) to a function pointer type.
 ///
@@ -33,3 +36,399 @@ pub unsafe fn read_ptr_value<T: Copy>(ptr: *const c_void) -> T {
 pub unsafe fn write_ptr_value<T>(ptr: *mut c_void, value: T) {
     core::ptr::write_unaligned(ptr as *mut T, value);
 }
+
+/// Copy `buf.len()` bytes from `ptr` into `buf` without faulting on unmapped memory.
+///
+/// Returns an error instead of crashing when any part of the range is not readable.
+// The pointer is only handed to the kernel (or probed through it) before being read.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn read_bytes(ptr: *const c_void, buf: &mut [u8]) -> Result<()> {
+    if ptr.is_null() {
+        return Err(Error::NullPointer);
+    }
+    if buf.is_empty() {
+        return Ok(());
+    }
+    unsafe { mem::read(ptr, buf) }
+}
+
+/// Copy `bytes` to `ptr`, returning an error instead of faulting when the range is not writable.
+///
+/// Read-only pages are an error on every platform; their protection is never changed.
+///
+/// # Safety
+/// - The write itself must not break invariants of whatever lives at `ptr`.
+pub unsafe fn write_bytes(ptr: *mut c_void, bytes: &[u8]) -> Result<()> {
+    if ptr.is_null() {
+        return Err(Error::NullPointer);
+    }
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    mem::write(ptr, bytes)
+}
+
+/// Fault-tolerant variant of [`read_ptr_value`].
+///
+/// # Safety
+/// - Any bit pattern read from `ptr` must be a valid `T`.
+pub unsafe fn try_read_ptr_value<T: Copy>(ptr: *const c_void) -> Result<T> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let buf = core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>());
+    read_bytes(ptr, buf)?;
+    Ok(value.assume_init())
+}
+
+/// Fault-tolerant variant of [`write_ptr_value`].
+///
+/// # Safety
+/// - The write itself must not break invariants of whatever lives at `ptr`.
+pub unsafe fn try_write_ptr_value<T: Copy>(ptr: *mut c_void, value: T) -> Result<()> {
+    let bytes = core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>());
+    write_bytes(ptr, bytes)
+}
+
+/// Read `count` consecutive `T` values starting at `ptr`.
+///
+/// # Safety
+/// - Any bit pattern read from `ptr` must be a valid `T`.
+pub unsafe fn read_array<T: Copy>(ptr: *const c_void, count: usize) -> Result<Vec<T>> {
+    let len = count
+        .checked_mul(size_of::<T>())
+        .ok_or(Error::InvalidInput)?;
+    let mut out = Vec::<T>::with_capacity(count);
+    let buf = core::slice::from_raw_parts_mut(out.as_mut_ptr() as *mut u8, len);
+    read_bytes(ptr, buf)?;
+    out.set_len(count);
+    Ok(out)
+}
+
+/// Read a NUL-terminated string of at most `max_len` bytes (terminator excluded).
+///
+/// Fails with [`Error::InvalidInput`] when no terminator is found within the bound.
+pub fn read_c_string(ptr: *const c_char, max_len: usize) -> Result<CString> {
+    let mut out = Vec::new();
+    let mut addr = ptr as usize;
+    // Read page by page so a short string near the end of a mapping is still readable.
+    while out.len() <= max_len {
+        let page_left = PAGE_CHUNK - (addr % PAGE_CHUNK);
+        let want = page_left.min(max_len.saturating_add(1) - out.len());
+        let start = out.len();
+        out.resize(start + want, 0);
+        read_bytes(addr as *const c_void, &mut out[start..])?;
+        if let Some(nul) = out[start..].iter().position(|&b| b == 0) {
+            out.truncate(start + nul);
+            return CString::new(out).map_err(|_| Error::InvalidInput);
+        }
+        addr += want;
+    }
+    Err(Error::InvalidInput)
+}
+
+/// Follow a pointer chain such as `p->a->b` without faulting.
+///
+/// Starting from `base`, each offset is added to the current pointer and the pointer stored there
+/// is loaded, so `read_ptr_chain(p, &[off_a, off_b])` yields the value of `p->a->b`.
+pub fn read_ptr_chain(base: *const c_void, offsets: &[usize]) -> Result<*mut c_void> {
+    let mut cur = base as usize;
+    for off in offsets {
+        if cur == 0 {
+            return Err(Error::NullPointer);
+        }
+        let slot = cur.checked_add(*off).ok_or(Error::InvalidInput)?;
+        cur = unsafe { try_read_ptr_value::<usize>(slot as *const c_void)? };
+    }
+    Ok(cur as *mut c_void)
+}
+
+// Smallest page size on supported targets; only used to split reads, never for protection.
+const PAGE_CHUNK: usize = 4096;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod mem {
+    use crate::{Error, Result};
+    use core::ffi::c_void;
+
+    unsafe fn errno() -> i32 {
+        *libc::__errno_location()
+    }
+
+    // process_vm_{readv,writev} on our own pid go through the kernel's copy routines, which report
+    // EFAULT for unmapped or protected pages instead of raising SIGSEGV.
+    pub(super) unsafe fn read(ptr: *const c_void, buf: &mut [u8]) -> Result<()> {
+        let local = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: ptr as *mut c_void,
+            iov_len: buf.len(),
+        };
+        let n = libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0);
+        check(n, buf.len())
+    }
+
+    pub(super) unsafe fn write(ptr: *mut c_void, bytes: &[u8]) -> Result<()> {
+        let local = libc::iovec {
+            iov_base: bytes.as_ptr() as *mut c_void,
+            iov_len: bytes.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: ptr,
+            iov_len: bytes.len(),
+        };
+        let n = libc::process_vm_writev(libc::getpid(), &local, 1, &remote, 1, 0);
+        check(n, bytes.len())
+    }
+
+    unsafe fn check(n: isize, want: usize) -> Result<()> {
+        if n < 0 {
+            return Err(Error::Unix(errno()));
+        }
+        if n as usize != want {
+            return Err(Error::Unix(libc::EFAULT));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+mod mem {
+    use crate::{Error, Result};
+    use core::ffi::c_void;
+
+    unsafe fn errno() -> i32 {
+        std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+    }
+
+    // Without process_vm_readv we validate the mapping by letting the kernel copy it into a pipe:
+    // `write` fails with EFAULT instead of faulting when the source is unreadable.
+    pub(super) unsafe fn read(ptr: *const c_void, buf: &mut [u8]) -> Result<()> {
+        let mut fds = [0i32; 2];
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(Error::Unix(errno()));
+        }
+        let r = probe(fds, ptr, buf.len());
+        let _ = libc::close(fds[0]);
+        let _ = libc::close(fds[1]);
+        r?;
+        core::ptr::copy_nonoverlapping(ptr as *const u8, buf.as_mut_ptr(), buf.len());
+        Ok(())
+    }
+
+    unsafe fn probe(fds: [i32; 2], ptr: *const c_void, len: usize) -> Result<()> {
+        // Probe in small pieces so we never block on a full pipe.
+        let mut off = 0usize;
+        while off < len {
+            let chunk = (len - off).min(512);
+            let n = libc::write(fds[1], (ptr as *const u8).add(off) as *const c_void, chunk);
+            if n < 0 {
+                return Err(Error::Unix(errno()));
+            }
+            let mut sink = [0u8; 512];
+            let _ = libc::read(fds[0], sink.as_mut_ptr() as *mut c_void, n as usize);
+            off += n as usize;
+        }
+        Ok(())
+    }
+
+    pub(super) unsafe fn write(_ptr: *mut c_void, _bytes: &[u8]) -> Result<()> {
+        Err(Error::UnsupportedPlatform)
+    }
+}
+
+#[cfg(windows)]
+mod mem {
+    use crate::{Error, Result};
+    use core::ffi::c_void;
+    use windows_sys::Win32::Foundation::{ERROR_NOACCESS, GetLastError};
+    use windows_sys::Win32::System::Diagnostics::Debug::{ReadProcessMemory, WriteProcessMemory};
+    use windows_sys::Win32::System::Memory::{
+        MEM_COMMIT, MEMORY_BASIC_INFORMATION, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY,
+        PAGE_GUARD, PAGE_READWRITE, PAGE_WRITECOPY, VirtualQuery,
+    };
+    use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+    pub(super) unsafe fn read(ptr: *const c_void, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0usize;
+        if ReadProcessMemory(
+            GetCurrentProcess(),
+            ptr,
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len(),
+            &mut done,
+        ) == 0
+        {
+            return Err(Error::Win32(GetLastError()));
+        }
+        if done != buf.len() {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+
+    // WriteProcessMemory makes read-only pages writable for the copy, unlike the unix paths.
+    unsafe fn writable(ptr: *mut c_void, len: usize) -> bool {
+        let writable =
+            PAGE_READWRITE | PAGE_WRITECOPY | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY;
+        let end = ptr as usize + len;
+        let mut addr = ptr as usize;
+        while addr < end {
+            let mut info: MEMORY_BASIC_INFORMATION = core::mem::zeroed();
+            if VirtualQuery(
+                addr as *const c_void,
+                &mut info,
+                core::mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+            ) == 0
+                || info.State != MEM_COMMIT
+                || info.Protect & writable == 0
+                || info.Protect & PAGE_GUARD != 0
+            {
+                return false;
+            }
+            addr = info.BaseAddress as usize + info.RegionSize;
+        }
+        true
+    }
+
+    pub(super) unsafe fn write(ptr: *mut c_void, bytes: &[u8]) -> Result<()> {
+        if !writable(ptr, bytes.len()) {
+            return Err(Error::Win32(ERROR_NOACCESS));
+        }
+        let mut done = 0usize;
+        if WriteProcessMemory(
+            GetCurrentProcess(),
+            ptr,
+            bytes.as_ptr() as *const c_void,
+            bytes.len(),
+            &mut done,
+        ) == 0
+        {
+            return Err(Error::Win32(GetLastError()));
+        }
+        if done != bytes.len() {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    // Two pages: the first read-write, the second with `prot`.
+    unsafe fn pages(prot: i32) -> *mut u8 {
+        let p = libc::mmap(
+            core::ptr::null_mut(),
+            2 * PAGE_CHUNK,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        assert_ne!(p, libc::MAP_FAILED);
+        assert_eq!(libc::mprotect(p.add(PAGE_CHUNK), PAGE_CHUNK, prot), 0);
+        p as *mut u8
+    }
+
+    unsafe fn unmap(p: *mut u8) {
+        libc::munmap(p as *mut c_void, 2 * PAGE_CHUNK);
+    }
+
+    #[test]
+    fn unreadable_memory_is_an_error() {
+        unsafe {
+            let p = pages(libc::PROT_NONE);
+            let second = p.add(PAGE_CHUNK) as *const c_void;
+            let mut buf = [0u8; 8];
+            assert!(read_bytes(second, &mut buf).is_err());
+            // Straddling into the unreadable page fails as a whole.
+            assert!(read_bytes(second.cast::<u8>().sub(4).cast(), &mut buf).is_err());
+            assert!(try_read_ptr_value::<u64>(second).is_err());
+            assert!(read_array::<u32>(p as *const c_void, PAGE_CHUNK / 4 + 1).is_err());
+            assert_eq!(
+                read_array::<u32>(p as *const c_void, 4).expect("mapped"),
+                [0; 4]
+            );
+            assert!(matches!(
+                read_bytes(core::ptr::null(), &mut buf),
+                Err(Error::NullPointer)
+            ));
+            unmap(p);
+        }
+    }
+
+    #[test]
+    fn read_only_memory_is_not_written() {
+        unsafe {
+            let p = pages(libc::PROT_READ);
+            let second = p.add(PAGE_CHUNK) as *mut c_void;
+            assert!(write_bytes(second, &[1, 2, 3]).is_err());
+            assert!(try_write_ptr_value(second, 7u32).is_err());
+            assert_eq!(*(second as *const u32), 0);
+
+            try_write_ptr_value(p as *mut c_void, 0x1234_5678u32).expect("writable");
+            assert_eq!(
+                try_read_ptr_value::<u32>(p as *const c_void).unwrap(),
+                0x1234_5678
+            );
+            unmap(p);
+        }
+    }
+
+    #[test]
+    fn strings_cross_pages_up_to_the_bound() {
+        unsafe {
+            let p = pages(libc::PROT_READ | libc::PROT_WRITE);
+            let start = p.add(PAGE_CHUNK - 3);
+            core::ptr::copy_nonoverlapping(c"hello".to_bytes_with_nul().as_ptr(), start, 6);
+            let ptr = start as *const c_char;
+            assert_eq!(read_c_string(ptr, 5).unwrap().as_bytes(), b"hello");
+            assert_eq!(read_c_string(ptr, usize::MAX).unwrap().as_bytes(), b"hello");
+            assert!(matches!(read_c_string(ptr, 4), Err(Error::InvalidInput)));
+
+            // Unterminated up to an unreadable page.
+            libc::mprotect(
+                p.add(PAGE_CHUNK) as *mut c_void,
+                PAGE_CHUNK,
+                libc::PROT_NONE,
+            );
+            core::ptr::write_bytes(p, b'x', PAGE_CHUNK);
+            assert!(read_c_string(p as *const c_char, usize::MAX).is_err());
+            unmap(p);
+        }
+    }
+
+    #[test]
+    fn pointer_chain_stops_at_null() {
+        #[repr(C)]
+        struct Node {
+            pad: usize,
+            next: *const Node,
+        }
+        let last = Node {
+            pad: 0,
+            next: core::ptr::null(),
+        };
+        let first = Node {
+            pad: 0,
+            next: &last,
+        };
+        let base = &first as *const Node as *const c_void;
+        let next = core::mem::offset_of!(Node, next);
+        assert_eq!(
+            read_ptr_chain(base, &[next]).unwrap(),
+            &last as *const Node as *mut c_void
+        );
+        assert!(read_ptr_chain(base, &[next, next]).unwrap().is_null());
+        assert!(matches!(
+            read_ptr_chain(base, &[next, next, next]),
+            Err(Error::NullPointer)
+        ));
+        assert!(matches!(
+            read_ptr_chain(base, &[usize::MAX]),
+            Err(Error::InvalidInput)
+        ));
+    }
+}
@@ -2,10 +2,25 @@
 #![allow(clippy::missing_safety_doc)]
 #![doc = include_str!("../README.md")]
 
+#[cfg(target_arch = "aarch64")]
+pub use dobby_rs::Arm64Assembler;
+#[cfg(target_arch = "x86_64")]
+pub use dobby_rs::RegisterContext;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use dobby_rs::code_asm;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+pub use dobby_rs::patch_instructions;
 pub use dobby_rs::{
-    Error, Result, code_patch, destroy, hook, import_table_replace, instrument,
-    register_alloc_near_code_callback, resolve_symbol, set_near_trampoline, set_options,
-    symbol_resolver,
+    Error, ExecutableAllocator, HookMode, HookOptions, InstrumentHandler, PatchAtomicity,
+    PatchGuard, PatchSet, PatchStyle, Result, SystemAllocator, ThreadSuspension,
+    TrampolinePlacement, WatchAccess, WatchCallback, WatchHit, WatchId, clear_symbol_cache,
+    code_patch, code_patch_guarded, destroy, disable_hook, disable_patch_set, enable_hook,
+    enable_patch_set, hook, hook_ibt_marked, hook_patch_atomicity, hook_patch_style,
+    hook_with_allocator, hook_with_options, import_table_replace, instrument, instrument_with_exit,
+    nop_out, patch_branch, patch_return_value, patch_set_enabled,
+    register_alloc_near_code_callback, register_executable_allocator, register_patch_set,
+    resolve_symbol, resolve_symbol_or_load, set_near_trampoline, set_options, symbol_resolver,
+    toggle_patch_set, unregister_patch_set, unwatch, watch,
 };
 
 pub mod framework;